@@ -0,0 +1,25 @@
+/* C interface of the bincomb library, built as a cdylib */
+#ifndef BINCOMB_H_
+#define BINCOMB_H_
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Builds the image like `bincomb LAYOUT OUTPUT -D NAME=VALUE...`, `defines`
+ * holds `count` strings of the form NAME=VALUE and may be NULL if `count` is
+ * 0. Returns 0 on success or the exit code the command would fail with. */
+int bincomb_build(const char *layout, const char *output,
+                  const char *const *defines, size_t count);
+
+/* Returns the message of the last failed call on the calling thread, or NULL
+ * if it succeeded. The string is valid until the next call. */
+const char *bincomb_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BINCOMB_H_ */
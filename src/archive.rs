@@ -0,0 +1,294 @@
+//! tar and newc cpio archives of a directory of the host.
+//!
+//! Entries are stored sorted by name with zero owners and the same timestamp
+//! so the same directory always produces the same archive.
+
+use anyhow::{bail, Context as _, Result};
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path;
+
+const TAR_BLOCK: usize = 512;
+const CPIO_MAGIC: &str = "070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const MODE_DIR: u32 = 0o040000;
+const MODE_FILE: u32 = 0o100000;
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Tar,
+    Cpio,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Result<Format> {
+        match s {
+            "tar" => Ok(Format::Tar),
+            "cpio" | "newc" => Ok(Format::Cpio),
+            _ => bail!("Unknown archive format '{}', expected tar or cpio", s),
+        }
+    }
+}
+
+/// A file or directory of the archive
+pub struct Entry {
+    pub name: String,
+    pub path: path::PathBuf,
+    pub is_dir: bool,
+    pub permissions: u32,
+    pub size: u64,
+}
+
+#[cfg(unix)]
+fn permissions(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permissions(meta: &fs::Metadata) -> u32 {
+    if meta.is_dir() { 0o755 } else { 0o644 }
+}
+
+/// Lists the directory tree, parents come before their children
+pub fn entries(dir: &path::Path) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    walk(dir, "", &mut entries)?;
+    Ok(entries)
+}
+
+fn walk(dir: &path::Path, prefix: &str, entries: &mut Vec<Entry>) -> Result<()> {
+    let mut children = fs::read_dir(dir)
+        .with_context(
+            || format!("could not read directory `{}`", dir.display())
+        )?
+        .collect::<io::Result<Vec<fs::DirEntry>>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let path = child.path();
+        let name = child.file_name();
+        let name = name.to_str()
+            .with_context(
+                || format!("file name `{}` is not valid UTF-8", path.display())
+            )?;
+        let name = format!("{}{}", prefix, name);
+        let meta = fs::metadata(&path)
+            .with_context(
+                || format!("could not open file `{}`", path.display())
+            )?;
+
+        entries.push(Entry {
+            name: name.clone(),
+            path: path.clone(),
+            is_dir: meta.is_dir(),
+            permissions: permissions(&meta),
+            size: if meta.is_dir() { 0 } else { meta.len() },
+        });
+        if meta.is_dir() {
+            walk(&path, &format!("{}/", name), entries)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn padding(len: usize, align: usize) -> usize {
+    (align - len % align) % align
+}
+
+/// Returns the size of the archive without reading the files
+pub fn size(entries: &[Entry], format: Format) -> u64 {
+    match format {
+        Format::Tar => {
+            let blocks = entries.iter()
+                .map(|entry| 1 + entry.size.div_ceil(TAR_BLOCK as u64))
+                .sum::<u64>();
+            (blocks + 2) * TAR_BLOCK as u64
+        }
+        Format::Cpio => {
+            let record = |name: &str, size: u64| {
+                let header = CPIO_HEADER_SIZE + name.len() + 1;
+                (header + padding(header, 4)) as u64 + size.next_multiple_of(4)
+            };
+            entries.iter()
+                .map(|entry| record(&entry.name, entry.size))
+                .sum::<u64>()
+                + record(CPIO_TRAILER, 0)
+        }
+    }
+}
+
+fn read(entry: &Entry) -> Result<Vec<u8>> {
+    if entry.is_dir {
+        return Ok(Vec::new());
+    }
+    let data = fs::read(&entry.path)
+        .with_context(
+            || format!("could not open file `{}`", entry.path.display())
+        )?;
+    if data.len() as u64 != entry.size {
+        bail!("File `{}` changed while it was archived", entry.path.display());
+    }
+    Ok(data)
+}
+
+/// Writes `value` as a NUL-terminated octal field filling `field`
+fn octal(field: &mut [u8], value: u64) -> Result<()> {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    if digits.len() >= field.len() {
+        bail!("Value {} does not fit in a tar header", value);
+    }
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+    Ok(())
+}
+
+fn tar_header(entry: &Entry, mtime: u64) -> Result<[u8; TAR_BLOCK]> {
+    let mut header = [0; TAR_BLOCK];
+    let name = if entry.is_dir { format!("{}/", entry.name) } else { entry.name.clone() };
+
+    // Names too long for the name field are split into the prefix field
+    let (prefix, name) = match name.len() {
+        0..=100 => ("", name.as_str()),
+        _ => match name[..name.len() - 1].rfind('/') {
+            Some(split) if split <= 155 && name.len() - split - 1 <= 100 => {
+                (&name[..split], &name[split + 1..])
+            }
+            _ => bail!("Name `{}` is too long for a tar archive", name),
+        },
+    };
+
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], entry.permissions.into())?;
+    octal(&mut header[108..116], 0)?;
+    octal(&mut header[116..124], 0)?;
+    octal(&mut header[124..136], entry.size)?;
+    octal(&mut header[136..148], mtime)?;
+    header[156] = if entry.is_dir { b'5' } else { b'0' };
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum = header.iter().map(|&byte| u64::from(byte)).sum::<u64>();
+    octal(&mut header[148..155], checksum)?;
+
+    Ok(header)
+}
+
+fn cpio_record(archive: &mut Vec<u8>, ino: usize, mode: u32, mtime: u32, name: &str, data: &[u8]) {
+    let nlink = if mode & MODE_DIR != 0 { 2 } else { 1 };
+    let fields = [
+        ino as u32, mode, 0, 0, nlink, mtime, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0,
+    ];
+
+    let start = archive.len();
+    archive.extend_from_slice(CPIO_MAGIC.as_bytes());
+    for field in &fields {
+        archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len() + padding(archive.len() - start, 4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len() + padding(data.len(), 4), 0);
+}
+
+/// Packs the entries into an archive, all of them modified at `mtime`
+pub fn pack(entries: &[Entry], format: Format, mtime: u64) -> Result<Vec<u8>> {
+    let mut archive = Vec::new();
+
+    match format {
+        Format::Tar => {
+            for entry in entries {
+                archive.extend_from_slice(&tar_header(entry, mtime)?);
+                archive.extend_from_slice(&read(entry)?);
+                archive.resize(archive.len() + padding(archive.len(), TAR_BLOCK), 0);
+            }
+            archive.resize(archive.len() + 2 * TAR_BLOCK, 0);
+        }
+        Format::Cpio => {
+            let mtime = u32::try_from(mtime)
+                .with_context(
+                    || format!("Timestamp {} does not fit in a cpio archive", mtime)
+                )?;
+            for (index, entry) in entries.iter().enumerate() {
+                if u32::try_from(entry.size).is_err() {
+                    bail!("File `{}` is too large for a cpio archive", entry.path.display());
+                }
+                let kind = if entry.is_dir { MODE_DIR } else { MODE_FILE };
+                cpio_record(&mut archive, index + 1, kind | entry.permissions, mtime, &entry.name, &read(entry)?);
+            }
+            cpio_record(&mut archive, 0, 0, 0, CPIO_TRAILER, &[]);
+        }
+    }
+
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, is_dir: bool, permissions: u32, size: u64) -> Entry {
+        Entry { name: name.to_string(), path: path::PathBuf::new(), is_dir, permissions, size }
+    }
+
+    #[test]
+    fn tar_headers() {
+        let header = tar_header(&entry("etc/hostname", false, 0o644, 7), 0).unwrap();
+        assert_eq!(&header[..13], b"etc/hostname\0");
+        assert_eq!(&header[100..108], b"0000644\0");
+        assert_eq!(&header[124..136], b"00000000007\0");
+        assert_eq!(&header[148..156], b"010256\0 ");
+        assert_eq!(header[156], b'0');
+        assert_eq!(&header[257..265], b"ustar\x0000");
+
+        let header = tar_header(&entry("etc", true, 0o755, 0), 0).unwrap();
+        assert_eq!(&header[..5], b"etc/\0");
+        assert_eq!(&header[148..156], b"006520\0 ");
+        assert_eq!(header[156], b'5');
+    }
+
+    #[test]
+    fn tar_long_names() {
+        let name = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        let header = tar_header(&entry(&name, false, 0o644, 0), 0).unwrap();
+        assert_eq!(&header[..90], "f".repeat(90).as_bytes());
+        assert_eq!(&header[345..465], "d".repeat(120).as_bytes());
+
+        let name = format!("{}/{}", "d".repeat(120), "f".repeat(101));
+        assert!(tar_header(&entry(&name, false, 0o644, 0), 0).is_err());
+    }
+
+    #[test]
+    fn cpio_records() {
+        let mut archive = Vec::new();
+        cpio_record(&mut archive, 2, MODE_FILE | 0o644, 0, "etc/hostname", b"device\n");
+        let expected = concat!(
+            "070701", "00000002", "000081a4", "00000000", "00000000", "00000001", "00000000",
+            "00000007", "00000000", "00000000", "00000000", "00000000", "0000000d", "00000000",
+            "etc/hostname\0", "\0", "device\n", "\0",
+        );
+        assert_eq!(archive, expected.as_bytes());
+
+        let mut archive = Vec::new();
+        cpio_record(&mut archive, 0, 0, 0, CPIO_TRAILER, &[]);
+        assert_eq!(archive.len(), 124);
+        assert_eq!(&archive[94..102], b"0000000b");
+        assert_eq!(&archive[110..], b"TRAILER!!!\0\0\0\0");
+    }
+
+    #[test]
+    fn sizes() {
+        let entries = [entry("etc", true, 0o755, 0)];
+        for format in [Format::Tar, Format::Cpio] {
+            assert_eq!(pack(&entries, format, 0).unwrap().len() as u64, size(&entries, format));
+        }
+    }
+}
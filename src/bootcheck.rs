@@ -0,0 +1,107 @@
+//! Host-side emulation of the checks a bootloader does on an image before
+//! booting it, run by the `bootcheck` subcommand. The layout tells where the
+//! checked areas are:
+//!
+//! - `mcuboot` validates the slot hashed by every `mcuboot_tlv` statement:
+//!   the image header, the TLV areas and the SHA-256 entry
+//! - `crc-header` recomputes the CRC of every `crc16` and `crc16_hex`
+//!   statement over the image and compares it with the stored one
+//!
+//! Checks run in the order of the statements and stop at the first one
+//! that fails, as the bootloader would.
+
+use anyhow::{bail, Context as _, Result};
+use clap::ValueEnum;
+use std::convert::TryInto;
+use std::fs;
+use std::io::Cursor;
+use std::path;
+
+use crate::digest;
+use crate::layout::{self, CheckError, Context, Region};
+use crate::mcuboot;
+
+/// The bootloader whose checks are emulated
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Profile {
+    Mcuboot,
+    CrcHeader,
+}
+
+/// Returns the bytes of the range, failing if the image is too short
+fn range(image: &[u8], start: u64, len: u64) -> Result<&[u8]> {
+    let start: usize = start.try_into()?;
+    let end = start.checked_add(len.try_into()?);
+    match end.and_then(|end| image.get(start..end)) {
+        Some(bytes) => Ok(bytes),
+        None => bail!(CheckError::Mismatch(format!(
+            "0x{:x}..0x{:x} is past the end of the image of 0x{:x} bytes",
+            start, start as u64 + len, image.len()
+        ))),
+    }
+}
+
+/// Recomputes the CRC the statement has written, returns the stored and
+/// the recomputed bytes
+fn crc16(image: &[u8], region: &Region) -> Result<(Vec<u8>, Vec<u8>)> {
+    let crc = crc::Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
+    let mut digest = crc.digest();
+    for &(start, len) in &region.reads {
+        digest.update(range(image, start, len)?);
+    }
+    let value = digest.finalize();
+    let expected = if region.func.ends_with("_hex") {
+        format!("{:04x}", value).into_bytes()
+    }
+    else {
+        value.to_le_bytes().to_vec()
+    };
+
+    Ok((range(image, region.addr, region.size)?.to_vec(), expected))
+}
+
+/// Runs the checks of the profile on the image built from the layout
+pub fn run(rpath: &path::Path, ipath: &path::Path, profile: Profile, mut ctx: Context) -> Result<()> {
+    let image = fs::read(ipath)
+        .with_context(
+            || format!("could not read file `{}`", ipath.display())
+        )?;
+    ctx.dry_run = true;
+    let regions = layout::run(rpath, &mut ctx, &mut Cursor::new(Vec::new()))?;
+
+    let mut checked = 0;
+    for region in &regions {
+        match (profile, region.func.as_str()) {
+            (Profile::Mcuboot, "mcuboot_tlv") => {
+                let start = region.reads.first().map_or(region.addr, |&(start, _)| start);
+                let passed = mcuboot::validate(&image, start.try_into()?)
+                    .map_err(|err| CheckError::Mismatch(format!("{}: {}", region.name, err)))?;
+                for check in passed {
+                    println!("{}: {}", region.name, check);
+                }
+            }
+            (Profile::CrcHeader, "crc16" | "crc16_hex") => {
+                let (stored, expected) = crc16(&image, region)?;
+                if stored != expected {
+                    bail!(CheckError::Mismatch(format!(
+                        "{}: CRC at 0x{:x} is {}, the data has {}",
+                        region.name, region.addr, digest::to_hex(&stored), digest::to_hex(&expected)
+                    )));
+                }
+                let ranges = region.reads.iter()
+                    .map(|&(start, len)| format!("0x{:x}..0x{:x}", start, start + len))
+                    .collect::<Vec<String>>();
+                println!("{}: CRC of {}", region.name, ranges.join(", "));
+            }
+            _ => continue,
+        }
+        checked += 1;
+    }
+
+    if checked == 0 {
+        bail!("The layout has no statements the profile checks");
+    }
+    println!("{}: the bootloader would accept the image", ipath.display());
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! C interface for build systems linking the engine instead of running the
+//! command, declared in `include/bincomb.h`.
+
+use anyhow::{anyhow, Result};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::io::{Cursor, Write};
+use std::panic;
+use std::path;
+use std::ptr;
+use std::slice;
+
+use crate::diagnostics;
+use crate::layout::{self, Context};
+use crate::output;
+
+thread_local! {
+    /// Message of the last failed call on the thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: String) {
+    // Messages don't contain NUL unless a path does
+    let message = CString::new(message.replace('\0', "\\0")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Converts a C string argument, NULL is an error
+unsafe fn text<'a>(arg: *const c_char, what: &str) -> Result<&'a str> {
+    if arg.is_null() {
+        return Err(anyhow!("The {} is NULL", what));
+    }
+    Ok(CStr::from_ptr(arg).to_str()?)
+}
+
+fn build(rpath: &path::Path, wpath: &path::Path, defines: &[&str]) -> Result<()> {
+    let defines = defines.iter()
+        .map(|define| layout::parse_define(define))
+        .collect::<Result<Vec<_>>>()?;
+    let mut ctx = Context::new(&defines);
+    ctx.base = layout::base_dir(rpath);
+
+    let mut image = Cursor::new(Vec::new());
+    layout::run(rpath, &mut ctx, &mut image)?;
+    output::atomic(wpath, false, |outf| Ok(outf.write_all(image.get_ref())?))?;
+
+    Ok(())
+}
+
+/// Builds the image like `bincomb LAYOUT OUTPUT -D NAME=VALUE...` and
+/// returns 0 on success or the exit code the command would fail with, the
+/// message is then returned by `bincomb_last_error`
+///
+/// # Safety
+///
+/// `layout` and `output` must be NUL-terminated strings and `defines` an
+/// array of `count` of them, it may be NULL if `count` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn bincomb_build(
+    layout: *const c_char,
+    output: *const c_char,
+    defines: *const *const c_char,
+    count: usize,
+) -> c_int {
+    let args = || -> Result<(&str, &str, Vec<&str>)> {
+        let defines = match count {
+            0 => Vec::new(),
+            _ if defines.is_null() => return Err(anyhow!("The defines are NULL")),
+            _ => slice::from_raw_parts(defines, count)
+                .iter()
+                .map(|&define| text(define, "define"))
+                .collect::<Result<Vec<&str>>>()?,
+        };
+        Ok((text(layout, "layout path")?, text(output, "output path")?, defines))
+    };
+
+    let result = args().and_then(|(layout, output, defines)| {
+        panic::catch_unwind(|| build(path::Path::new(layout), path::Path::new(output), &defines))
+            .unwrap_or_else(|_| Err(anyhow!("Internal error, the build panicked")))
+    });
+
+    match result {
+        Ok(()) => {
+            LAST_ERROR.with(|last| *last.borrow_mut() = None);
+            0
+        }
+        Err(err) => {
+            set_error(format!("{:#}", err));
+            diagnostics::exit_code(&err)
+        }
+    }
+}
+
+/// Returns the message of the last failed call on the thread, or NULL if
+/// it succeeded. The string is valid until the next call.
+#[no_mangle]
+pub extern "C" fn bincomb_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn last_error() -> Option<String> {
+        let message = bincomb_last_error();
+        (!message.is_null()).then(|| unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn builds() {
+        let dir = env::temp_dir().join(format!("bincomb-capi-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.bin"), b"abcd").unwrap();
+        fs::write(dir.join("layout.txt"), b"0x0:A:file,a.bin\n0x8:B:file,$INPUT\n").unwrap();
+
+        let layout = CString::new(dir.join("layout.txt").to_str().unwrap()).unwrap();
+        let output = CString::new(dir.join("out.bin").to_str().unwrap()).unwrap();
+        let define = CString::new("INPUT=a.bin").unwrap();
+        let defines = [define.as_ptr()];
+
+        let code = unsafe { bincomb_build(layout.as_ptr(), output.as_ptr(), defines.as_ptr(), defines.len()) };
+        assert_eq!((code, last_error()), (0, None));
+        assert_eq!(fs::read(dir.join("out.bin")).unwrap(), b"abcd\0\0\0\0abcd");
+
+        // Codes are those the command exits with
+        let missing = CString::new(dir.join("missing.txt").to_str().unwrap()).unwrap();
+        let code = unsafe { bincomb_build(missing.as_ptr(), output.as_ptr(), ptr::null(), 0) };
+        assert_eq!(code, 4);
+        assert!(last_error().is_some_and(|message| message.contains("missing.txt")));
+
+        let code = unsafe { bincomb_build(ptr::null(), output.as_ptr(), ptr::null(), 0) };
+        assert_eq!((code, last_error().as_deref()), (1, Some("The layout path is NULL")));
+        let code = unsafe { bincomb_build(layout.as_ptr(), output.as_ptr(), ptr::null(), 1) };
+        assert_eq!((code, last_error().as_deref()), (1, Some("The defines are NULL")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
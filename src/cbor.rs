@@ -0,0 +1,127 @@
+//! Canonical CBOR encoding of metadata maps.
+//!
+//! Keys are text strings sorted by their encoding as RFC 8949 requires for
+//! deterministic encoding, so equal maps always produce equal bytes.
+
+use anyhow::{bail, Result};
+
+use crate::value::Value;
+
+const MAJOR_UINT: u8 = 0;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_MAP: u8 = 5;
+
+/// Writes the head of an item in its shortest form
+fn head(out: &mut Vec<u8>, major: u8, arg: u64) {
+    let major = major << 5;
+    if arg < 24 {
+        out.push(major | arg as u8);
+    }
+    else if arg <= u8::MAX.into() {
+        out.push(major | 24);
+        out.push(arg as u8);
+    }
+    else if arg <= u16::MAX.into() {
+        out.push(major | 25);
+        out.extend_from_slice(&(arg as u16).to_be_bytes());
+    }
+    else if arg <= u32::MAX.into() {
+        out.push(major | 26);
+        out.extend_from_slice(&(arg as u32).to_be_bytes());
+    }
+    else {
+        out.push(major | 27);
+        out.extend_from_slice(&arg.to_be_bytes());
+    }
+}
+
+fn text(out: &mut Vec<u8>, s: &str) {
+    head(out, MAJOR_TEXT, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Int(number) => head(out, MAJOR_UINT, *number),
+        Value::Str(s) => text(out, s),
+        Value::Bytes(bytes) => {
+            head(out, MAJOR_BYTES, bytes.len() as u64);
+            out.extend_from_slice(bytes);
+        }
+    }
+}
+
+/// Encodes the map with text keys
+pub fn map(entries: &[(String, Value)]) -> Result<Vec<u8>> {
+    let mut pairs = entries.iter()
+        .map(|(key, item)| {
+            let mut encoded_key = Vec::new();
+            text(&mut encoded_key, key);
+            let mut encoded_value = Vec::new();
+            value(&mut encoded_value, item);
+            (encoded_key, encoded_value, key)
+        })
+        .collect::<Vec<(Vec<u8>, Vec<u8>, &String)>>();
+    pairs.sort();
+
+    if let Some(pair) = pairs.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        bail!("Duplicate key {} in the map", pair[0].2);
+    }
+
+    let mut out = Vec::new();
+    head(&mut out, MAJOR_MAP, pairs.len() as u64);
+    for (key, item, _) in pairs {
+        out.extend_from_slice(&key);
+        out.extend_from_slice(&item);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(item: &Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        value(&mut out, item);
+        out
+    }
+
+    // Examples of RFC 8949 appendix A
+    #[test]
+    fn items() {
+        assert_eq!(encode(&Value::Int(0)), [0x00]);
+        assert_eq!(encode(&Value::Int(23)), [0x17]);
+        assert_eq!(encode(&Value::Int(24)), [0x18, 0x18]);
+        assert_eq!(encode(&Value::Int(1000)), [0x19, 0x03, 0xe8]);
+        assert_eq!(encode(&Value::Int(1_000_000)), [0x1a, 0x00, 0x0f, 0x42, 0x40]);
+        assert_eq!(
+            encode(&Value::Int(1_000_000_000_000)),
+            [0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00]
+        );
+        assert_eq!(encode(&Value::Str(String::new())), [0x60]);
+        assert_eq!(encode(&Value::Str("IETF".to_string())), [0x64, 0x49, 0x45, 0x54, 0x46]);
+        assert_eq!(encode(&Value::Bytes(vec![1, 2, 3, 4])), [0x44, 0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn sorted_maps() {
+        assert_eq!(map(&[]).unwrap(), [0xa0]);
+        let entries = [
+            ("aa".to_string(), Value::Int(2)),
+            ("b".to_string(), Value::Int(1)),
+        ];
+        assert_eq!(map(&entries).unwrap(), [0xa2, 0x61, 0x62, 0x01, 0x62, 0x61, 0x61, 0x02]);
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let entries = [
+            ("a".to_string(), Value::Int(1)),
+            ("a".to_string(), Value::Int(2)),
+        ];
+        assert!(map(&entries).is_err());
+    }
+}
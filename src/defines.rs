@@ -0,0 +1,127 @@
+//! Variables defined in TOML, JSON or YAML files given with `--defines-file`
+//! and in `.env` files.
+
+use anyhow::{bail, Context as _, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path;
+
+use crate::layout::{parse_define, Value};
+
+/// A value in a defines file, strings are evaluated like `-D` values
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Raw {
+    Number(u64),
+    Expression(String),
+    Table(BTreeMap<String, Raw>),
+}
+
+/// Flattens nested tables into `prefix.name` definitions
+fn flatten(prefix: &str, table: BTreeMap<String, Raw>, defines: &mut Vec<(String, String)>) {
+    for (name, value) in table {
+        let name = if prefix.is_empty() { name } else { format!("{}.{}", prefix, name) };
+        match value {
+            Raw::Number(number) => defines.push((name, number.to_string())),
+            Raw::Expression(expr) => defines.push((name, expr)),
+            Raw::Table(table) => flatten(&name, table, defines),
+        }
+    }
+}
+
+/// Reads the `NAME = VALUE` table from the file, the format is chosen by its
+/// extension. Nested tables define namespaced names like `app.version`.
+pub fn load(rpath: &path::Path) -> Result<Vec<(String, Value)>> {
+    let text = fs::read_to_string(rpath)
+        .with_context(
+            || format!("could not open file `{}`", rpath.display())
+        )?;
+
+    let extension = rpath.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let table: BTreeMap<String, Raw> = match extension.as_str() {
+        "toml" => toml::from_str(&text).map_err(anyhow::Error::from),
+        "json" => serde_json::from_str(&text).map_err(anyhow::Error::from),
+        "yaml" | "yml" => serde_yaml::from_str(&text).map_err(anyhow::Error::from),
+        _ => bail!("Unknown format of `{}`, expected .toml, .json or .yaml", rpath.display()),
+    }
+    .with_context(
+        || format!("could not parse file `{}`", rpath.display())
+    )?;
+
+    let mut flat = Vec::new();
+    flatten("", table, &mut flat);
+
+    flat.into_iter()
+        .map(|(name, value)| {
+            parse_define(&format!("{}={}", name, value))
+                .with_context(
+                    || format!("invalid define `{}` in `{}`", name, rpath.display())
+                )
+        })
+        .collect()
+}
+
+/// Reads `KEY=value` lines from a `.env` file, values may be quoted and
+/// lines may start with `export`
+pub fn load_env(rpath: &path::Path) -> Result<Vec<(String, Value)>> {
+    let text = fs::read_to_string(rpath)
+        .with_context(
+            || format!("could not open file `{}`", rpath.display())
+        )?;
+
+    let mut defines = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let define = match line.split_once('=') {
+            Some((name, value)) => {
+                let value = value.trim();
+                let unquoted = value.strip_prefix('"').and_then(|v| v.strip_suffix('"'))
+                    .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                    .unwrap_or(value);
+                format!("{}={}", name, unquoted)
+            }
+            None => line.to_string(),
+        };
+        defines.push(
+            parse_define(&define)
+                .with_context(
+                    || format!("invalid line {} in `{}`", index + 1, rpath.display())
+                )?
+        );
+    }
+
+    Ok(defines)
+}
+
+/// Defines from the `.env` file, then from the defines files, then the ones
+/// given with `-D`, later ones take precedence
+pub fn merge(
+    env: Option<&path::Path>,
+    files: &[path::PathBuf],
+    overrides: &[(String, Value)],
+) -> Result<Vec<(String, Value)>> {
+    let mut defines: Vec<(String, Value)> = match env {
+        Some(env) => load_env(env)?,
+        None => Vec::new(),
+    };
+
+    for file in files {
+        for (name, value) in load(file)? {
+            defines.retain(|(other, _)| *other != name);
+            defines.push((name, value));
+        }
+    }
+
+    defines.retain(|(name, _)| !overrides.iter().any(|(other, _)| other == name));
+    defines.extend(overrides.iter().cloned());
+
+    Ok(defines)
+}
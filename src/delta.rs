@@ -0,0 +1,708 @@
+//! Delta patches between two images in the VCDIFF format of RFC 3284, as
+//! read by `xdelta3 -d` and open-vcdiff.
+//!
+//! Matches of the new image in the old one are found with the bsdiff
+//! algorithm. The bytes of the new image equal to those of the matched
+//! ones are copied from the old image, the others are added, long runs of
+//! one byte are written as runs. Patches use the default code table without
+//! secondary compression. The application header holds the SHA-256 of both
+//! images as `sha256 OLD NEW` in hex, `apply` checks them when present.
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+use crate::digest;
+
+const MAGIC: [u8; 4] = [0xd6, 0xc3, 0xc4, 0x00];
+
+/// Bits of the header indicator
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+/// Bits of the window indicator, the checksum is an extension of xdelta3
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+
+/// Bytes of the new image encoded by a window, decoders limit its size
+const WINDOW_SIZE: usize = 1 << 22;
+
+/// Matching bytes shorter than this are cheaper to add than to copy
+const MIN_COPY: usize = 8;
+
+/// Repeated bytes shorter than this are added instead of a run
+const MIN_RUN: usize = 16;
+
+/// The near and same caches of addresses of the default code table
+const NEAR_SIZE: usize = 4;
+const SAME_SIZE: usize = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Noop,
+    Add,
+    Run,
+    Copy,
+}
+
+/// An instruction of the code table, a size of 0 is read from the
+/// instructions
+#[derive(Clone, Copy)]
+struct Instruction {
+    kind: Kind,
+    size: usize,
+    mode: usize,
+}
+
+/// An instruction of the new image, before it is split into windows
+enum Op {
+    /// Bytes of the new image from the offset
+    Add(usize, usize),
+    /// Bytes of the old image from the offset
+    Copy(usize, usize),
+}
+
+/// Sorts the suffixes of the data, including the empty one, by prefix
+/// doubling
+fn suffix_array(data: &[u8]) -> Vec<usize> {
+    let n = data.len() + 1;
+    let mut sa = (0..n).collect::<Vec<usize>>();
+    // The empty suffix ranks below every byte
+    let mut rank = (0..n)
+        .map(|i| data.get(i).map_or(0, |&byte| usize::from(byte) + 1))
+        .collect::<Vec<usize>>();
+    let mut next = vec![0; n];
+
+    let mut k = 1;
+    loop {
+        let key = |i: usize| (rank[i], rank.get(i + k).copied().unwrap_or(0));
+        sa.sort_unstable_by_key(|&i| key(i));
+
+        next[sa[0]] = 0;
+        for pair in sa.windows(2) {
+            next[pair[1]] = next[pair[0]] + usize::from(key(pair[0]) != key(pair[1]));
+        }
+        std::mem::swap(&mut rank, &mut next);
+
+        if rank[sa[n - 1]] == n - 1 {
+            return sa;
+        }
+        k *= 2;
+    }
+}
+
+fn match_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// Finds the longest match of `new` in the old data, returns its length and
+/// position
+fn search(sa: &[usize], old: &[u8], new: &[u8], mut start: usize, mut end: usize) -> (usize, usize) {
+    while end - start >= 2 {
+        let mid = start + (end - start) / 2;
+        let suffix = &old[sa[mid]..];
+        let len = suffix.len().min(new.len());
+        if suffix[..len].cmp(&new[..len]) == Ordering::Less {
+            start = mid;
+        }
+        else {
+            end = mid;
+        }
+    }
+
+    let first = match_len(&old[sa[start]..], new);
+    let second = match_len(&old[sa[end]..], new);
+    if first > second { (first, sa[start]) } else { (second, sa[end]) }
+}
+
+/// Computes the VCDIFF patch turning `old` into `new`
+pub fn diff(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let sa = suffix_array(old);
+    let old_len = old.len() as i64;
+    let new_len = new.len() as i64;
+    let at = |data: &[u8], i: i64| data[i as usize];
+
+    let mut ops = Vec::new();
+
+    let (mut scan, mut len, mut pos) = (0i64, 0i64, 0i64);
+    let (mut last_scan, mut last_pos, mut last_offset) = (0i64, 0i64, 0i64);
+
+    while scan < new_len {
+        let mut old_score = 0i64;
+        scan += len;
+        let mut scsc = scan;
+
+        // Look for a match that is better than continuing the last one
+        while scan < new_len {
+            let (found, found_pos) = search(&sa, old, &new[scan as usize..], 0, old.len());
+            len = found as i64;
+            pos = found_pos as i64;
+
+            while scsc < scan + len {
+                if scsc + last_offset < old_len && at(old, scsc + last_offset) == at(new, scsc) {
+                    old_score += 1;
+                }
+                scsc += 1;
+            }
+            if (len == old_score && len != 0) || len > old_score + 8 {
+                break;
+            }
+            if scan + last_offset < old_len && at(old, scan + last_offset) == at(new, scan) {
+                old_score -= 1;
+            }
+            scan += 1;
+        }
+
+        if len == old_score && scan != new_len {
+            continue;
+        }
+
+        // Extend the last match forwards and the new one backwards as long
+        // as most bytes still match
+        let (mut score, mut best, mut len_forward) = (0i64, 0i64, 0i64);
+        let mut i = 0;
+        while last_scan + i < scan && last_pos + i < old_len {
+            if at(old, last_pos + i) == at(new, last_scan + i) {
+                score += 1;
+            }
+            i += 1;
+            if score * 2 - i > best * 2 - len_forward {
+                best = score;
+                len_forward = i;
+            }
+        }
+
+        let mut len_back = 0i64;
+        if scan < new_len {
+            let (mut score, mut best) = (0i64, 0i64);
+            let mut i = 1;
+            while scan >= last_scan + i && pos >= i {
+                if at(old, pos - i) == at(new, scan - i) {
+                    score += 1;
+                }
+                if score * 2 - i > best * 2 - len_back {
+                    best = score;
+                    len_back = i;
+                }
+                i += 1;
+            }
+        }
+
+        // Split an overlap of both extensions where it scores best
+        if last_scan + len_forward > scan - len_back {
+            let overlap = last_scan + len_forward - (scan - len_back);
+            let (mut score, mut best, mut len_split) = (0i64, 0i64, 0i64);
+            for i in 0..overlap {
+                if at(new, last_scan + len_forward - overlap + i) == at(old, last_pos + len_forward - overlap + i) {
+                    score += 1;
+                }
+                if at(new, scan - len_back + i) == at(old, pos - len_back + i) {
+                    score -= 1;
+                }
+                if score > best {
+                    best = score;
+                    len_split = i + 1;
+                }
+            }
+            len_forward += len_split - overlap;
+            len_back -= len_split;
+        }
+
+        // bsdiff would store the difference to the matched bytes, the
+        // equal ones are copied instead
+        let (new_start, old_start) = (last_scan as usize, last_pos as usize);
+        let mut i = 0;
+        while i < len_forward as usize {
+            let equal = match_len(&old[old_start + i..old_start + len_forward as usize], &new[new_start + i..]);
+            if equal >= MIN_COPY {
+                ops.push(Op::Copy(old_start + i, equal));
+                i += equal;
+            }
+            else {
+                push_add(&mut ops, new_start + i, equal.max(1));
+                i += equal.max(1);
+            }
+        }
+        let extra = (last_scan + len_forward) as usize..(scan - len_back) as usize;
+        if !extra.is_empty() {
+            push_add(&mut ops, extra.start, extra.len());
+        }
+
+        last_scan = scan - len_back;
+        last_pos = pos - len_back;
+        last_offset = pos - scan;
+    }
+
+    encode(old, new, &ops)
+}
+
+
+/// Adds bytes of the new image, merged with the bytes added before them
+fn push_add(ops: &mut Vec<Op>, start: usize, len: usize) {
+    if let Some(Op::Add(last, last_len)) = ops.last_mut() {
+        if *last + *last_len == start {
+            *last_len += len;
+            return;
+        }
+    }
+    ops.push(Op::Add(start, len));
+}
+
+fn push_varint(out: &mut Vec<u8>, value: u64) {
+    let mut digits = vec![(value & 0x7f) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        digits.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.extend(digits.iter().rev());
+}
+
+/// The sections of a window being encoded
+#[derive(Default)]
+struct Window {
+    size: usize,
+    data: Vec<u8>,
+    instructions: Vec<u8>,
+    addresses: Vec<u8>,
+}
+
+impl Window {
+    fn add(&mut self, bytes: &[u8]) {
+        // The code table has sizes 1 to 17 of lone adds
+        if bytes.len() <= 17 {
+            self.instructions.push(1 + bytes.len() as u8);
+        }
+        else {
+            self.instructions.push(1);
+            push_varint(&mut self.instructions, bytes.len() as u64);
+        }
+        self.data.extend_from_slice(bytes);
+        self.size += bytes.len();
+    }
+
+    fn run(&mut self, byte: u8, len: usize) {
+        self.instructions.push(0);
+        push_varint(&mut self.instructions, len as u64);
+        self.data.push(byte);
+        self.size += len;
+    }
+
+    /// Copies from the old image with addresses in the `VCD_SELF` mode
+    fn copy(&mut self, addr: usize, len: usize) {
+        // The code table has sizes 4 to 18 of lone copies of mode 0
+        if (4..=18).contains(&len) {
+            self.instructions.push(16 + len as u8);
+        }
+        else {
+            self.instructions.push(19);
+            push_varint(&mut self.instructions, len as u64);
+        }
+        push_varint(&mut self.addresses, addr as u64);
+        self.size += len;
+    }
+
+    /// Adds the bytes, with long runs of one byte as runs
+    fn add_runs(&mut self, bytes: &[u8]) {
+        let mut start = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let len = bytes[i..].iter().take_while(|&&byte| byte == bytes[i]).count();
+            if len >= MIN_RUN {
+                if start < i {
+                    self.add(&bytes[start..i]);
+                }
+                self.run(bytes[i], len);
+                start = i + len;
+            }
+            i += len;
+        }
+        if start < bytes.len() {
+            self.add(&bytes[start..]);
+        }
+    }
+
+    fn write(&self, patch: &mut Vec<u8>, source_len: usize) {
+        if source_len > 0 {
+            patch.push(VCD_SOURCE);
+            push_varint(patch, source_len as u64);
+            push_varint(patch, 0);
+        }
+        else {
+            patch.push(0);
+        }
+
+        let mut encoding = Vec::new();
+        push_varint(&mut encoding, self.size as u64);
+        encoding.push(0);
+        push_varint(&mut encoding, self.data.len() as u64);
+        push_varint(&mut encoding, self.instructions.len() as u64);
+        push_varint(&mut encoding, self.addresses.len() as u64);
+        encoding.extend_from_slice(&self.data);
+        encoding.extend_from_slice(&self.instructions);
+        encoding.extend_from_slice(&self.addresses);
+
+        push_varint(patch, encoding.len() as u64);
+        patch.extend_from_slice(&encoding);
+    }
+}
+
+/// Writes the instructions as windows of the whole old image and at most
+/// `WINDOW_SIZE` bytes of the new one
+fn encode(old: &[u8], new: &[u8], ops: &[Op]) -> Vec<u8> {
+    let mut patch = MAGIC.to_vec();
+    let header = format!("sha256 {} {}", digest::to_hex(&Sha256::digest(old)), digest::to_hex(&Sha256::digest(new)));
+    patch.push(VCD_APPHEADER);
+    push_varint(&mut patch, header.len() as u64);
+    patch.extend_from_slice(header.as_bytes());
+
+    let mut window = Window::default();
+    for op in ops {
+        let (mut start, mut len) = match *op {
+            Op::Add(start, len) | Op::Copy(start, len) => (start, len),
+        };
+        while len > 0 {
+            let part = len.min(WINDOW_SIZE - window.size);
+            match op {
+                Op::Add(..) => window.add_runs(&new[start..start + part]),
+                Op::Copy(..) => window.copy(start, part),
+            }
+            start += part;
+            len -= part;
+            if window.size == WINDOW_SIZE {
+                window.write(&mut patch, old.len());
+                window = Window::default();
+            }
+        }
+    }
+    if window.size > 0 {
+        window.write(&mut patch, old.len());
+    }
+
+    patch
+}
+
+/// The default code table of RFC 3284, section 5.6
+fn code_table() -> Vec<[Instruction; 2]> {
+    let inst = |kind, size, mode| Instruction { kind, size, mode };
+    let noop = inst(Kind::Noop, 0, 0);
+    let mut table = vec![[inst(Kind::Run, 0, 0), noop]];
+
+    for size in 0..=17 {
+        table.push([inst(Kind::Add, size, 0), noop]);
+    }
+    for mode in 0..=8 {
+        table.push([inst(Kind::Copy, 0, mode), noop]);
+        for size in 4..=18 {
+            table.push([inst(Kind::Copy, size, mode), noop]);
+        }
+    }
+    for mode in 0..=5 {
+        for add in 1..=4 {
+            for copy in 4..=6 {
+                table.push([inst(Kind::Add, add, 0), inst(Kind::Copy, copy, mode)]);
+            }
+        }
+    }
+    for mode in 6..=8 {
+        for add in 1..=4 {
+            table.push([inst(Kind::Add, add, 0), inst(Kind::Copy, 4, mode)]);
+        }
+    }
+    for mode in 0..=8 {
+        table.push([inst(Kind::Copy, 4, mode), inst(Kind::Add, 1, 0)]);
+    }
+
+    table
+}
+
+/// Reads a section of the patch
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() {
+            bail!("The patch is truncated");
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        loop {
+            let byte = self.byte()?;
+            if value.leading_zeros() < 7 {
+                bail!("The patch is corrupted");
+            }
+            value = value << 7 | u64::from(byte & 0x7f);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn size(&mut self) -> Result<usize> {
+        Ok(usize::try_from(self.varint()?)?)
+    }
+}
+
+/// The address caches of a window
+struct Cache {
+    near: [usize; NEAR_SIZE],
+    next_slot: usize,
+    same: [usize; SAME_SIZE * 256],
+}
+
+impl Cache {
+    fn new() -> Cache {
+        Cache { near: [0; NEAR_SIZE], next_slot: 0, same: [0; SAME_SIZE * 256] }
+    }
+
+    /// Decodes the address of a copy at `here` in the window
+    fn decode(&mut self, addresses: &mut Reader, here: usize, mode: usize) -> Result<usize> {
+        let addr = match mode {
+            0 => addresses.size()?,
+            1 => here.checked_sub(addresses.size()?).unwrap_or(usize::MAX),
+            _ if mode < 2 + NEAR_SIZE => self.near[mode - 2].saturating_add(addresses.size()?),
+            _ => self.same[(mode - 2 - NEAR_SIZE) * 256 + usize::from(addresses.byte()?)],
+        };
+        if addr >= here {
+            bail!("The patch is corrupted");
+        }
+
+        self.near[self.next_slot] = addr;
+        self.next_slot = (self.next_slot + 1) % NEAR_SIZE;
+        self.same[addr % (SAME_SIZE * 256)] = addr;
+        Ok(addr)
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + u32::from(byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+/// Checks the SHA-256 of the image against the one of the application
+/// header
+fn check_hash(header: &str, index: usize, image: &[u8], message: &str) -> Result<()> {
+    let expected = header.strip_prefix("sha256 ")
+        .and_then(|hashes| hashes.split(' ').nth(index));
+    if expected.is_some_and(|expected| expected != digest::to_hex(&Sha256::digest(image))) {
+        bail!("{}", message);
+    }
+    Ok(())
+}
+
+/// Applies the VCDIFF patch to `old`, checking that it was made for this
+/// image if the patch has the digests
+pub fn apply(old: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    if patch.get(..MAGIC.len()) != Some(&MAGIC[..]) {
+        bail!("Not a VCDIFF patch");
+    }
+    let mut reader = Reader { data: &patch[MAGIC.len()..] };
+
+    let indicator = reader.byte()?;
+    if indicator & VCD_DECOMPRESS != 0 {
+        bail!("Patches with secondary compression are not supported");
+    }
+    if indicator & VCD_CODETABLE != 0 {
+        bail!("Patches with their own code table are not supported");
+    }
+    let mut header = String::new();
+    if indicator & VCD_APPHEADER != 0 {
+        let len = reader.size()?;
+        header = String::from_utf8_lossy(reader.bytes(len)?).into_owned();
+    }
+    check_hash(&header, 0, old, "The patch was made for a different old image")?;
+
+    let table = code_table();
+    let mut new = Vec::new();
+    while !reader.data.is_empty() {
+        let indicator = reader.byte()?;
+        let segment = match indicator & (VCD_SOURCE | VCD_TARGET) {
+            0 => &[][..],
+            flags => {
+                let len = reader.size()?;
+                let start = reader.size()?;
+                let image = if flags == VCD_SOURCE { old } else { &new[..] };
+                match start.checked_add(len).and_then(|end| image.get(start..end)) {
+                    Some(segment) => segment,
+                    None => bail!("The patch is corrupted"),
+                }
+            }
+        };
+
+        let len = reader.size()?;
+        let mut encoding = Reader { data: reader.bytes(len)? };
+        let size = encoding.size()?;
+        if encoding.byte()? != 0 {
+            bail!("Patches with compressed sections are not supported");
+        }
+        let data_len = encoding.size()?;
+        let instructions_len = encoding.size()?;
+        let addresses_len = encoding.size()?;
+        let checksum = match indicator & VCD_ADLER32 {
+            0 => None,
+            _ => Some(u32::from_be_bytes([encoding.byte()?, encoding.byte()?, encoding.byte()?, encoding.byte()?])),
+        };
+        let mut data = Reader { data: encoding.bytes(data_len)? };
+        let mut instructions = Reader { data: encoding.bytes(instructions_len)? };
+        let mut addresses = Reader { data: encoding.bytes(addresses_len)? };
+
+        let mut window = Vec::with_capacity(size.min(WINDOW_SIZE));
+        let mut cache = Cache::new();
+        while !instructions.data.is_empty() {
+            let code = table[usize::from(instructions.byte()?)];
+            for inst in code.iter().filter(|inst| inst.kind != Kind::Noop) {
+                let len = match inst.size {
+                    0 => instructions.size()?,
+                    size => size,
+                };
+                if window.len().saturating_add(len) > size {
+                    bail!("The patch is corrupted");
+                }
+                match inst.kind {
+                    Kind::Add => window.extend_from_slice(data.bytes(len)?),
+                    Kind::Run => {
+                        let byte = data.byte()?;
+                        window.resize(window.len() + len, byte);
+                    }
+                    Kind::Copy => {
+                        let here = segment.len() + window.len();
+                        let addr = cache.decode(&mut addresses, here, inst.mode)?;
+                        // Copies from the window may overlap the bytes they write
+                        for pos in addr..addr + len {
+                            let byte = match segment.get(pos) {
+                                Some(&byte) => byte,
+                                None => window[pos - segment.len()],
+                            };
+                            window.push(byte);
+                        }
+                    }
+                    Kind::Noop => {}
+                }
+            }
+        }
+
+        if window.len() != size {
+            bail!("The patch is corrupted");
+        }
+        if checksum.is_some_and(|checksum| checksum != adler32(&window)) {
+            bail!("The checksum of a window of the patch does not match");
+        }
+        new.extend_from_slice(&window);
+    }
+
+    check_hash(&header, 1, &new, "The patched image does not match the new image")?;
+    Ok(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random bytes of a linear congruential generator
+    fn noise(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(old: &[u8], new: &[u8]) -> Vec<u8> {
+        let patch = diff(old, new);
+        assert_eq!(apply(old, &patch).unwrap(), new);
+        patch
+    }
+
+    #[test]
+    fn round_trips() {
+        let old = noise(1, 5000);
+        let mut new = old.clone();
+        new[100] ^= 0xff;
+        new.splice(2000..2000, noise(2, 300));
+        new.drain(4000..4200);
+        new.extend(vec![0xff; 1000]);
+
+        let patch = round_trip(&old, &new);
+        assert!(patch.len() < 1000, "patch of {} bytes", patch.len());
+        round_trip(&old, &old);
+        round_trip(&old, &noise(3, 3000));
+        round_trip(&old, b"");
+        round_trip(b"", &new);
+        round_trip(b"", b"");
+    }
+
+    #[test]
+    fn splits_windows() {
+        let old = noise(4, WINDOW_SIZE + 1000);
+        let mut new = noise(5, 100);
+        new.extend_from_slice(&old);
+        let patch = encode(&old, &new, &[Op::Add(0, 100), Op::Copy(0, old.len())]);
+        assert_eq!(apply(&old, &patch).unwrap(), new);
+    }
+
+    #[test]
+    fn encodes_vcdiff() {
+        let patch = diff(b"", b"abc");
+        let header = format!("sha256 {} {}", digest::to_hex(&Sha256::digest(b"")), digest::to_hex(&Sha256::digest(b"abc")));
+        // The header of 136 bytes has a length of two bytes
+        assert_eq!(header.len(), 136);
+        let mut expected = vec![0xd6, 0xc3, 0xc4, 0x00, VCD_APPHEADER, 0x81, 0x08];
+        expected.extend_from_slice(header.as_bytes());
+        // A window without a source: its encoding of 9 bytes adds 3 bytes
+        expected.extend_from_slice(&[0x00, 0x09, 0x03, 0x00, 0x03, 0x01, 0x00, b'a', b'b', b'c', 0x04]);
+        assert_eq!(patch, expected);
+    }
+
+    #[test]
+    fn applies_cached_addresses() {
+        // Copies in the here, near and same modes, and an add and copy
+        // pair of the default code table
+        let old = b"0123456789";
+        let mut patch = MAGIC.to_vec();
+        patch.push(0);
+        let window = [
+            VCD_SOURCE, 10, 0,
+            // Length of the encoding set below, target size, indicator and
+            // lengths of the sections
+            0, 14, 0, 2, 3, 3,
+            b'x', b'y',
+            // COPY 4 mode 1, COPY 4 mode 2, ADD 2 + COPY 4 mode 6
+            36, 52, 236,
+            // Here is 10, 10 - 10 = 0; near[0] is 0, 0 + 2 = 2; same[2]
+            // is 2
+            10, 2, 2,
+        ];
+        let mut window = window.to_vec();
+        window[3] = (window.len() - 4) as u8;
+        patch.extend_from_slice(&window);
+        assert_eq!(apply(old, &patch).unwrap(), b"01232345xy2345");
+    }
+
+    #[test]
+    fn rejects_other_images() {
+        let patch = diff(b"old image", b"new image");
+        assert!(apply(b"another image", &patch).is_err());
+        assert!(apply(b"old image", &patch[..patch.len() - 1]).is_err());
+        assert!(apply(b"old image", b"BCDELTA1").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io;
+use std::path;
+
+use crate::layout::{CheckError, LayoutError, Location, Warning};
+
+/// How errors and warnings are reported
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Plain text for humans
+    Human,
+    /// One JSON record per line
+    Json,
+}
+
+/// An error or warning as reported in the JSON format
+#[derive(Serialize)]
+struct Diagnostic<'a> {
+    file: Option<&'a path::Path>,
+    line: Option<usize>,
+    column: Option<usize>,
+    severity: &'static str,
+    code: &'static str,
+    message: String,
+}
+
+fn emit(diagnostic: &Diagnostic) {
+    // Serializing plain strings and numbers can't fail
+    if let Ok(json) = serde_json::to_string(diagnostic) {
+        eprintln!("{}", json);
+    }
+}
+
+/// Returns a stable identifier of the kind of the error
+pub fn code(err: &anyhow::Error) -> &'static str {
+    if let Some(layout_err) = err.chain().find_map(|cause| cause.downcast_ref::<LayoutError>()) {
+        return layout_err.code();
+    }
+    if let Some(check_err) = err.chain().find_map(|cause| cause.downcast_ref::<CheckError>()) {
+        return check_err.code();
+    }
+    if err.downcast_ref::<Location>().is_some_and(|location| location.parsing) {
+        return "syntax";
+    }
+    if err.chain().any(|cause| cause.downcast_ref::<ureq::Error>().is_some()) {
+        return "network";
+    }
+    if let Some(io_err) = err.chain().find_map(|cause| cause.downcast_ref::<io::Error>()) {
+        return match io_err.kind() {
+            io::ErrorKind::NotFound => "missing-file",
+            _ => "io",
+        };
+    }
+    "error"
+}
+
+/// Exit codes of the kinds of errors, listed in the help
+pub const EXIT_CODES: &str = "\
+Exit codes:
+  1  any other error
+  2  invalid command line options
+  3  the layout is invalid: syntax, unknown function, arguments or variables
+  4  an input file is missing
+  5  a download failed
+  6  a checksum or --verify found different content
+  7  a region is placed where the target or --deny-unaligned forbids it";
+
+/// Returns the exit code for the kind of the error, 2 is used by clap for
+/// invalid options
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    match code(err) {
+        "syntax" | "unknown-function" | "arguments" | "undefined-variable" => 3,
+        "missing-file" => 4,
+        "network" => 5,
+        "mismatch" => 6,
+        "placement" => 7,
+        _ => 1,
+    }
+}
+
+pub fn error(format: Format, err: &anyhow::Error) {
+    match format {
+        Format::Human => eprintln!("Error: {:?}", err),
+        Format::Json => {
+            let location = err.downcast_ref::<Location>();
+            // The location is reported in its own fields
+            let context = location.map(|location| location.to_string());
+            let message = err.chain()
+                .map(|cause| cause.to_string())
+                .filter(|cause| Some(cause) != context.as_ref())
+                .collect::<Vec<String>>()
+                .join(": ");
+            emit(&Diagnostic {
+                file: location.map(|location| location.file.as_path()),
+                line: location.map(|location| location.line),
+                column: location.map(|location| location.column),
+                severity: "error",
+                code: code(err),
+                message,
+            });
+        }
+    }
+}
+
+pub fn warning(format: Format, file: &path::Path, warning: &Warning) {
+    match format {
+        Format::Human => eprintln!("Warning: {}", warning),
+        Format::Json => emit(&Diagnostic {
+            file: Some(file),
+            line: Some(warning.line),
+            column: None,
+            severity: "warning",
+            code: warning.code,
+            message: warning.message.clone(),
+        }),
+    }
+}
+
+/// Reports a warning which isn't about a line of the layout, such as a
+/// retried download
+pub fn global_warning(format: Format, code: &'static str, message: &str) {
+    match format {
+        Format::Human => eprintln!("Warning: {}", message),
+        Format::Json => emit(&Diagnostic {
+            file: None,
+            line: None,
+            column: None,
+            severity: "warning",
+            code,
+            message: message.to_string(),
+        }),
+    }
+}
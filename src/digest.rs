@@ -0,0 +1,107 @@
+use anyhow::{bail, Context as _, Result};
+use clap::ValueEnum;
+use md5::Md5;
+use sha2::digest::DynDigest;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path;
+
+use crate::archive;
+use crate::layout::CheckError;
+
+/// Formats the bytes as lowercase hex
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Hash algorithms of the checksum files written next to the output
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Algorithm {
+    Sha256,
+    Md5,
+}
+
+impl Algorithm {
+    /// Suffix of the checksum file, as expected by `sha256sum` and `md5sum`
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => ".sha256",
+            Algorithm::Md5 => ".md5",
+        }
+    }
+
+    /// Starts a digest of data fed in parts
+    pub fn hasher(self) -> Box<dyn DynDigest> {
+        match self {
+            Algorithm::Sha256 => Box::new(Sha256::new()),
+            Algorithm::Md5 => Box::new(Md5::new()),
+        }
+    }
+
+    /// Computes the digest of the file
+    pub fn file(self, rpath: &path::Path) -> Result<Vec<u8>> {
+        match self {
+            Algorithm::Sha256 => hash_file::<Sha256>(rpath),
+            Algorithm::Md5 => hash_file::<Md5>(rpath),
+        }
+    }
+}
+
+/// Computes the digest of the file without loading it into memory
+fn hash_file<D: Digest + io::Write>(rpath: &path::Path) -> Result<Vec<u8>> {
+    let f = File::open(rpath)
+        .with_context(
+            || format!("could not open file `{}`", rpath.display())
+        )?;
+    let mut hasher = D::new();
+    io::copy(&mut BufReader::new(f), &mut hasher)
+        .with_context(
+            || format!("could not read file `{}`", rpath.display())
+        )?;
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Computes SHA-256 of the file without loading it into memory
+pub fn sha256_file(rpath: &path::Path) -> Result<Vec<u8>> {
+    hash_file::<Sha256>(rpath)
+}
+
+/// Computes SHA-256 of the input file, or of the names, permissions and
+/// contents of everything in the input directory
+pub fn sha256_input(ipath: &path::Path) -> Result<Vec<u8>> {
+    if !ipath.is_dir() {
+        return sha256_file(ipath);
+    }
+    let mut hasher = Sha256::new();
+    for entry in archive::entries(ipath)? {
+        Digest::update(&mut hasher, format!("{} {:o}\n", entry.name, entry.permissions));
+        if !entry.is_dir {
+            Digest::update(&mut hasher, sha256_file(&entry.path)?);
+        }
+    }
+    Ok(Digest::finalize(hasher).to_vec())
+}
+
+/// Returns the lowercase hex of a pinned `sha256:<hex>` digest
+pub fn parse(expected: &str) -> Result<String> {
+    match expected.split_once(':') {
+        Some(("sha256", hex)) if hex.len() == 64
+            && hex.chars().all(|c| c.is_ascii_hexdigit()) => Ok(hex.to_ascii_lowercase()),
+        _ => bail!("Invalid digest '{}', expected sha256:<64 hex digits>", expected),
+    }
+}
+
+/// Checks the file against a pinned `sha256:<hex>` digest
+pub fn verify(rpath: &path::Path, expected: &str) -> Result<()> {
+    let hex = parse(expected)?;
+    let actual = to_hex(&sha256_file(rpath)?);
+    if actual != hex {
+        bail!(CheckError::Mismatch(format!(
+            "Checksum mismatch for `{}`: expected sha256:{}, got sha256:{}",
+            rpath.display(), hex, actual
+        )));
+    }
+
+    Ok(())
+}
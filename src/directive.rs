@@ -0,0 +1,413 @@
+//! Lines of the layout that configure the interpreter instead of writing a
+//! region:
+//!
+//! - `default NAME VALUE` defines the variable unless it was given on the
+//!   command line, the value is a quoted string or an expression which may
+//!   read input files and the image written so far
+//! - `param NAME: TYPE [in LO..HI]` requires the variable to be defined
+//!   with a value of the type, one of `int`, `addr`, `u8`, `u16`, `u32`,
+//!   `u64`, `string` or `enum("a", "b", ...)`
+//! - `struct NAME { FIELD: TYPE, ... }` declares a record the `struct`
+//!   function writes, fields are integers like `u32` or `u16be`, bitfields
+//!   like `u8:3` and bytes like `[u8; 16]`
+//! - `slots NAME=BASE ...` builds the layout once for every slot with the
+//!   addresses of its statements moved by BASE, see `slots`
+//! - `label NAME` defines `$NAME.start` as the end of everything written so
+//!   far, `$IMAGE.end`, marking a boundary later statements refer to
+//! - `min_offset ADDR` fails the build on regions written below ADDR, such
+//!   as into the bootloader, like `--min-offset`
+//! - `bincomb_version "REQ"` requires the running version of bincomb to
+//!   match, REQ is a comma-separated list of comparisons like `>=0.4`, a
+//!   version without an operator is a minimum
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use std::fmt;
+
+use crate::expr;
+use crate::fields;
+use crate::layout::{expand_env, parse_value, split_unquoted, valid_name, Context, Image, LayoutError, Location, Memory, Value};
+
+const KEYWORDS: [&str; 7] = ["bincomb_version", "default", "label", "min_offset", "param", "slots", "struct"];
+
+/// Splits the line into the keyword and its arguments
+fn split(line: &str) -> Option<(&str, &str)> {
+    let (keyword, rest) = line.split_once(char::is_whitespace)?;
+    if KEYWORDS.contains(&keyword) {
+        Some((keyword, rest.trim()))
+    }
+    else {
+        None
+    }
+}
+
+/// Checks whether the line is a directive rather than a statement
+pub fn is_directive(line: &str) -> bool {
+    split(line).is_some()
+}
+
+/// Executes the line if it is a directive, returns `None` for statements.
+/// The image is read by `peek` functions.
+pub fn execute(ctx: &mut Context, line: &str, image: Option<&mut dyn Image>) -> Option<Result<()>> {
+    let (keyword, rest) = split(line)?;
+
+    Some(match keyword {
+        "bincomb_version" => check_version(rest),
+        "default" => default(ctx, rest, image),
+        "label" => label(ctx, rest),
+        "min_offset" => expr::eval(rest, &ctx.vars).map(|floor| {
+            // The higher floor of the layout and the command line applies
+            ctx.min_offset = ctx.min_offset.max(floor);
+        }),
+        "struct" => declare_struct(ctx, rest),
+        // Evaluated on its own like the first slot, `layout::run` builds the
+        // others
+        "slots" => parse_slots(ctx, rest).map(|slots| enter_slot(ctx, 0, &slots[0])),
+        _ => Param::parse(rest, ctx).and_then(|param| param.check(ctx)),
+    })
+}
+
+/// Returns the variable defined if the line is a `default` directive
+pub fn default_name(line: &str) -> Option<&str> {
+    match split(line) {
+        Some(("default", rest)) => rest.split_whitespace().next(),
+        _ => None,
+    }
+}
+
+fn default(ctx: &mut Context, rest: &str, image: Option<&mut dyn Image>) -> Result<()> {
+    let (name, value) = rest.split_once(char::is_whitespace)
+        .ok_or(LayoutError::Syntax("Expected default NAME VALUE"))?;
+    if !valid_name(name) {
+        bail!(LayoutError::Syntax("Invalid variable name"));
+    }
+    let mut memory = Memory::new(ctx, image.map(|image| image as &mut dyn Image));
+    let value = match parse_value(value, &ctx.vars, Some(&mut memory))? {
+        Value::Str(text) if ctx.expand_env => Value::Str(expand_env(&text)?),
+        value => value,
+    };
+    ctx.set_default(name, value);
+    Ok(())
+}
+
+/// Parses a version like `0.4` or `1.2.3` into its numbers, a pre-release
+/// or build suffix like `-rc1` or `+git` is ignored
+fn parse_version(text: &str) -> Option<Vec<u64>> {
+    let text = text.split(['-', '+']).next().unwrap_or(text);
+    text.split('.')
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<u64>>>()
+        .filter(|numbers| (1..=3).contains(&numbers.len()))
+}
+
+/// Checks that the running version matches the requirement. Versions are
+/// compared up to the numbers the requirement gives, so `=0.4` matches any
+/// `0.4.x`.
+fn check_version(rest: &str) -> Result<()> {
+    check_version_of(rest, env!("CARGO_PKG_VERSION"))
+}
+
+fn check_version_of(rest: &str, running: &str) -> Result<()> {
+    let requirement = rest.strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or(LayoutError::Syntax("Expected bincomb_version \"REQ\""))?;
+    let current = parse_version(running).unwrap_or_default();
+
+    for comparison in requirement.split(',').map(str::trim) {
+        let split = comparison.find(|c: char| c.is_ascii_digit()).unwrap_or(comparison.len());
+        let (op, version) = comparison.split_at(split);
+        let version = parse_version(version)
+            .ok_or(LayoutError::Syntax("Expected a version like 0.4 or 1.2.3"))?;
+        let compared = (0..version.len())
+            .map(|index| current.get(index).copied().unwrap_or(0))
+            .collect::<Vec<u64>>();
+        let ordering = compared.cmp(&version);
+        let matches = match op.trim() {
+            "" | ">=" => ordering.is_ge(),
+            ">" => ordering.is_gt(),
+            "<=" => ordering.is_le(),
+            "<" => ordering.is_lt(),
+            "=" | "==" => ordering.is_eq(),
+            _ => bail!(LayoutError::Syntax("Unknown version operator")),
+        };
+        if !matches {
+            bail!(
+                "The layout requires bincomb {}, but this is version {}",
+                requirement, running
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the `bincomb_version` requirements of the layout before anything
+/// else, so older versions report them instead of unknown features
+pub fn check_versions(statements: &[(Location, String)]) -> Result<()> {
+    for (location, line) in statements {
+        if let Some(("bincomb_version", rest)) = split(line) {
+            check_version(rest).map_err(|err| err.context(location.clone()))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn label(ctx: &mut Context, rest: &str) -> Result<()> {
+    if !valid_name(rest) {
+        bail!(LayoutError::Syntax("Expected label NAME"));
+    }
+    let end = ctx.vars.get("IMAGE.end").copied().unwrap_or(0);
+    ctx.vars.insert(format!("{}.start", rest), end);
+    Ok(())
+}
+
+/// A copy of the layout at another base address, as for A/B images
+pub type Slot = (String, u64);
+
+fn parse_slots(ctx: &Context, rest: &str) -> Result<Vec<Slot>> {
+    let slots = rest.split_whitespace()
+        .map(|slot| {
+            let (name, base) = slot.split_once('=')
+                .ok_or(LayoutError::Syntax("Expected slots NAME=BASE ..."))?;
+            if !valid_name(name) {
+                bail!(LayoutError::Syntax("Invalid slot name"));
+            }
+            Ok((name.to_string(), expr::eval(base, &ctx.vars)?))
+        })
+        .collect::<Result<Vec<Slot>>>()?;
+
+    if slots.is_empty() {
+        bail!(LayoutError::Syntax("Expected slots NAME=BASE ..."));
+    }
+    Ok(slots)
+}
+
+/// Returns the slots the layout is built in if it has a `slots` directive
+pub fn slots(ctx: &Context, statements: &[(Location, String)]) -> Result<Option<Vec<Slot>>> {
+    let mut found = statements.iter()
+        .filter_map(|(location, line)| match split(line) {
+            Some(("slots", rest)) => Some((location, rest)),
+            _ => None,
+        });
+
+    let slots = match found.next() {
+        Some((location, rest)) => parse_slots(ctx, rest).context(location.clone())?,
+        None => return Ok(None),
+    };
+    if let Some((location, _)) = found.next() {
+        return Err(anyhow!("Slots are already declared").context(location.clone()));
+    }
+
+    Ok(Some(slots))
+}
+
+/// Checks whether the line is the `slots` directive
+pub fn is_slots(line: &str) -> bool {
+    matches!(split(line), Some(("slots", _)))
+}
+
+/// Defines `$SLOT.index`, `$SLOT.base` and `$SLOT.name` for the slot
+pub fn enter_slot(ctx: &mut Context, index: usize, slot: &Slot) {
+    ctx.vars.insert("SLOT.index".to_string(), index as u64);
+    ctx.vars.insert("SLOT.base".to_string(), slot.1);
+    ctx.strings.insert("SLOT.name".to_string(), slot.0.clone());
+}
+
+fn declare_struct(ctx: &mut Context, rest: &str) -> Result<()> {
+    let (name, body) = rest.split_once('{')
+        .and_then(|(name, body)| Some((name.trim(), body.trim().strip_suffix('}')?)))
+        .ok_or(LayoutError::Syntax("Expected struct NAME { FIELD: TYPE, ... }"))?;
+    if !valid_name(name) {
+        bail!(LayoutError::Syntax("Invalid struct name"));
+    }
+    if ctx.structs.contains_key(name) {
+        bail!("Struct {} is already declared", name);
+    }
+
+    let mut declared: fields::Struct = Vec::new();
+    for field in body.split(',').map(str::trim).filter(|field| !field.is_empty()) {
+        let (field, kind) = field.split_once(':')
+            .ok_or(LayoutError::Syntax("Expected FIELD: TYPE"))?;
+        let field = field.trim();
+        if !valid_name(field) {
+            bail!(LayoutError::Syntax("Invalid field name"));
+        }
+        if declared.iter().any(|(other, _)| other == field) {
+            bail!("Field {} is declared twice", field);
+        }
+        declared.push((field.to_string(), fields::FieldType::parse(kind.trim())?));
+    }
+
+    ctx.structs.insert(name.to_string(), declared);
+    Ok(())
+}
+
+enum Kind {
+    /// An integer not larger than the maximum
+    Int(&'static str, u64),
+    Str,
+    Enum(Vec<String>),
+}
+
+/// A variable the layout expects to be defined
+struct Param {
+    name: String,
+    kind: Kind,
+    range: Option<(u64, u64)>,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Int(name, _) => write!(f, "{}", name),
+            Kind::Str => write!(f, "a string"),
+            Kind::Enum(options) => {
+                let quoted = options.iter()
+                    .map(|option| format!("{:?}", option))
+                    .collect::<Vec<String>>();
+                write!(f, "one of {}", quoted.join(", "))
+            }
+        }
+    }
+}
+
+impl Param {
+    fn parse(rest: &str, ctx: &Context) -> Result<Param> {
+        let (name, spec) = rest.split_once(':')
+            .ok_or(LayoutError::Syntax("Expected param NAME: TYPE"))?;
+        let name = name.trim();
+        if !valid_name(name) {
+            bail!(LayoutError::Syntax("Invalid variable name"));
+        }
+
+        let (spec, range) = match spec.split_once(" in ") {
+            Some((spec, range)) => {
+                let (lo, hi) = range.split_once("..")
+                    .ok_or(LayoutError::Syntax("Expected a range LO..HI"))?;
+                (spec, Some((expr::eval(lo, &ctx.vars)?, expr::eval(hi, &ctx.vars)?)))
+            }
+            None => (spec, None),
+        };
+
+        let spec = spec.trim();
+        let kind = match spec {
+            "int" | "u64" => Kind::Int("an integer", u64::MAX),
+            "addr" => Kind::Int("an address", u64::MAX),
+            "u8" => Kind::Int("a u8", u8::MAX.into()),
+            "u16" => Kind::Int("a u16", u16::MAX.into()),
+            "u32" => Kind::Int("a u32", u32::MAX.into()),
+            "string" => Kind::Str,
+            _ => {
+                let options = spec.strip_prefix("enum(")
+                    .and_then(|spec| spec.strip_suffix(')'))
+                    .ok_or(LayoutError::Syntax("Unknown parameter type"))?;
+                let options = split_unquoted(options, ',')
+                    .into_iter()
+                    .map(|option| {
+                        option.trim()
+                            .strip_prefix('"')
+                            .and_then(|option| option.strip_suffix('"'))
+                            .map(str::to_string)
+                            .ok_or(LayoutError::Syntax("Expected quoted enum values"))
+                    })
+                    .collect::<Result<Vec<String>, LayoutError>>()?;
+                Kind::Enum(options)
+            }
+        };
+
+        if range.is_some() && !matches!(kind, Kind::Int(..)) {
+            bail!(LayoutError::Syntax("Only integer parameters can have a range"));
+        }
+
+        Ok(Param { name: name.to_string(), kind, range })
+    }
+
+    /// Checks the value the variable currently has
+    fn check(&self, ctx: &Context) -> Result<()> {
+        let error = |got: String| {
+            anyhow!("Parameter {} must be {}, got {}", self.name, self.describe(), got)
+        };
+
+        match (&self.kind, ctx.vars.get(&self.name), ctx.strings.get(&self.name)) {
+            (_, None, None) => {
+                bail!("Missing parameter {}, expected {}", self.name, self.describe());
+            }
+            (Kind::Int(_, max), Some(&value), _) => {
+                let in_range = self.range.is_none_or(|(lo, hi)| lo <= value && value < hi);
+                if value > *max || !in_range {
+                    return Err(error(format!("0x{:x}", value)));
+                }
+            }
+            (Kind::Int(..), None, Some(value)) => return Err(error(format!("{:?}", value))),
+            (Kind::Str, _, _) => {}
+            (Kind::Enum(options), _, _) => {
+                let value = ctx.text(&format!("${}", self.name))?;
+                if !options.contains(&value) {
+                    return Err(error(format!("{:?}", value)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        match self.range {
+            Some((lo, hi)) => format!("{} in 0x{:x}..0x{:x}", self.kind, lo, hi),
+            None => self.kind.to_string(),
+        }
+    }
+}
+
+/// Checks the values given for all parameters of the layout before anything
+/// is executed, parameters with a `default` are left to be checked when
+/// their line is reached
+pub fn check_params(ctx: &Context, statements: &[(Location, String)]) -> Result<()> {
+    let defaulted = statements.iter()
+        .filter_map(|(_, line)| default_name(line))
+        .collect::<Vec<&str>>();
+
+    for (location, line) in statements {
+        if let Some(("param", rest)) = split(line) {
+            let param = match Param::parse(rest, ctx) {
+                Ok(param) => param,
+                // Ranges may refer to variables that are defined later
+                Err(_) => continue,
+            };
+            let given = ctx.vars.contains_key(&param.name) || ctx.strings.contains_key(&param.name);
+            if given || !defaulted.contains(&param.name.as_str()) {
+                param.check(ctx)
+                    .map_err(|err| err.context(location.clone()))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version("1.2.3"), Some(vec![1, 2, 3]));
+        assert_eq!(parse_version("0.5.0-rc1"), Some(vec![0, 5, 0]));
+        assert_eq!(parse_version("0.5.0+git.abc"), Some(vec![0, 5, 0]));
+        assert_eq!(parse_version("1.2.3.4"), None);
+        assert_eq!(parse_version("a.b"), None);
+    }
+
+    #[test]
+    fn requirements() {
+        assert!(check_version_of("\">=0.4\"", "0.5.0-rc1").is_ok());
+        assert!(check_version_of("\"=0.5\"", "0.5.0-rc1").is_ok());
+        assert!(check_version_of("\">=0.4, <0.5\"", "0.5.0-rc1").is_err());
+        assert!(check_version_of("\"=0.4\"", "0.4.7").is_ok());
+        assert!(check_version_of("\">0.4.7\"", "0.4.7").is_err());
+        // Versions without every number compare as zeros
+        assert!(check_version_of("\">=1.0.0\"", "1").is_ok());
+        assert!(check_version_of("\"<1.0.1\"", "1").is_ok());
+        assert!(check_version_of("\">=0.4\"", "").is_err());
+    }
+}
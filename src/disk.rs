@@ -0,0 +1,381 @@
+//! MBR and GPT partition tables of disk images.
+//!
+//! A row in the layout is written as `LABEL TYPE START SIZE [boot]`, where
+//! START is the offset of the partition from the start of the disk and both
+//! must be multiples of the sector size. Types are numbers or names like
+//! `linux` and `efi`, GPT types may also be given as GUIDs.
+
+use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+
+use crate::layout::{unpack_arg, LayoutError};
+use crate::partitions::parse_size;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Number of entries in a GPT and the size of each of them
+const GPT_ENTRIES: u64 = 128;
+const GPT_ENTRY_SIZE: u64 = 128;
+/// Sectors taken by the entries of a GPT
+const GPT_ENTRY_SECTORS: u64 = GPT_ENTRIES * GPT_ENTRY_SIZE / SECTOR_SIZE;
+/// Size of the primary GPT: the protective MBR, the header and the entries
+pub const GPT_SIZE: u64 = (2 + GPT_ENTRY_SECTORS) * SECTOR_SIZE;
+/// Size of the backup GPT at the end of the disk: the entries and the header
+pub const GPT_BACKUP_SIZE: u64 = (GPT_ENTRY_SECTORS + 1) * SECTOR_SIZE;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_SIZE: usize = 92;
+
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PROTECTIVE: u8 = 0xee;
+
+const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// A partition of the disk
+pub struct Partition {
+    pub label: String,
+    kind: String,
+    pub start: u64,
+    pub size: u64,
+    boot: bool,
+}
+
+impl Partition {
+    pub fn parse(vars: &HashMap<String, u64>, row: &str) -> Result<Partition> {
+        let fields = row.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() < 4 || fields.len() > 5 {
+            bail!(LayoutError::Syntax("Expected LABEL TYPE START SIZE [boot]"));
+        }
+
+        let boot = match fields.get(4) {
+            Some(&"boot") => true,
+            Some(flag) => bail!("Unknown partition flag '{}'", flag),
+            None => false,
+        };
+        let start = parse_size(vars, fields[2])?;
+        let size = parse_size(vars, fields[3])?;
+        if !start.is_multiple_of(SECTOR_SIZE) || !size.is_multiple_of(SECTOR_SIZE) || size == 0 {
+            bail!("Partition {} must start and end on a sector boundary", fields[0]);
+        }
+
+        Ok(Partition {
+            label: fields[0].to_string(),
+            kind: fields[1].to_string(),
+            start,
+            size,
+            boot,
+        })
+    }
+
+    fn first_lba(&self) -> u64 {
+        self.start / SECTOR_SIZE
+    }
+
+    fn last_lba(&self) -> u64 {
+        (self.start + self.size) / SECTOR_SIZE - 1
+    }
+}
+
+/// Checks that the partitions lie in the usable sectors and do not overlap
+fn check(partitions: &[Partition], first: u64, last: u64) -> Result<()> {
+    for (index, partition) in partitions.iter().enumerate() {
+        if partition.first_lba() < first || partition.last_lba() > last {
+            bail!("Partition {} is outside of the usable area of the disk", partition.label);
+        }
+        for other in &partitions[..index] {
+            if partition.first_lba() <= other.last_lba() && other.first_lba() <= partition.last_lba() {
+                bail!("Partition {} overlaps {}", partition.label, other.label);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn mbr_kind(kind: &str) -> Result<u8> {
+    match kind {
+        "fat12" => Ok(0x01),
+        "fat16" => Ok(0x0e),
+        "fat32" => Ok(0x0c),
+        "ntfs" | "exfat" => Ok(0x07),
+        "swap" => Ok(0x82),
+        "linux" => Ok(0x83),
+        "efi" => Ok(0xef),
+        _ => Ok(unpack_arg(&HashMap::new(), kind)?.try_into()?),
+    }
+}
+
+/// A 16-byte MBR entry of a partition addressed by LBA only
+fn mbr_entry(boot: bool, kind: u8, first: u64, sectors: u64) -> Result<[u8; 16]> {
+    let mut entry = [0; 16];
+    entry[0] = if boot { 0x80 } else { 0x00 };
+    entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[4] = kind;
+    entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&u32::try_from(first)?.to_le_bytes());
+    entry[12..16].copy_from_slice(&u32::try_from(sectors)?.to_le_bytes());
+    Ok(entry)
+}
+
+/// Builds the boot sector with the MBR partition table, the boot code area
+/// is left zeroed
+pub fn mbr(partitions: &[Partition]) -> Result<Vec<u8>> {
+    if partitions.len() > 4 {
+        bail!("An MBR holds at most 4 partitions");
+    }
+    check(partitions, 1, u64::from(u32::MAX))?;
+
+    let mut sector = vec![0; SECTOR_SIZE as usize];
+    for (index, partition) in partitions.iter().enumerate() {
+        let entry = mbr_entry(
+            partition.boot,
+            mbr_kind(&partition.kind)?,
+            partition.first_lba(),
+            partition.size / SECTOR_SIZE,
+        )?;
+        let offset = MBR_ENTRIES_OFFSET + index * entry.len();
+        sector[offset..offset + entry.len()].copy_from_slice(&entry);
+    }
+    sector[510..].copy_from_slice(&MBR_SIGNATURE);
+
+    Ok(sector)
+}
+
+/// Parses a GUID written as `XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX` into its
+/// on-disk form, where the first three groups are little-endian
+fn parse_guid(s: &str) -> Option<[u8; 16]> {
+    let groups = s.split('-').collect::<Vec<&str>>();
+    let lengths = groups.iter().map(|group| group.len()).collect::<Vec<usize>>();
+    if lengths != [8, 4, 4, 4, 12] {
+        return None;
+    }
+
+    let mut guid = [0; 16];
+    let mut offset = 0;
+    for (index, group) in groups.iter().enumerate() {
+        let mut bytes = (0..group.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(group.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        if index < 3 {
+            bytes.reverse();
+        }
+        guid[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        offset += bytes.len();
+    }
+
+    Some(guid)
+}
+
+fn gpt_kind(kind: &str) -> Result<[u8; 16]> {
+    let guid = match kind {
+        "efi" => "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+        "linux" => "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+        "swap" => "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F",
+        "data" | "fat32" | "exfat" | "ntfs" => "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+        _ => kind,
+    };
+    parse_guid(guid).ok_or_else(|| anyhow!("Unknown GPT partition type '{}'", kind))
+}
+
+/// Derives a version 4 style GUID from the name so the same layout always
+/// produces the same disk
+fn derived_guid(name: &str) -> [u8; 16] {
+    let hash = Sha256::digest(name.as_bytes());
+    let mut guid = [0; 16];
+    guid.copy_from_slice(&hash[..16]);
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    guid
+}
+
+/// Builds the 92-byte GPT header with its CRC
+fn gpt_header(current: u64, backup: u64, last_usable: u64, disk_guid: &[u8; 16],
+              entries_lba: u64, entries_crc: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(GPT_HEADER_SIZE);
+    header.extend_from_slice(GPT_SIGNATURE);
+    header.extend_from_slice(&GPT_REVISION.to_le_bytes());
+    header.extend_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+    header.extend_from_slice(&[0; 4]);
+    header.extend_from_slice(&[0; 4]);
+    header.extend_from_slice(&current.to_le_bytes());
+    header.extend_from_slice(&backup.to_le_bytes());
+    header.extend_from_slice(&(2 + GPT_ENTRY_SECTORS).to_le_bytes());
+    header.extend_from_slice(&last_usable.to_le_bytes());
+    header.extend_from_slice(disk_guid);
+    header.extend_from_slice(&entries_lba.to_le_bytes());
+    header.extend_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+    header.extend_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+    header.extend_from_slice(&entries_crc.to_le_bytes());
+
+    let crc = CRC32.checksum(&header);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+/// Builds the protective MBR, the primary GPT header and the partition
+/// entries of a disk of `disk_size` bytes
+pub fn gpt(partitions: &[Partition], disk_size: u64) -> Result<Vec<u8>> {
+    if !disk_size.is_multiple_of(SECTOR_SIZE) || disk_size < GPT_SIZE + GPT_BACKUP_SIZE {
+        bail!("Invalid disk size 0x{:x}", disk_size);
+    }
+    if partitions.len() as u64 > GPT_ENTRIES {
+        bail!("A GPT holds at most {} partitions", GPT_ENTRIES);
+    }
+    let last_lba = disk_size / SECTOR_SIZE - 1;
+    let last_usable = last_lba - GPT_BACKUP_SIZE / SECTOR_SIZE;
+    check(partitions, GPT_SIZE / SECTOR_SIZE, last_usable)?;
+
+    let mut entries = Vec::with_capacity((GPT_ENTRIES * GPT_ENTRY_SIZE) as usize);
+    for partition in partitions {
+        let name = partition.label.encode_utf16().collect::<Vec<u16>>();
+        if name.len() > 36 {
+            bail!("Partition label '{}' is longer than 36 characters", partition.label);
+        }
+        let start = entries.len();
+        entries.extend_from_slice(&gpt_kind(&partition.kind)?);
+        entries.extend_from_slice(&derived_guid(&format!("{}:{}", disk_size, partition.label)));
+        entries.extend_from_slice(&partition.first_lba().to_le_bytes());
+        entries.extend_from_slice(&partition.last_lba().to_le_bytes());
+        // Bit 2 marks the partition as legacy BIOS bootable
+        let attributes: u64 = if partition.boot { 1 << 2 } else { 0 };
+        entries.extend_from_slice(&attributes.to_le_bytes());
+        for unit in name {
+            entries.extend_from_slice(&unit.to_le_bytes());
+        }
+        entries.resize(start + GPT_ENTRY_SIZE as usize, 0);
+    }
+    entries.resize((GPT_ENTRIES * GPT_ENTRY_SIZE) as usize, 0);
+
+    let disk_guid = derived_guid(&format!("{}", disk_size));
+    let header = gpt_header(1, last_lba, last_usable, &disk_guid, 2, CRC32.checksum(&entries));
+
+    let mut table = Vec::with_capacity(GPT_SIZE as usize);
+    let protective = mbr_entry(false, MBR_PROTECTIVE, 1, last_lba.min(u64::from(u32::MAX)))?;
+    table.resize(MBR_ENTRIES_OFFSET, 0);
+    table.extend_from_slice(&protective);
+    table.resize(510, 0);
+    table.extend_from_slice(&MBR_SIGNATURE);
+    table.extend_from_slice(&header);
+    table.resize(2 * SECTOR_SIZE as usize, 0);
+    table.extend_from_slice(&entries);
+
+    Ok(table)
+}
+
+/// Returns the offset of the backup GPT from the start of the disk
+pub fn gpt_backup_offset(primary: &[u8]) -> Result<u64> {
+    let header = &primary[SECTOR_SIZE as usize..][..GPT_HEADER_SIZE];
+    if &header[..8] != GPT_SIGNATURE {
+        bail!("No GPT header found");
+    }
+    let backup = u64::from_le_bytes(header[32..40].try_into().unwrap());
+    backup.checked_sub(GPT_ENTRY_SECTORS)
+        .and_then(|lba| lba.checked_mul(SECTOR_SIZE))
+        .ok_or_else(|| anyhow!("Invalid backup GPT location in the header"))
+}
+
+/// Builds the backup GPT from the primary one: the entries followed by the
+/// header pointing back at the primary header
+pub fn gpt_backup(primary: &[u8]) -> Result<Vec<u8>> {
+    gpt_backup_offset(primary)?;
+    let header = &primary[SECTOR_SIZE as usize..][..GPT_HEADER_SIZE];
+    let field = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+
+    let backup = field(32);
+    let last_usable = field(48);
+    let mut disk_guid = [0; 16];
+    disk_guid.copy_from_slice(&header[56..72]);
+    let entries_crc = u32::from_le_bytes(header[88..92].try_into().unwrap());
+
+    let entries = &primary[2 * SECTOR_SIZE as usize..GPT_SIZE as usize];
+    if CRC32.checksum(entries) != entries_crc {
+        bail!("The CRC of the primary GPT entries does not match");
+    }
+
+    let mut table = Vec::with_capacity(GPT_BACKUP_SIZE as usize);
+    table.extend_from_slice(entries);
+    table.extend_from_slice(&gpt_header(
+        backup, 1, last_usable, &disk_guid, backup - GPT_ENTRY_SECTORS, entries_crc,
+    ));
+    table.resize(GPT_BACKUP_SIZE as usize, 0);
+
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISK_SIZE: u64 = 0x10_0000;
+
+    fn crc_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn rootfs() -> Vec<Partition> {
+        vec![Partition::parse(&HashMap::new(), "rootfs linux 0x10000 0x20000").unwrap()]
+    }
+
+    #[test]
+    fn guids() {
+        assert_eq!(gpt_kind("efi").unwrap(), [
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11,
+            0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+        ]);
+        assert!(parse_guid("C12A7328-F81F-11D2-BA4B-00A0C93EC93").is_none());
+        assert!(gpt_kind("unknown").is_err());
+    }
+
+    #[test]
+    fn mbr_entries() {
+        let partitions = vec![Partition::parse(&HashMap::new(), "boot fat32 0x100000 0x400000 boot").unwrap()];
+        let sector = mbr(&partitions).unwrap();
+        assert_eq!(&sector[446..462], &[
+            0x80, 0xfe, 0xff, 0xff, 0x0c, 0xfe, 0xff, 0xff,
+            0x00, 0x08, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00,
+        ]);
+        assert_eq!(&sector[510..], &[0x55, 0xaa]);
+    }
+
+    #[test]
+    fn empty_gpt_crcs() {
+        let table = gpt(&[], DISK_SIZE).unwrap();
+        let header = &table[SECTOR_SIZE as usize..];
+        assert_eq!(crc_at(header, 88), 0xab54_d286);
+        assert_eq!(crc_at(header, 16), 0xdcd2_dcbf);
+    }
+
+    #[test]
+    fn gpt_crcs() {
+        let table = gpt(&rootfs(), DISK_SIZE).unwrap();
+        assert_eq!(table.len() as u64, GPT_SIZE);
+        let header = &table[SECTOR_SIZE as usize..];
+        assert_eq!(crc_at(header, 88), 0xe83e_a24d);
+        assert_eq!(crc_at(header, 16), 0x59a8_4eef);
+
+        let entry = &table[2 * SECTOR_SIZE as usize..][..GPT_ENTRY_SIZE as usize];
+        assert_eq!(u64::from_le_bytes(entry[32..40].try_into().unwrap()), 128);
+        assert_eq!(u64::from_le_bytes(entry[40..48].try_into().unwrap()), 383);
+    }
+
+    #[test]
+    fn backup_gpt_crcs() {
+        let primary = gpt(&rootfs(), DISK_SIZE).unwrap();
+        assert_eq!(gpt_backup_offset(&primary).unwrap(), DISK_SIZE - GPT_BACKUP_SIZE);
+
+        let backup = gpt_backup(&primary).unwrap();
+        assert_eq!(backup.len() as u64, GPT_BACKUP_SIZE);
+        assert_eq!(&backup[..(GPT_BACKUP_SIZE - SECTOR_SIZE) as usize], &primary[2 * SECTOR_SIZE as usize..]);
+        let header = &backup[(GPT_BACKUP_SIZE - SECTOR_SIZE) as usize..];
+        assert_eq!(crc_at(header, 88), 0xe83e_a24d);
+        assert_eq!(crc_at(header, 16), 0xeada_60e4);
+
+        let mut corrupted = primary.clone();
+        corrupted[2 * SECTOR_SIZE as usize] ^= 1;
+        assert!(gpt_backup(&corrupted).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::{bail, Context as _, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path;
+
+use crate::directive;
+use crate::layout::{Context, Entry, Location, Region};
+
+/// Converts a region name to an identifier suitable for generated sources
+fn ident(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+fn write(wpath: &path::Path, content: &str) -> Result<()> {
+    fs::write(wpath, content)
+        .with_context(
+            || format!("could not write file `{}`", wpath.display())
+        )
+}
+
+/// Writes a C header defining start, size and end of every region
+pub fn header(wpath: &path::Path, regions: &[Region]) -> Result<()> {
+    let file_name = wpath.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let guard = format!("{}_", ident(&file_name));
+
+    let mut content = String::new();
+    content.push_str("/* Generated by bincomb, do not edit */\n");
+    content.push_str(&format!("#ifndef {}\n#define {}\n\n", guard, guard));
+
+    for region in regions {
+        let name = ident(&region.name);
+        content.push_str(&format!("#define REGION_{}_START 0x{:08x}UL\n", name, region.addr));
+        content.push_str(&format!("#define REGION_{}_SIZE 0x{:08x}UL\n", name, region.size));
+        content.push_str(&format!("#define REGION_{}_END 0x{:08x}UL\n", name, region.end()));
+    }
+
+    content.push_str(&format!("\n#endif /* {} */\n", guard));
+
+    write(wpath, &content)
+}
+
+/// Writes a Rust module defining start, size and end of every region
+pub fn rust(wpath: &path::Path, regions: &[Region]) -> Result<()> {
+    let mut content = String::new();
+    content.push_str("// Generated by bincomb, do not edit\n\n");
+
+    for region in regions {
+        let name = ident(&region.name);
+        content.push_str(&format!("pub const {}_START: usize = 0x{:08x};\n", name, region.addr));
+        content.push_str(&format!("pub const {}_SIZE: usize = 0x{:08x};\n", name, region.size));
+        content.push_str(&format!("pub const {}_END: usize = 0x{:08x};\n", name, region.end()));
+    }
+
+    write(wpath, &content)
+}
+
+/// Writes a linker script fragment with a MEMORY block and symbols for every
+/// region, the origins are shifted by `base`
+pub fn ld(wpath: &path::Path, regions: &[Region], base: u64) -> Result<()> {
+    if let Some(region) = regions.iter().find(|region| region.end().checked_add(base).is_none()) {
+        bail!("Region {} doesn't fit the 64-bit address space at base 0x{:x}", region.name, base);
+    }
+
+    let mut content = String::new();
+    content.push_str("/* Generated by bincomb, do not edit */\n\n");
+
+    content.push_str("MEMORY\n{\n");
+    for region in regions {
+        content.push_str(&format!(
+            "  {} (rx) : ORIGIN = 0x{:08x}, LENGTH = 0x{:08x}\n",
+            ident(&region.name), base + region.addr, region.size
+        ));
+    }
+    content.push_str("}\n\n");
+
+    for region in regions {
+        let name = ident(&region.name);
+        content.push_str(&format!("__{}_start = 0x{:08x};\n", name, base + region.addr));
+        content.push_str(&format!("__{}_size = 0x{:08x};\n", name, region.size));
+        content.push_str(&format!("__{}_end = 0x{:08x};\n", name, base + region.end()));
+    }
+
+    write(wpath, &content)
+}
+
+/// Writes all variables as a JSON object, numbers for integers and strings
+/// for strings
+pub fn vars(wpath: &path::Path, ctx: &Context) -> Result<()> {
+    let mut vars = BTreeMap::new();
+    for (name, value) in &ctx.vars {
+        vars.insert(name, serde_json::Value::from(*value));
+    }
+    for (name, value) in &ctx.strings {
+        vars.insert(name, serde_json::Value::from(value.as_str()));
+    }
+
+    let mut content = serde_json::to_string_pretty(&vars)?;
+    content.push('\n');
+    write(wpath, &content)
+}
+
+/// Returns the names of the variables the text refers to as `$NAME`
+fn references(text: &str) -> BTreeSet<&str> {
+    text.match_indices('$')
+        .map(|(start, _)| {
+            let name = &text[start + 1..];
+            let end = name.find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.')
+                .unwrap_or(name.len());
+            name[..end].trim_end_matches('.')
+        })
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Writes a Graphviz graph of the statements, the variables they use and
+/// the files they read. Variables of statements such as `$APP.size` are
+/// drawn as edges between the statements.
+pub fn graph(wpath: &path::Path, statements: &[(Location, String)], regions: &[Region]) -> Result<()> {
+    let names = statements.iter()
+        .filter_map(|(_, line)| Entry::from_str(line).ok().map(|entry| entry.name))
+        .collect::<BTreeSet<&str>>();
+    // Variables of statements are named after them, as in `$APP.size`
+    let node = |var: &str| {
+        names.iter()
+            .filter(|name| var.strip_prefix(*name).is_some_and(|rest| rest.starts_with('.')))
+            .max_by_key(|name| name.len())
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| format!("${}", var))
+    };
+
+    let mut content = String::new();
+    content.push_str("// Generated by bincomb, do not edit\n");
+    content.push_str("digraph layout {\n  rankdir=LR;\n");
+
+    let mut variables = BTreeSet::new();
+    let mut edges = BTreeMap::new();
+    for (_, line) in statements {
+        let target = match (directive::default_name(line), Entry::from_str(line)) {
+            (Some(name), _) => {
+                variables.insert(format!("${}", name));
+                format!("${}", name)
+            }
+            (None, Ok(entry)) if !directive::is_directive(line) => {
+                content.push_str(&format!("  {:?} [shape=box];\n", entry.name));
+                if let Some(source) = regions.iter()
+                    .find(|region| region.name == entry.name)
+                    .and_then(|region| region.source.as_ref())
+                {
+                    content.push_str(&format!("  {:?} [shape=note];\n", source));
+                    content.push_str(&format!("  {:?} -> {:?};\n", source, entry.name));
+                }
+                entry.name.to_string()
+            }
+            _ => continue,
+        };
+        for var in references(line) {
+            let from = node(var);
+            if from.starts_with('$') {
+                variables.insert(from.clone());
+            }
+            edges.entry((from, target.clone()))
+                .or_insert_with(Vec::new)
+                .push(format!("${}", var));
+        }
+    }
+
+    for var in variables {
+        content.push_str(&format!("  {:?} [shape=ellipse];\n", var));
+    }
+    for ((from, to), vars) in edges {
+        if from.starts_with('$') {
+            content.push_str(&format!("  {:?} -> {:?};\n", from, to));
+        }
+        else {
+            content.push_str(&format!("  {:?} -> {:?} [label={:?}];\n", from, to, vars.join(", ")));
+        }
+    }
+    content.push_str("}\n");
+
+    write(wpath, &content)
+}
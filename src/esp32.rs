@@ -0,0 +1,104 @@
+//! The ESP-IDF application image format, as produced by `esptool elf2image`.
+//!
+//! The image is a common header, an extended header and a list of segments
+//! each with a load address and length. It ends with a checksum byte
+//! aligned so that the image length is a multiple of 16, followed by the
+//! SHA-256 of everything before it.
+
+use sha2::{Digest, Sha256};
+
+const MAGIC: u8 = 0xe9;
+const CHECKSUM_SEED: u8 = 0xef;
+/// SPI flash mode DIO
+const SPI_MODE: u8 = 2;
+/// 4 MB flash at 40 MHz
+const SPI_SIZE_SPEED: u8 = 0x20;
+/// No write protect pin
+const WP_PIN_DISABLED: u8 = 0xee;
+
+const HEADER_SIZE: u64 = 24;
+const SEGMENT_HEADER_SIZE: u64 = 8;
+const HASH_SIZE: u64 = 32;
+
+fn align4(len: u64) -> u64 {
+    len.div_ceil(4) * 4
+}
+
+/// Returns the size of an image with a single segment of `data_len` bytes
+pub fn image_size(data_len: u64) -> u64 {
+    let body = HEADER_SIZE + SEGMENT_HEADER_SIZE + align4(data_len);
+    // Padding and the checksum byte make the length a multiple of 16
+    (body + 1).div_ceil(16) * 16 + HASH_SIZE
+}
+
+/// Wraps the data into an image with a single segment loaded at `load_addr`
+pub fn image(data: &[u8], load_addr: u32, entry: u32, chip_id: u16) -> Vec<u8> {
+    let mut segment = data.to_vec();
+    segment.resize(align4(data.len() as u64) as usize, 0);
+
+    let mut image = vec![MAGIC, 1, SPI_MODE, SPI_SIZE_SPEED];
+    image.extend_from_slice(&entry.to_le_bytes());
+    image.push(WP_PIN_DISABLED);
+    image.extend_from_slice(&[0; 3]);
+    image.extend_from_slice(&chip_id.to_le_bytes());
+    // Minimum chip revision, then minimum and maximum full revisions
+    image.push(0);
+    image.extend_from_slice(&0u16.to_le_bytes());
+    image.extend_from_slice(&0xffffu16.to_le_bytes());
+    image.extend_from_slice(&[0; 4]);
+    // The SHA-256 is appended
+    image.push(1);
+
+    image.extend_from_slice(&load_addr.to_le_bytes());
+    image.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+    image.extend_from_slice(&segment);
+
+    let checksum = segment.iter().fold(CHECKSUM_SEED, |acc, byte| acc ^ byte);
+    while image.len() % 16 != 15 {
+        image.push(0);
+    }
+    image.push(checksum);
+
+    let hash = Sha256::digest(&image);
+    image.extend_from_slice(&hash);
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest;
+
+    #[test]
+    fn single_segment_image() {
+        let image = image(&[1, 2, 3, 4, 5], 0x3fc0_0000, 0x4008_0000, 9);
+        assert_eq!(image.len() as u64, image_size(5));
+        assert_eq!(image.len(), 80);
+
+        let expected: [u8; 48] = [
+            // Common header: magic, one segment, DIO, 4 MB at 40 MHz, entry
+            0xe9, 0x01, 0x02, 0x20, 0x00, 0x00, 0x08, 0x40,
+            // Extended header: WP pin, drive settings, chip id 9, revisions,
+            // reserved and the SHA-256 flag
+            0xee, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x01,
+            // Segment header and the data padded to 4 bytes
+            0x00, 0x00, 0xc0, 0x3f, 0x08, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x00, 0x00, 0x00,
+            // Padding up to the checksum 0xef ^ 1 ^ 2 ^ 3 ^ 4 ^ 5
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xee,
+        ];
+        assert_eq!(&image[..48], &expected);
+        assert_eq!(
+            digest::to_hex(&image[48..]),
+            "f147ac0d9d1ce71016cd7a23bd2af4edb283ea52f90e2bdd89cc2ca8a443f417"
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(image_size(0), 48 + 32);
+        assert_eq!(image_size(8), 48 + 32);
+        assert_eq!(image_size(9), 48 + 32);
+        // 48 bytes of headers and data leave no room for the checksum byte
+        assert_eq!(image_size(16), 64 + 32);
+    }
+}
@@ -0,0 +1,408 @@
+//! Integer expressions such as `0x8000000 + 16 * 1024` or `$APP.start + 4`.
+//!
+//! Operators follow C precedence: `|`, `^`, `&`, `<<` `>>`, `+` `-`,
+//! `*` `/` `%` and unary `~` and `!`. Arithmetic is done on u64 and fails on
+//! overflow or division by zero instead of wrapping.
+//!
+//! Functions read integers from elsewhere, multi-byte values are
+//! little-endian:
+//!
+//! - `read_u32("app.bin", OFFSET)` reads the input file at the offset
+//! - `peek_u32(ADDR)` reads what the layout has already written to the image
+//!
+//! Strings, either quoted or string variables, are taken apart with
+//! `len(STR)`, `substr(STR, START, END)` counting characters from 0 with the
+//! end excluded, and `parse_int(STR, BASE)`. An expression may evaluate to a
+//! string, e.g. `substr($VERSION, 1, 2)`, but operators only take integers.
+//!
+//! `defined(NAME)` is 1 if the variable is defined and 0 otherwise, as used
+//! by the guards of statements like `0x1000:DBG:file,dbg.bin if defined(DEBUG)`.
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::layout::{parse_uint, LayoutError, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u64),
+    Variable(String),
+    Function(String),
+    Str(String),
+    Op(&'static str),
+    Open,
+    Close,
+    Comma,
+}
+
+/// Where expression functions read from
+pub trait Env {
+    /// Reads bytes of the input file at the offset
+    fn read_file(&mut self, file: &str, offset: u64, buf: &mut [u8]) -> Result<()>;
+    /// Reads bytes of the image at the address
+    fn peek(&mut self, addr: u64, buf: &mut [u8]) -> Result<()>;
+    /// Returns the string variable
+    fn string(&self, name: &str) -> Option<String>;
+}
+
+/// A value while the expression is evaluated
+enum Operand {
+    Int(u64),
+    Str(String),
+}
+
+impl Operand {
+    fn int(self) -> Result<u64> {
+        match self {
+            Operand::Int(value) => Ok(value),
+            Operand::Str(text) => bail!("Expected an integer, got the string {:?}", text),
+        }
+    }
+}
+
+const OPERATORS: [&str; 12] = ["<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "!"];
+
+/// Binary operators from the lowest to the highest precedence
+const LEVELS: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+
+    while !rest.is_empty() {
+        let word_len = |rest: &str, first: usize| {
+            rest[first..]
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .map_or(rest.len(), |len| first + len)
+        };
+
+        if let Some(var) = rest.strip_prefix('$') {
+            let len = word_len(var, 0);
+            if len == 0 {
+                bail!(LayoutError::Syntax("Variable name cannot be empty"));
+            }
+            tokens.push(Token::Variable(var[..len].to_string()));
+            rest = &var[len..];
+        }
+        else if rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            let len = word_len(rest, 0);
+            tokens.push(Token::Function(rest[..len].to_string()));
+            rest = &rest[len..];
+        }
+        else if let Some(after) = rest.strip_prefix('"') {
+            let len = after.find('"')
+                .ok_or(LayoutError::Syntax("Missing closing quote"))?;
+            tokens.push(Token::Str(after[..len].to_string()));
+            rest = &after[len + 1..];
+        }
+        else if rest.starts_with(|c: char| c.is_ascii_digit()) {
+            let len = word_len(rest, 0);
+            tokens.push(Token::Number(parse_uint(&rest[..len])?));
+            rest = &rest[len..];
+        }
+        else if let Some(after) = rest.strip_prefix('(') {
+            tokens.push(Token::Open);
+            rest = after;
+        }
+        else if let Some(after) = rest.strip_prefix(')') {
+            tokens.push(Token::Close);
+            rest = after;
+        }
+        else if let Some(after) = rest.strip_prefix(',') {
+            tokens.push(Token::Comma);
+            rest = after;
+        }
+        else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        }
+        else {
+            bail!(LayoutError::Syntax("Unexpected character in expression"));
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    vars: &'a HashMap<String, u64>,
+    env: Option<&'a mut dyn Env>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Operand> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut value = self.binary(level + 1)?;
+        while let Some(&Token::Op(op)) = self.peek() {
+            if !LEVELS[level].contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            value = Operand::Int(apply(op, value.int()?, rhs.int()?)?);
+        }
+
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<Operand> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+
+        match token {
+            Some(Token::Number(value)) => Ok(Operand::Int(value)),
+            Some(Token::Str(text)) => Ok(Operand::Str(text)),
+            Some(Token::Variable(name)) => match self.vars.get(&name) {
+                Some(&value) => Ok(Operand::Int(value)),
+                None => self.env.as_deref()
+                    .and_then(|env| env.string(&name))
+                    .map(Operand::Str)
+                    .ok_or_else(|| LayoutError::MissingVariable(format!("${}", name)).into()),
+            },
+            Some(Token::Function(name)) if name == "defined" => self.defined(),
+            Some(Token::Function(name)) => {
+                let args = self.args()?;
+                self.call(&name, args)
+            }
+            Some(Token::Op("~")) => Ok(Operand::Int(!self.unary()?.int()?)),
+            Some(Token::Op("!")) => Ok(Operand::Int((self.unary()?.int()? == 0).into())),
+            Some(Token::Open) => {
+                let value = self.binary(0)?;
+                if self.peek() != Some(&Token::Close) {
+                    bail!(LayoutError::Syntax("Missing closing parenthesis"));
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            _ => bail!(LayoutError::Syntax("Expected a number, variable or '('")),
+        }
+    }
+
+    /// Parses the variable of `defined`, which is named with or without `$`
+    fn defined(&mut self) -> Result<Operand> {
+        let name = match self.tokens.get(self.pos..self.pos + 3) {
+            Some([Token::Open, Token::Function(name) | Token::Variable(name), Token::Close]) => name,
+            _ => bail!("Expected defined(NAME)"),
+        };
+        let defined = self.vars.contains_key(name)
+            || self.env.as_deref().is_some_and(|env| env.string(name).is_some());
+        self.pos += 3;
+        Ok(Operand::Int(defined.into()))
+    }
+
+    /// Parses the parenthesized arguments of a function
+    fn args(&mut self) -> Result<Vec<Operand>> {
+        if self.peek() != Some(&Token::Open) {
+            bail!(LayoutError::Syntax("Expected '(' after the function name"));
+        }
+        self.pos += 1;
+
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::Close) {
+            self.pos += 1;
+            return Ok(args);
+        }
+        loop {
+            args.push(self.binary(0)?);
+            match self.peek() {
+                Some(Token::Comma) => self.pos += 1,
+                Some(Token::Close) => {
+                    self.pos += 1;
+                    return Ok(args);
+                }
+                _ => bail!(LayoutError::Syntax("Expected ',' or ')' after a function argument")),
+            }
+        }
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Operand>) -> Result<Operand> {
+        match (name, args.as_slice()) {
+            ("len", [Operand::Str(text)]) => Ok(Operand::Int(text.chars().count() as u64)),
+            ("substr", [Operand::Str(text), Operand::Int(start), Operand::Int(end)]) => {
+                let len = text.chars().count() as u64;
+                if start > end || *end > len {
+                    bail!("Range {}..{} is out of the string {:?}", start, end, text);
+                }
+                let part = text.chars()
+                    .skip(*start as usize)
+                    .take((end - start) as usize)
+                    .collect();
+                Ok(Operand::Str(part))
+            }
+            ("parse_int", [Operand::Str(text), Operand::Int(base)]) => {
+                let base = u32::try_from(*base).ok()
+                    .filter(|base| (2..=36).contains(base))
+                    .ok_or_else(|| anyhow!("Invalid base {}, expected 2 to 36", base))?;
+                u64::from_str_radix(text.trim(), base)
+                    .map(Operand::Int)
+                    .map_err(|_| anyhow!("Could not parse {:?} as an integer in base {}", text, base))
+            }
+            ("read_u32", [Operand::Str(file), Operand::Int(offset)]) => {
+                let mut buf = [0u8; 4];
+                self.env()?.read_file(file, *offset, &mut buf)?;
+                Ok(Operand::Int(u32::from_le_bytes(buf).into()))
+            }
+            ("peek_u32", [Operand::Int(addr)]) => {
+                let mut buf = [0u8; 4];
+                self.env()?.peek(*addr, &mut buf)?;
+                Ok(Operand::Int(u32::from_le_bytes(buf).into()))
+            }
+            ("len", _) => bail!("Expected len(STR)"),
+            ("substr", _) => bail!("Expected substr(STR, START, END)"),
+            ("parse_int", _) => bail!("Expected parse_int(STR, BASE)"),
+            ("read_u32", _) => bail!("Expected read_u32(\"FILE\", OFFSET)"),
+            ("peek_u32", _) => bail!("Expected peek_u32(ADDR)"),
+            _ => bail!("Unknown function {}", name),
+        }
+    }
+
+    fn env(&mut self) -> Result<&mut dyn Env> {
+        match self.env.as_deref_mut() {
+            Some(env) => Ok(env),
+            None => bail!("Files and the image can't be read here"),
+        }
+    }
+}
+
+fn apply(op: &str, lhs: u64, rhs: u64) -> Result<u64> {
+    let value = match op {
+        "+" => lhs.checked_add(rhs),
+        "-" => lhs.checked_sub(rhs),
+        "*" => lhs.checked_mul(rhs),
+        "/" => lhs.checked_div(rhs),
+        "%" => lhs.checked_rem(rhs),
+        "<<" => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shl(rhs)),
+        ">>" => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shr(rhs)),
+        "&" => Some(lhs & rhs),
+        "|" => Some(lhs | rhs),
+        "^" => Some(lhs ^ rhs),
+        _ => None,
+    };
+
+    value.ok_or_else(|| anyhow!("Invalid operation 0x{:x} {} 0x{:x}", lhs, op, rhs))
+}
+
+/// Evaluates the expression, `$NAME` refers to a variable
+pub fn eval(s: &str, vars: &HashMap<String, u64>) -> Result<u64> {
+    evaluate(s, vars, None)?.int()
+}
+
+/// Evaluates the expression with functions reading from the environment
+pub fn eval_with(s: &str, vars: &HashMap<String, u64>, env: &mut dyn Env) -> Result<u64> {
+    evaluate(s, vars, Some(env))?.int()
+}
+
+/// Evaluates the expression to an integer or a string, string variables are
+/// only known with the environment
+pub fn eval_value(s: &str, vars: &HashMap<String, u64>, env: Option<&mut dyn Env>) -> Result<Value> {
+    // Shortens the lifetime of the environment to the one of the variables
+    let env = env.map(|env| env as &mut dyn Env);
+    Ok(match evaluate(s, vars, env)? {
+        Operand::Int(value) => Value::Int(value),
+        Operand::Str(text) => Value::Str(text),
+    })
+}
+
+fn evaluate<'a>(s: &str, vars: &'a HashMap<String, u64>, env: Option<&'a mut dyn Env>) -> Result<Operand> {
+    let mut parser = Parser { tokens: tokenize(s)?, pos: 0, vars, env };
+    let value = parser.binary(0)?;
+
+    if parser.pos != parser.tokens.len() {
+        bail!(LayoutError::Syntax("Unexpected token in expression"));
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An image of 0..=255 bytes with the string variable `$VERSION`
+    struct TestEnv;
+
+    impl Env for TestEnv {
+        fn read_file(&mut self, _file: &str, offset: u64, buf: &mut [u8]) -> Result<()> {
+            self.peek(offset, buf)
+        }
+
+        fn peek(&mut self, addr: u64, buf: &mut [u8]) -> Result<()> {
+            for (index, byte) in buf.iter_mut().enumerate() {
+                *byte = (addr as usize + index) as u8;
+            }
+            Ok(())
+        }
+
+        fn string(&self, name: &str) -> Option<String> {
+            (name == "VERSION").then(|| "v1.2.3".to_string())
+        }
+    }
+
+    fn vars() -> HashMap<String, u64> {
+        vec![("APP.start".to_string(), 0x8000), ("SIZE".to_string(), 16)].into_iter().collect()
+    }
+
+    fn value(s: &str) -> Result<Value> {
+        eval_value(s, &vars(), Some(&mut TestEnv))
+    }
+
+    #[test]
+    fn precedence() {
+        let vars = vars();
+        assert_eq!(eval("0x8000000 + 16 * 1024", &vars).unwrap(), 0x800_4000);
+        assert_eq!(eval("1 + 2 << 3", &vars).unwrap(), 24);
+        assert_eq!(eval("1 | 2 ^ 3 & 6", &vars).unwrap(), 1);
+        assert_eq!(eval("(1 + 2) * 3 % 5", &vars).unwrap(), 4);
+        assert_eq!(eval("~0 >> 60", &vars).unwrap(), 0xf);
+        assert_eq!(eval("!0 + !7", &vars).unwrap(), 1);
+        assert_eq!(eval("$APP.start + $SIZE * 2", &vars).unwrap(), 0x8020);
+    }
+
+    #[test]
+    fn errors() {
+        let vars = vars();
+        assert!(eval("1 - 2", &vars).is_err());
+        assert!(eval("1 / 0", &vars).is_err());
+        assert!(eval("1 << 64", &vars).is_err());
+        assert!(eval("(1 + 2", &vars).is_err());
+        assert!(eval("1 2", &vars).is_err());
+        assert!(eval("1 # 2", &vars).is_err());
+        assert!(eval("$", &vars).is_err());
+        assert!(eval("$MISSING", &vars).is_err());
+        assert!(eval("\"text", &vars).is_err());
+        assert!(eval("\"text\" + 1", &vars).is_err());
+        assert!(eval("peek_u32(0)", &vars).is_err());
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(value("peek_u32(4)").unwrap(), Value::Int(0x0706_0504));
+        assert_eq!(value("read_u32(\"app.bin\", 0x10)").unwrap(), Value::Int(0x1312_1110));
+        assert_eq!(value("len($VERSION)").unwrap(), Value::Int(6));
+        assert_eq!(value("substr($VERSION, 1, 2)").unwrap(), Value::Str("1".to_string()));
+        assert_eq!(value("parse_int(substr($VERSION, 3, 4), 10) + 1").unwrap(), Value::Int(3));
+        assert_eq!(value("parse_int(\"ff\", 16)").unwrap(), Value::Int(0xff));
+        assert!(value("substr($VERSION, 4, 7)").is_err());
+        assert!(value("parse_int(\"ff\", 37)").is_err());
+        assert!(value("len(1)").is_err());
+        assert!(value("unknown(1)").is_err());
+    }
+
+    #[test]
+    fn defined() {
+        assert_eq!(value("defined(SIZE) + defined($VERSION)").unwrap(), Value::Int(2));
+        assert_eq!(value("defined(MISSING)").unwrap(), Value::Int(0));
+        assert!(value("defined(1)").is_err());
+    }
+}
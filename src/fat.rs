@@ -0,0 +1,68 @@
+//! FAT file systems populated from a directory of the host.
+//!
+//! The FAT type is chosen from the size of the volume. Timestamps are left
+//! at the DOS epoch so the same directory always produces the same image.
+
+use anyhow::{Context as _, Result};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path;
+
+/// Formats a volume of `size` bytes and copies the directory tree into it
+pub fn image(size: u64, dir: &path::Path) -> Result<Vec<u8>> {
+    let mut volume = Cursor::new(vec![0; usize::try_from(size)?]);
+    fatfs::format_volume(&mut volume, fatfs::FormatVolumeOptions::new())
+        .with_context(
+            || format!("could not format a FAT volume of {} bytes", size)
+        )?;
+    volume.set_position(0);
+
+    {
+        let fs = fatfs::FileSystem::new(&mut volume, fatfs::FsOptions::new())?;
+        copy_dir(dir, &fs.root_dir())?;
+        fs.unmount()?;
+    }
+
+    Ok(volume.into_inner())
+}
+
+fn copy_dir<T: fatfs::ReadWriteSeek>(src: &path::Path, dst: &fatfs::Dir<T>) -> Result<()> {
+    let mut entries = fs::read_dir(src)
+        .with_context(
+            || format!("could not read directory `{}`", src.display())
+        )?
+        .collect::<io::Result<Vec<fs::DirEntry>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name.to_str()
+            .with_context(
+                || format!("file name `{}` is not valid UTF-8", entry.path().display())
+            )?;
+        let spath = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            let dir = dst.create_dir(name)
+                .with_context(
+                    || format!("could not create directory `{}` in the volume", spath.display())
+                )?;
+            copy_dir(&spath, &dir)?;
+        }
+        else {
+            let mut rfile = fs::File::open(&spath)
+                .with_context(
+                    || format!("could not open file `{}`", spath.display())
+                )?;
+            let mut wfile = dst.create_file(name)?;
+            wfile.truncate()?;
+            io::copy(&mut rfile, &mut wfile)
+                .with_context(
+                    || format!("could not copy `{}` into the volume", spath.display())
+                )?;
+        }
+    }
+
+    Ok(())
+}
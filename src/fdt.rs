@@ -0,0 +1,230 @@
+//! Flattened device tree blobs with patched properties.
+//!
+//! An override is written as `/path/to/node/property=VALUE`, where VALUE is
+//! a number stored as a 32-bit cell (or a 64-bit one if the property already
+//! has 8 bytes), a `"string"`, `[bytes]` in hex like `[00 11 22 aa bb cc]` or
+//! a raw byte string like `b"\x7fELF\0"`.
+//! Missing properties are added to the node.
+
+use anyhow::{anyhow, bail, Result};
+use std::convert::{TryFrom, TryInto};
+
+use crate::value::Value;
+
+const MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+const VERSION: u32 = 17;
+const LAST_COMP_VERSION: u32 = 16;
+
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const NOP: u32 = 4;
+const END: u32 = 9;
+
+/// Encodes the value of a property, numbers keep the width of the old
+/// value
+fn encode(value: &Value, old: Option<&[u8]>) -> Result<Vec<u8>> {
+    Ok(match value {
+        Value::Int(value) if old.is_some_and(|old| old.len() == 8) => value.to_be_bytes().to_vec(),
+        Value::Int(value) => u32::try_from(*value)
+            .map_err(|_| anyhow!("Value 0x{:x} does not fit in a cell", value))?
+            .to_be_bytes()
+            .to_vec(),
+        Value::Str(text) => {
+            let mut bytes = text.as_bytes().to_vec();
+            bytes.push(0);
+            bytes
+        }
+        Value::Bytes(bytes) => bytes.clone(),
+    })
+}
+
+struct Node {
+    name: String,
+    props: Vec<(String, Vec<u8>)>,
+    children: Vec<Node>,
+}
+
+/// A parsed device tree blob
+pub struct Fdt {
+    boot_cpuid: u32,
+    reserved: Vec<(u64, u64)>,
+    root: Node,
+}
+
+fn be32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| anyhow!("Truncated device tree blob"))
+}
+
+fn be64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok(u64::from(be32(data, offset)?) << 32 | u64::from(be32(data, offset + 4)?))
+}
+
+fn c_str(data: &[u8], offset: usize) -> Result<String> {
+    let bytes = data.get(offset..).unwrap_or_default();
+    let len = bytes.iter()
+        .position(|&byte| byte == 0)
+        .ok_or_else(|| anyhow!("Unterminated string in device tree blob"))?;
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+fn align4(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+impl Fdt {
+    pub fn parse(data: &[u8]) -> Result<Fdt> {
+        if be32(data, 0)? != MAGIC {
+            bail!("Not a device tree blob");
+        }
+        let off_struct = be32(data, 8)? as usize;
+        let off_strings = be32(data, 12)? as usize;
+        let off_rsvmap = be32(data, 16)? as usize;
+        let boot_cpuid = be32(data, 28)?;
+        let strings = data.get(off_strings..)
+            .ok_or_else(|| anyhow!("Truncated device tree blob"))?;
+
+        let mut reserved = Vec::new();
+        let mut offset = off_rsvmap;
+        loop {
+            let (addr, size) = (be64(data, offset)?, be64(data, offset + 8)?);
+            offset += 16;
+            if addr == 0 && size == 0 {
+                break;
+            }
+            reserved.push((addr, size));
+        }
+
+        let mut stack: Vec<Node> = Vec::new();
+        let mut root = None;
+        let mut offset = off_struct;
+        loop {
+            let token = be32(data, offset)?;
+            offset += 4;
+            match token {
+                BEGIN_NODE => {
+                    let name = c_str(data, offset)?;
+                    offset = align4(offset + name.len() + 1);
+                    stack.push(Node { name, props: Vec::new(), children: Vec::new() });
+                }
+                END_NODE => {
+                    let node = stack.pop()
+                        .ok_or_else(|| anyhow!("Unbalanced nodes in device tree blob"))?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => root = Some(node),
+                    }
+                }
+                PROP => {
+                    let len = be32(data, offset)? as usize;
+                    let name = c_str(strings, be32(data, offset + 4)? as usize)?;
+                    let value = data.get(offset + 8..offset + 8 + len)
+                        .ok_or_else(|| anyhow!("Truncated device tree blob"))?;
+                    stack.last_mut()
+                        .ok_or_else(|| anyhow!("Property outside of a node in device tree blob"))?
+                        .props.push((name, value.to_vec()));
+                    offset = align4(offset + 8 + len);
+                }
+                NOP => {}
+                END => break,
+                _ => bail!("Invalid token 0x{:x} in device tree blob", token),
+            }
+        }
+
+        Ok(Fdt {
+            boot_cpuid,
+            reserved,
+            root: root.ok_or_else(|| anyhow!("Device tree blob has no root node"))?,
+        })
+    }
+
+    /// Sets the property given as `/path/to/node/property`
+    pub fn set(&mut self, path: &str, value: &Value) -> Result<()> {
+        let (node_path, prop) = path.rsplit_once('/')
+            .filter(|(_, prop)| !prop.is_empty())
+            .ok_or_else(|| anyhow!("Invalid property path {}", path))?;
+
+        let mut node = &mut self.root;
+        for name in node_path.split('/').filter(|name| !name.is_empty()) {
+            // A name without a unit address matches the node with one
+            node = node.children.iter_mut()
+                .find(|child| child.name == name || child.name.split('@').next() == Some(name))
+                .ok_or_else(|| anyhow!("No node {} in the device tree", node_path))?;
+        }
+
+        match node.props.iter_mut().find(|(name, _)| name == prop) {
+            Some((_, old)) => *old = encode(value, Some(old))?,
+            None => node.props.push((prop.to_string(), encode(value, None)?)),
+        }
+
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        write_node(&self.root, &mut structure, &mut strings);
+        structure.extend_from_slice(&END.to_be_bytes());
+
+        let mut rsvmap = Vec::new();
+        for &(addr, size) in self.reserved.iter().chain(&[(0, 0)]) {
+            rsvmap.extend_from_slice(&addr.to_be_bytes());
+            rsvmap.extend_from_slice(&size.to_be_bytes());
+        }
+
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + rsvmap.len();
+        let off_strings = off_struct + structure.len();
+        let total = off_strings + strings.len();
+
+        let mut blob = Vec::with_capacity(total);
+        for field in [
+            MAGIC, total as u32, off_struct as u32, off_strings as u32, off_rsvmap as u32,
+            VERSION, LAST_COMP_VERSION, self.boot_cpuid, strings.len() as u32, structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&rsvmap);
+        blob.extend_from_slice(&structure);
+        blob.extend_from_slice(&strings);
+        blob
+    }
+}
+
+/// Returns the offset of the name in the strings block, adding it if needed
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+    let mut offset = 0;
+    for existing in strings.split(|&byte| byte == 0) {
+        if existing == name.as_bytes() && offset < strings.len() {
+            return offset as u32;
+        }
+        offset += existing.len() + 1;
+    }
+    let offset = strings.len();
+    strings.extend_from_slice(name.as_bytes());
+    strings.push(0);
+    offset as u32
+}
+
+fn write_node(node: &Node, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+    structure.extend_from_slice(&BEGIN_NODE.to_be_bytes());
+    structure.extend_from_slice(node.name.as_bytes());
+    structure.push(0);
+    structure.resize(align4(structure.len()), 0);
+
+    for (name, value) in &node.props {
+        structure.extend_from_slice(&PROP.to_be_bytes());
+        structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        structure.extend_from_slice(&string_offset(strings, name).to_be_bytes());
+        structure.extend_from_slice(value);
+        structure.resize(align4(structure.len()), 0);
+    }
+    for child in &node.children {
+        write_node(child, structure, strings);
+    }
+
+    structure.extend_from_slice(&END_NODE.to_be_bytes());
+}
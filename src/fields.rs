@@ -0,0 +1,169 @@
+//! Typed integer fields of binary records, written as `u8`, `u16`, `u32` or
+//! `u64` with an optional `le` or `be` suffix. Fields are little-endian
+//! unless stated otherwise.
+//!
+//! Bitfields such as `u16:3` take that many bits of an integer, consecutive
+//! bitfields of the same type are packed into one from the least significant
+//! bit like in C. The next one starts when they don't fit anymore.
+
+use anyhow::{anyhow, bail, Context as _, Result};
+
+use crate::value::Value;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct IntType {
+    pub width: usize,
+    big_endian: bool,
+}
+
+impl IntType {
+    pub fn parse(s: &str) -> Option<IntType> {
+        let (s, big_endian) = match (s.strip_suffix("be"), s.strip_suffix("le")) {
+            (Some(s), _) => (s, true),
+            (_, Some(s)) => (s, false),
+            _ => (s, false),
+        };
+        let width = match s {
+            "u8" => 1,
+            "u16" => 2,
+            "u32" => 4,
+            "u64" => 8,
+            _ => return None,
+        };
+        Some(IntType { width, big_endian })
+    }
+
+    /// Encodes the value, failing if it does not fit in the field
+    pub fn encode(&self, value: u64) -> Result<Vec<u8>> {
+        if self.width < 8 && value >> (self.width * 8) != 0 {
+            bail!("Value 0x{:x} does not fit in a {}-byte field", value, self.width);
+        }
+        let bytes = if self.big_endian {
+            value.to_be_bytes()[8 - self.width..].to_vec()
+        }
+        else {
+            value.to_le_bytes()[..self.width].to_vec()
+        };
+        Ok(bytes)
+    }
+}
+
+/// Parses a field type, failing with a message naming the field
+pub fn int_type(s: &str) -> Result<IntType> {
+    IntType::parse(s).ok_or_else(|| anyhow!("Unknown field type '{}'", s))
+}
+
+/// The type of a struct field: an integer, bits of an integer or `[u8; N]`
+/// bytes, which are filled from a string or bytes value and padded with
+/// zeros
+#[derive(Clone, Copy)]
+pub enum FieldType {
+    Int(IntType),
+    Bits(IntType, u32),
+    Bytes(usize),
+}
+
+impl FieldType {
+    pub fn parse(s: &str) -> Result<FieldType> {
+        if let Some(len) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            let len = len.strip_prefix("u8")
+                .and_then(|len| len.trim_start().strip_prefix(';'))
+                .and_then(|len| len.trim().parse().ok())
+                .ok_or_else(|| anyhow!("Unknown field type '{}'", s))?;
+            return Ok(FieldType::Bytes(len));
+        }
+        if let Some((kind, bits)) = s.split_once(':') {
+            let kind = int_type(kind.trim())?;
+            let bits = bits.trim().parse().ok()
+                .filter(|bits| (1..=kind.width as u32 * 8).contains(bits))
+                .ok_or_else(|| anyhow!("Invalid bit width in '{}'", s))?;
+            return Ok(FieldType::Bits(kind, bits));
+        }
+        int_type(s).map(FieldType::Int)
+    }
+
+    /// Returns the size of the field, bitfields share theirs
+    pub fn size(&self) -> usize {
+        match self {
+            FieldType::Int(kind) | FieldType::Bits(kind, _) => kind.width,
+            FieldType::Bytes(len) => *len,
+        }
+    }
+
+    pub fn encode(&self, value: &Value) -> Result<Vec<u8>> {
+        match (self, value) {
+            (FieldType::Int(kind), Value::Int(number)) => kind.encode(*number),
+            (FieldType::Bytes(len), Value::Str(_) | Value::Bytes(_)) => {
+                let mut bytes = match value {
+                    Value::Str(text) => text.as_bytes().to_vec(),
+                    Value::Bytes(bytes) => bytes.clone(),
+                    Value::Int(_) => unreachable!(),
+                };
+                if bytes.len() > *len {
+                    bail!("Value is {} bytes long, the field holds {}", bytes.len(), len);
+                }
+                bytes.resize(*len, 0);
+                Ok(bytes)
+            }
+            (FieldType::Bits(..), _) => bail!("Bitfields are only encoded with their record"),
+            (FieldType::Int(_), _) => bail!("Expected an integer"),
+            (FieldType::Bytes(_), _) => bail!("Expected a string or bytes"),
+        }
+    }
+}
+
+/// The fields of a struct declared with the `struct` directive
+pub type Struct = Vec<(String, FieldType)>;
+
+/// Encodes a record of the struct, `value` returns the value of a field or
+/// `None` to leave it zeroed
+pub fn encode_record<F>(declared: &Struct, mut value: F) -> Result<Vec<u8>>
+where
+    F: FnMut(&str, &FieldType) -> Result<Option<Value>>,
+{
+    let mut record = Vec::new();
+    // The integer bitfields are packed into and the bits it has used
+    let mut unit: Option<(IntType, u32, u64)> = None;
+
+    for (name, kind) in declared {
+        let context = || format!("invalid value of field {}", name);
+        let value = value(name, kind).with_context(context)?;
+
+        if let FieldType::Bits(int, bits) = *kind {
+            let number = match value {
+                Some(Value::Int(number)) => number,
+                Some(_) => return Err(anyhow!("Expected an integer")).with_context(context),
+                None => 0,
+            };
+            if number.checked_shr(bits).unwrap_or(0) != 0 {
+                let err = anyhow!("Value 0x{:x} does not fit in {} bits", number, bits);
+                return Err(err).with_context(context);
+            }
+            unit = match unit {
+                Some((current, used, packed)) if current == int && used + bits <= int.width as u32 * 8 => {
+                    Some((current, used + bits, packed | number << used))
+                }
+                previous => {
+                    if let Some((current, _, packed)) = previous {
+                        record.extend(current.encode(packed)?);
+                    }
+                    Some((int, bits, number))
+                }
+            };
+            continue;
+        }
+
+        if let Some((current, _, packed)) = unit.take() {
+            record.extend(current.encode(packed)?);
+        }
+        match value {
+            Some(value) => record.extend(kind.encode(&value).with_context(context)?),
+            None => record.resize(record.len() + kind.size(), 0),
+        }
+    }
+    if let Some((current, _, packed)) = unit {
+        record.extend(current.encode(packed)?);
+    }
+
+    Ok(record)
+}
@@ -0,0 +1,309 @@
+//! Fixtures of the external inputs of a build for hermetic tests. With
+//! `--record DIR` the input files and downloads a build reads are copied to
+//! the directory, `--replay DIR` then builds from it alone without touching
+//! the original files or the network.
+//!
+//! Inputs are stored by the SHA-256 of their content as `DIR/objects/HEX`.
+//! `DIR/index.json` maps the path of an input file as the layout resolves
+//! it, or the URL or reference of a download, to the hash. A directory read
+//! by functions like `archive` maps to `tree:HEX`, the hash of a JSON list
+//! of its entries with their permissions and the objects of their files.
+//! Replays restore the directory in a temporary one.
+//!
+//! Every input the layout resolves is also listed for `--incremental` and
+//! `--watch`, whatever the mode.
+
+use anyhow::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::path;
+use std::process;
+use std::sync::{Mutex, OnceLock};
+
+use crate::archive;
+use crate::digest;
+
+pub enum Mode {
+    Record(path::PathBuf),
+    Replay(path::PathBuf),
+}
+
+const INDEX: &str = "index.json";
+
+/// The mode of the process, inputs are used as they are without one
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+/// Hashes of the inputs by their names
+static RECORDED: OnceLock<Mutex<BTreeMap<String, String>>> = OnceLock::new();
+
+fn recorded() -> &'static Mutex<BTreeMap<String, String>> {
+    RECORDED.get_or_init(Default::default)
+}
+
+/// Paths of the inputs resolved since they were last taken
+static OPENED: OnceLock<Mutex<BTreeSet<path::PathBuf>>> = OnceLock::new();
+
+/// Returns the paths of the input files and directories resolved since the
+/// last call, as the layout names them
+pub fn take_opened() -> Vec<path::PathBuf> {
+    let opened = std::mem::take(&mut *OPENED.get_or_init(Default::default).lock().unwrap());
+    opened.into_iter().collect()
+}
+
+/// Sets the mode for the rest of the process, loading the index to replay
+pub fn start(mode: Mode) -> Result<()> {
+    if let Mode::Replay(dir) = &mode {
+        let ipath = dir.join(INDEX);
+        let text = fs::read_to_string(&ipath)
+            .with_context(
+                || format!("could not open file `{}`", ipath.display())
+            )?;
+        *recorded().lock().unwrap() = serde_json::from_str(&text)
+            .with_context(
+                || format!("could not parse file `{}`", ipath.display())
+            )?;
+    }
+    if MODE.set(mode).is_err() {
+        bail!("Fixtures are already recorded or replayed");
+    }
+    Ok(())
+}
+
+/// Writes the index of the recorded inputs
+pub fn finish() -> Result<()> {
+    if let Some(Mode::Record(dir)) = MODE.get() {
+        let ipath = dir.join(INDEX);
+        let text = serde_json::to_string_pretty(&*recorded().lock().unwrap())?;
+        fs::write(&ipath, text + "\n")
+            .with_context(
+                || format!("could not write file `{}`", ipath.display())
+            )?;
+    }
+    Ok(())
+}
+
+/// Prefix of the hashes of recorded directories in the index
+const TREE: &str = "tree:";
+
+/// An entry of a recorded directory, files refer to their objects
+#[derive(Serialize, Deserialize)]
+struct TreeEntry {
+    name: String,
+    permissions: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+/// Directories restored by the replay, removed by `remove_restored`
+static RESTORED: Mutex<Vec<path::PathBuf>> = Mutex::new(Vec::new());
+
+fn read(fpath: &path::Path) -> Result<Vec<u8>> {
+    fs::read(fpath)
+        .with_context(
+            || format!("could not read file `{}`", fpath.display())
+        )
+}
+
+/// Stores the data under its hash, returns the hash
+fn store_object(dir: &path::Path, data: &[u8]) -> Result<String> {
+    let hash = digest::to_hex(&Sha256::digest(data));
+    let opath = dir.join("objects").join(&hash);
+    if !opath.exists() {
+        fs::create_dir_all(dir.join("objects"))
+            .and_then(|_| fs::write(&opath, data))
+            .with_context(
+                || format!("could not write file `{}`", opath.display())
+            )?;
+    }
+    Ok(hash)
+}
+
+/// Stores the data under its hash and records it for the name
+fn store(dir: &path::Path, name: &str, data: &[u8]) -> Result<()> {
+    let hash = store_object(dir, data)?;
+    recorded().lock().unwrap().insert(name.to_string(), hash);
+    Ok(())
+}
+
+/// Stores the files of the directory and the list of its entries, records
+/// the list for the name
+fn store_tree(dir: &path::Path, name: &str, tpath: &path::Path) -> Result<()> {
+    let mut tree = Vec::new();
+    for entry in archive::entries(tpath)? {
+        let sha256 = if entry.is_dir {
+            None
+        }
+        else {
+            Some(store_object(dir, &read(&entry.path)?)?)
+        };
+        tree.push(TreeEntry { name: entry.name, permissions: entry.permissions, sha256 });
+    }
+    let hash = store_object(dir, &serde_json::to_vec_pretty(&tree)?)?;
+    recorded().lock().unwrap().insert(name.to_string(), format!("{}{}", TREE, hash));
+    Ok(())
+}
+
+/// Returns the path of the stored input recorded for the name, restoring
+/// a recorded directory
+fn replayed(dir: &path::Path, name: &str) -> Result<path::PathBuf> {
+    let hash = match recorded().lock().unwrap().get(name) {
+        Some(hash) => hash.clone(),
+        None => bail!("`{}` is not recorded in the fixtures `{}`", name, dir.display()),
+    };
+    match hash.strip_prefix(TREE) {
+        Some(hash) => restore_tree(dir, hash),
+        None => Ok(dir.join("objects").join(hash)),
+    }
+}
+
+/// Creates the recorded directory from its objects, once per process
+fn restore_tree(dir: &path::Path, hash: &str) -> Result<path::PathBuf> {
+    let tpath = env::temp_dir().join(format!("bincomb-replay-{}-{}", process::id(), hash));
+    let mut restored = RESTORED.lock().unwrap();
+    if restored.contains(&tpath) {
+        return Ok(tpath);
+    }
+
+    let lpath = dir.join("objects").join(hash);
+    let tree: Vec<TreeEntry> = serde_json::from_slice(&read(&lpath)?)
+        .with_context(
+            || format!("could not parse file `{}`", lpath.display())
+        )?;
+    let _ = fs::remove_dir_all(&tpath);
+    fs::create_dir_all(&tpath)
+        .with_context(
+            || format!("could not create directory `{}`", tpath.display())
+        )?;
+    restored.push(tpath.clone());
+
+    for entry in &tree {
+        let relative = path::Path::new(&entry.name);
+        if !relative.components().all(|part| matches!(part, path::Component::Normal(_))) {
+            bail!("Invalid name `{}` in the recorded directory `{}`", entry.name, lpath.display());
+        }
+        let epath = tpath.join(relative);
+        let result = match &entry.sha256 {
+            Some(hash) => fs::copy(dir.join("objects").join(hash), &epath).map(|_| ()),
+            None => fs::create_dir_all(&epath),
+        };
+        result.with_context(
+            || format!("could not restore `{}`", epath.display())
+        )?;
+    }
+    // Directories may be read-only, children are restored first
+    for entry in tree.iter().rev() {
+        set_permissions(&tpath.join(&entry.name), entry.permissions)?;
+    }
+
+    Ok(tpath)
+}
+
+#[cfg(unix)]
+fn set_permissions(fpath: &path::Path, permissions: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(fpath, fs::Permissions::from_mode(permissions))
+        .with_context(
+            || format!("could not set the permissions of `{}`", fpath.display())
+        )
+}
+
+#[cfg(not(unix))]
+fn set_permissions(_fpath: &path::Path, _permissions: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Removes the directories restored by the replay
+pub fn remove_restored() {
+    for tpath in RESTORED.lock().unwrap().drain(..) {
+        make_writable(&tpath);
+        let _ = fs::remove_dir_all(&tpath);
+    }
+}
+
+/// Makes the restored directories writable again so they can be removed
+#[cfg(unix)]
+fn make_writable(tpath: &path::Path) {
+    let entries = archive::entries(tpath).unwrap_or_default();
+    for dpath in entries.iter().filter(|entry| entry.is_dir).map(|entry| &entry.path) {
+        let _ = set_permissions(dpath, 0o755);
+    }
+    let _ = set_permissions(tpath, 0o755);
+}
+
+#[cfg(not(unix))]
+fn make_writable(_tpath: &path::Path) {}
+
+/// Returns the path to read an input file or directory from, recording it
+/// first
+pub fn input(ipath: path::PathBuf) -> Result<path::PathBuf> {
+    OPENED.get_or_init(Default::default).lock().unwrap().insert(ipath.clone());
+    let name = ipath.to_string_lossy();
+    match MODE.get() {
+        Some(Mode::Record(dir)) if ipath.is_dir() => {
+            store_tree(dir, &name, &ipath)?;
+            Ok(ipath)
+        }
+        Some(Mode::Record(dir)) if ipath.is_file() => {
+            store(dir, &name, &read(&ipath)?)?;
+            Ok(ipath)
+        }
+        Some(Mode::Replay(dir)) => replayed(dir, &name),
+        _ => Ok(ipath),
+    }
+}
+
+/// Downloads already made by the process, by their names
+static FETCHED: OnceLock<Mutex<HashMap<String, Vec<u8>>>> = OnceLock::new();
+
+/// Downloads the data named by the URL or reference once per process, a
+/// build evaluates the layout several times. The data comes from the
+/// fixtures when replaying.
+pub fn fetch<F>(name: &str, download: F) -> Result<Vec<u8>>
+where
+    F: FnOnce() -> Result<Vec<u8>>,
+{
+    let fetched = FETCHED.get_or_init(Default::default);
+    if let Some(data) = fetched.lock().unwrap().get(name) {
+        return Ok(data.clone());
+    }
+
+    let data = match MODE.get() {
+        Some(Mode::Record(dir)) => {
+            let data = download()?;
+            store(dir, name, &data)?;
+            Ok(data)
+        }
+        Some(Mode::Replay(dir)) => read(&replayed(dir, name)?),
+        None => download(),
+    }?;
+    fetched.lock().unwrap().insert(name.to_string(), data.clone());
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn fetches_once() {
+        let calls = Cell::new(0);
+        let download = || {
+            calls.set(calls.get() + 1);
+            Ok(b"data".to_vec())
+        };
+        assert_eq!(fetch("test:fetches_once", download).unwrap(), b"data");
+        assert_eq!(fetch("test:fetches_once", download).unwrap(), b"data");
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn failed_fetches_are_retried() {
+        assert!(fetch("test:failed", || bail!("offline")).is_err());
+        assert_eq!(fetch("test:failed", || Ok(b"data".to_vec())).unwrap(), b"data");
+    }
+}
@@ -0,0 +1,59 @@
+//! Input files stored in git repositories, fetched with the `git` command.
+
+use anyhow::{bail, Context as _, Result};
+use std::env;
+use std::fs;
+use std::path;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static FETCHES: AtomicUsize = AtomicUsize::new(0);
+
+fn git(dir: &path::Path, args: &[&str]) -> Result<Output> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("could not run `git`")?;
+
+    if !output.status.success() {
+        bail!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(output)
+}
+
+/// Checks whether the revision is a full commit id rather than a branch or
+/// tag, which may be moved
+pub fn is_commit(rev: &str) -> bool {
+    matches!(rev.len(), 40 | 64) && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Returns the content of the file at `rev` in the repository, only the
+/// requested revision is fetched
+pub fn fetch(repo: &str, rev: &str, file: &str) -> Result<Vec<u8>> {
+    let dir = env::temp_dir().join(format!(
+        "bincomb-git-{}-{}",
+        std::process::id(),
+        FETCHES.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir)
+        .with_context(
+            || format!("could not create directory `{}`", dir.display())
+        )?;
+
+    let result = git(&dir, &["init", "-q"])
+        .and_then(|_| git(&dir, &["fetch", "-q", "--depth", "1", repo, rev]))
+        .and_then(|_| git(&dir, &["show", &format!("FETCH_HEAD:{}", file)]))
+        .with_context(
+            || format!("Could not fetch {} at {} from {}", file, rev, repo)
+        );
+    let _ = fs::remove_dir_all(&dir);
+
+    Ok(result?.stdout)
+}
@@ -0,0 +1,56 @@
+//! Shell commands run before and after a build, given with `--pre-cmd` and
+//! `--post-cmd`.
+//!
+//! Commands run in the current directory with `BINCOMB_LAYOUT` and
+//! `BINCOMB_OUTPUT` set to the paths of the build. Commands run after the
+//! build also get `BINCOMB_SIZE` and `BINCOMB_SHA256` of the output and every
+//! variable as `BINCOMB_VAR_<NAME>`, upper case with dots replaced by
+//! underscores, such as `BINCOMB_VAR_APP_SIZE` for `$APP.size`.
+
+use anyhow::{bail, Context as _, Result};
+use std::process::Command;
+
+use crate::layout::Context;
+
+/// Environment variables for the command
+pub type Env = Vec<(String, String)>;
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    }
+    else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
+
+/// Returns the variables of the layout as environment variables
+pub fn variables(ctx: &Context) -> Env {
+    let name = |var: &str| format!("BINCOMB_VAR_{}", var.replace('.', "_").to_ascii_uppercase());
+    let mut env = ctx.vars.iter()
+        .map(|(var, value)| (name(var), value.to_string()))
+        .chain(ctx.strings.iter().map(|(var, value)| (name(var), value.clone())))
+        .collect::<Env>();
+    env.sort();
+    env
+}
+
+/// Runs the command with the shell, fails if it doesn't exit successfully
+pub fn run(command: &str, env: &Env) -> Result<()> {
+    let status = shell(command)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .status()
+        .with_context(
+            || format!("could not run `{}`", command)
+        )?;
+
+    if !status.success() {
+        bail!("`{}` failed with {}", command, status);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,234 @@
+//! The HTTP agent shared by remote layouts, `url` inputs, release assets
+//! and OCI registries.
+//!
+//! Requests failing with a connection error or a 5xx status are retried
+//! `--retries` times, waiting `--retry-delay` before the first retry and
+//! twice as long before each next one. `--http-timeout` bounds each attempt.
+//!
+//! Servers are trusted if their certificate is issued by one of the Mozilla
+//! roots or a CA of `--ca-cert`, `--client-cert` and `--client-key`
+//! authenticate bincomb to servers requiring mutual TLS.
+
+use anyhow::{bail, Context as _, Result};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::fmt;
+use std::path;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::diagnostics;
+
+pub struct Options {
+    pub retries: u32,
+    pub retry_delay: Duration,
+    /// Limit of the time to connect and transfer a response, none without
+    pub timeout: Option<Duration>,
+    pub max_redirects: u32,
+    pub user_agent: String,
+    /// How retries are reported, none to keep quiet about them
+    pub report: Option<diagnostics::Format>,
+    /// TLS configuration replacing the default one of `ureq`
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+            timeout: None,
+            max_redirects: 5,
+            user_agent: USER_AGENT.to_string(),
+            report: Some(diagnostics::Format::Human),
+            tls: None,
+        }
+    }
+}
+
+pub const USER_AGENT: &str = concat!("bincomb/", env!("CARGO_PKG_VERSION"));
+
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+
+static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+
+/// Sets the options for the rest of the process
+pub fn configure(options: Options) {
+    let _ = OPTIONS.set(options);
+}
+
+fn options() -> &'static Options {
+    OPTIONS.get_or_init(Default::default)
+}
+
+/// Starts a GET request with the shared agent
+pub fn get(url: &str) -> ureq::Request {
+    AGENT.get_or_init(|| {
+        let options = options();
+        let mut builder = ureq::AgentBuilder::new()
+            .redirects(options.max_redirects)
+            .user_agent(&options.user_agent);
+        if let Some(timeout) = options.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(tls) = &options.tls {
+            builder = builder.tls_config(tls.clone());
+        }
+        builder.build()
+    }).get(url)
+}
+
+/// Builds a TLS configuration trusting the certificates of the PEM bundle
+/// besides the Mozilla roots, and presenting the PEM client certificate
+/// chain and key to servers asking for one
+pub fn tls_config(
+    ca_bundle: Option<&path::Path>,
+    client: Option<(&path::Path, &path::Path)>,
+) -> Result<Arc<rustls::ClientConfig>> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(bpath) = ca_bundle {
+        for cert in read_certs(bpath)? {
+            roots.add(cert)
+                .with_context(
+                    || format!("invalid CA certificate in `{}`", bpath.display())
+                )?;
+        }
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let config = match client {
+        Some((cpath, kpath)) => {
+            let key = PrivateKeyDer::from_pem_file(kpath)
+                .with_context(
+                    || format!("could not read a private key from `{}`", kpath.display())
+                )?;
+            builder.with_client_auth_cert(read_certs(cpath)?, key)
+                .with_context(
+                    || format!("invalid client certificate `{}`", cpath.display())
+                )?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Reads the certificates of a PEM file
+fn read_certs(cpath: &path::Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(cpath)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(
+            || format!("could not read certificates from `{}`", cpath.display())
+        )?;
+    if certs.is_empty() {
+        bail!("no certificates in `{}`", cpath.display());
+    }
+    Ok(certs)
+}
+
+/// Sends the request, retrying transient failures. Errors are those of
+/// `ureq` for callers to handle statuses like 401
+#[allow(clippy::result_large_err)]
+pub fn call(request: ureq::Request) -> Result<ureq::Response, ureq::Error> {
+    let mut attempt = 0;
+    loop {
+        match request.clone().call() {
+            Err(err) if is_transient(&err) && wait_retry(attempt, &err) => attempt += 1,
+            result => return result,
+        }
+    }
+}
+
+/// Reports the failure and waits before retrying it for the `attempt + 1`th
+/// time, false if no retries are left
+pub fn wait_retry(attempt: u32, err: &dyn fmt::Display) -> bool {
+    let options = options();
+    if attempt >= options.retries {
+        return false;
+    }
+    let delay = backoff(options.retry_delay, attempt);
+    if let Some(format) = options.report {
+        let message = format!("{}, retrying in {:.1}s", err, delay.as_secs_f64());
+        diagnostics::global_warning(format, "retry", &message);
+    }
+    thread::sleep(delay);
+    true
+}
+
+/// Returns the `Authorization` header value of HTTP basic authentication
+pub fn basic_auth(user: &str, password: &str) -> String {
+    format!("Basic {}", base64(&format!("{}:{}", user, password)))
+}
+
+fn base64(s: &str) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+
+    for chunk in s.as_bytes().chunks(3) {
+        let bits = chunk.iter().enumerate()
+            .fold(0u32, |acc, (i, &byte)| acc | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            }
+            else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn is_transient(err: &ureq::Error) -> bool {
+    match err {
+        ureq::Error::Transport(_) => true,
+        ureq::Error::Status(status, _) => *status >= 500,
+    }
+}
+
+/// Returns the time to wait before the retry after `attempt` failed ones
+fn backoff(delay: Duration, attempt: u32) -> Duration {
+    delay.saturating_mul(1 << attempt.min(16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_delays() {
+        let delay = Duration::from_millis(250);
+        assert_eq!(backoff(delay, 0), Duration::from_millis(250));
+        assert_eq!(backoff(delay, 1), Duration::from_millis(500));
+        assert_eq!(backoff(delay, 3), Duration::from_secs(2));
+        assert_eq!(backoff(Duration::MAX, 40), Duration::MAX);
+    }
+
+    #[test]
+    fn base64_vectors() {
+        // RFC 4648, section 10
+        assert_eq!(base64(""), "");
+        assert_eq!(base64("f"), "Zg==");
+        assert_eq!(base64("fo"), "Zm8=");
+        assert_eq!(base64("foo"), "Zm9v");
+        assert_eq!(base64("foob"), "Zm9vYg==");
+        assert_eq!(base64("fooba"), "Zm9vYmE=");
+        assert_eq!(base64("foobar"), "Zm9vYmFy");
+        // RFC 7617, section 2
+        assert_eq!(base64("Aladdin:open sesame"), "QWxhZGRpbjpvcGVuIHNlc2FtZQ==");
+        assert_eq!(base64("\u{ff}\u{fe}"), "w7/Dvg==");
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let status = |code| ureq::Error::Status(code, ureq::Response::new(code, "", "").unwrap());
+        assert!(is_transient(&status(503)));
+        assert!(!is_transient(&status(404)));
+        assert!(!is_transient(&status(401)));
+    }
+}
@@ -0,0 +1,2309 @@
+use anyhow::{anyhow, bail, Context as _, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, copy, Cursor, SeekFrom, Seek, Read, Write, BufRead, BufReader};
+use std::path;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::fmt;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+use crate::archive;
+use crate::cbor;
+use crate::digest;
+use crate::directive;
+use crate::disk;
+use crate::esp32;
+use crate::expr;
+use crate::fat;
+use crate::fdt;
+use crate::fields;
+use crate::fixture;
+use crate::git;
+use crate::littlefs;
+use crate::mcuboot;
+use crate::oci;
+use crate::partitions;
+use crate::plugin;
+use crate::progress;
+use crate::release;
+use crate::remote;
+use crate::signature::Signed;
+use crate::table;
+use crate::target;
+use crate::url;
+use crate::value;
+
+#[derive(Debug)]
+pub struct Entry<'a> {
+    pub addr: u64,
+    pub name: &'a str,
+    pub func: &'a str,
+    pub args: Vec<&'a str>,
+    /// Expression after `if`, the statement is skipped when it is zero
+    pub guard: Option<&'a str>,
+    /// Marked `final`, no later statement may write to its region
+    pub sealed: bool,
+}
+
+/// Size of the chunks ranges of the image are read in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Offset of the word `vector_checksum` writes in the vector table
+const VECTOR_CHECKSUM_OFFSET: u64 = 0x1c;
+
+/// Functions defining `$NAME.value` from the data they read
+const CHECKSUM_FUNCTIONS: &[&str] = &["crc16", "crc16_hex", "crc16_var", "crc32_var", "vector_checksum"];
+
+/// A part of the image written by a single layout entry
+#[derive(Debug, Serialize)]
+pub struct Region {
+    pub name: String,
+    #[serde(rename = "offset")]
+    pub addr: u64,
+    pub size: u64,
+    #[serde(rename = "function")]
+    pub func: String,
+    pub source: Option<String>,
+    pub checksum: Option<u64>,
+    /// Ranges of the image read by the entry as `(offset, length)`
+    #[serde(skip)]
+    pub reads: Vec<(u64, u64)>,
+    /// Time it took to execute the entry
+    #[serde(skip)]
+    pub elapsed: Duration,
+    /// Later statements must not write to the region, see `Entry::sealed`
+    #[serde(skip)]
+    pub sealed: bool,
+}
+
+impl Region {
+    /// Returns the offset right after the last byte of the region
+    pub fn end(&self) -> u64 {
+        self.addr + self.size
+    }
+}
+
+/// Errors in the layout itself, as opposed to I/O errors
+#[derive(Debug)]
+pub enum LayoutError {
+    Syntax(&'static str),
+    UnknownFunction(String),
+    Arguments,
+    MissingVariable(String),
+}
+
+impl LayoutError {
+    /// A stable identifier of the error kind for tools
+    pub fn code(&self) -> &'static str {
+        match self {
+            LayoutError::Syntax(_) => "syntax",
+            LayoutError::UnknownFunction(_) => "unknown-function",
+            LayoutError::Arguments => "arguments",
+            LayoutError::MissingVariable(_) => "undefined-variable",
+        }
+    }
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LayoutError::Syntax(msg) => write!(f, "{}", msg),
+            LayoutError::UnknownFunction(name) => write!(f, "Unknown function name '{}'", name),
+            LayoutError::Arguments => write!(f, "Error number of arguments"),
+            LayoutError::MissingVariable(name) => write!(f, "Missing variable: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+/// Checks of the image that failed after it was built
+#[derive(Debug)]
+pub enum CheckError {
+    /// The output or an input doesn't have the expected content
+    Mismatch(String),
+    /// A region is placed where it must not be
+    Placement(String),
+}
+
+impl CheckError {
+    /// A stable identifier of the error kind for tools
+    pub fn code(&self) -> &'static str {
+        match self {
+            CheckError::Mismatch(_) => "mismatch",
+            CheckError::Placement(_) => "placement",
+        }
+    }
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckError::Mismatch(msg) | CheckError::Placement(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+/// Position of a statement in the layout, attached to errors as context
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub file: path::PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub parsing: bool,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.parsing {
+            write!(f, "Failed to parse line {}", self.line)
+        }
+        else {
+            write!(f, "Failed on line {}", self.line)
+        }
+    }
+}
+
+/// A problem found while executing that doesn't stop the build
+#[derive(Debug, Serialize)]
+pub struct Warning {
+    pub line: usize,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// A value of a variable defined outside of the statements
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Value {
+    Int(u64),
+    Str(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Str(value) => write!(f, "{:?}", value),
+        }
+    }
+}
+
+/// State shared by the entries of a layout while it is executed
+pub struct Context {
+    pub vars: HashMap<String, u64>,
+    /// String variables, these can only be used where a path or text is expected
+    pub strings: HashMap<String, String>,
+    /// Resolve arguments and sizes only, without reading or writing any data
+    pub dry_run: bool,
+    /// In a dry run, only check that inputs exist instead of downloading and
+    /// hashing them, downloads are taken to be empty
+    pub check_only: bool,
+    /// Trace executed entries to stderr: 1 for regions, 2 for arguments too
+    pub verbose: u8,
+    /// Problems found while executing that don't stop the build
+    pub warnings: Vec<Warning>,
+    /// Directory relative input paths are resolved against, empty for the
+    /// working directory
+    pub base: path::PathBuf,
+    /// Report progress of large copies and downloads on stderr
+    pub progress: bool,
+    /// Structs declared with the `struct` directive
+    pub structs: HashMap<String, fields::Struct>,
+    /// While planning, variables statements referred to before they were
+    /// defined, such statements are skipped instead of failing
+    pub missing: Option<Vec<String>>,
+    /// Refuse functions whose result may differ between builds of the same
+    /// layout and inputs
+    pub reproducible: bool,
+    /// Timestamp of archive entries, `SOURCE_DATE_EPOCH` in reproducible builds
+    pub epoch: u64,
+    /// Flash the image is built for, regions are checked against it
+    pub target: Option<target::Target>,
+    /// Fail on regions not aligned to sectors instead of warning
+    pub deny_unaligned: bool,
+    /// Lowest address regions may write to, see `min_offset`
+    pub min_offset: u64,
+    /// Replace `${NAME}` in text arguments and strings with the environment
+    /// variable, see `expand_env`
+    pub expand_env: bool,
+    /// Layouts merged into the executed one, see `statements`
+    pub overlays: Vec<path::PathBuf>,
+    /// Statements executed in full, the others only define their variables
+    /// as in a dry run. Statements whose checksums they use are added by `run`.
+    pub only: Option<Vec<String>>,
+    /// Libraries providing additional functions, see `plugin`
+    pub plugins: Vec<Arc<plugin::Plugin>>,
+}
+
+impl Context {
+    pub fn new(defines: &[(String, Value)]) -> Context {
+        let mut vars = HashMap::new();
+        let mut strings = HashMap::new();
+        for (name, value) in defines {
+            match value {
+                Value::Int(value) => {
+                    vars.insert(name.clone(), *value);
+                }
+                Value::Str(value) => {
+                    strings.insert(name.clone(), value.clone());
+                }
+            }
+        }
+
+        let mut ctx = Context {
+            vars,
+            strings,
+            dry_run: false,
+            check_only: false,
+            verbose: 0,
+            warnings: Vec::new(),
+            base: path::PathBuf::new(),
+            progress: false,
+            structs: HashMap::new(),
+            missing: None,
+            reproducible: false,
+            epoch: 0,
+            target: None,
+            deny_unaligned: false,
+            min_offset: 0,
+            expand_env: false,
+            overlays: Vec::new(),
+            only: None,
+            plugins: Vec::new(),
+        };
+        set_image_end(&mut ctx, 0);
+        ctx
+    }
+
+    /// Returns a context for a dry run with the same variables and paths
+    pub fn planning(&self) -> Context {
+        let mut ctx = Context::new(&[]);
+        ctx.vars = self.vars.clone();
+        ctx.strings = self.strings.clone();
+        ctx.base = self.base.clone();
+        ctx.structs = self.structs.clone();
+        ctx.reproducible = self.reproducible;
+        ctx.epoch = self.epoch;
+        ctx.target = self.target.clone();
+        ctx.deny_unaligned = self.deny_unaligned;
+        ctx.min_offset = self.min_offset;
+        ctx.expand_env = self.expand_env;
+        ctx.overlays = self.overlays.clone();
+        ctx.plugins = self.plugins.clone();
+        ctx.dry_run = true;
+        ctx.check_only = self.check_only;
+        ctx
+    }
+
+    /// Returns the path of an input file named in the layout
+    pub fn resolve(&self, arg: &str) -> Result<path::PathBuf> {
+        fixture::input(self.base.join(self.text(arg)?))
+    }
+
+    /// Returns a text argument, `$NAME` is replaced with the variable
+    pub fn text(&self, arg: &str) -> Result<String> {
+        if self.expand_env && arg.contains("${") {
+            return expand_env(arg);
+        }
+        match arg.strip_prefix('$') {
+            Some(name) => match (self.strings.get(name), self.vars.get(name)) {
+                (Some(value), _) => Ok(value.clone()),
+                (None, Some(value)) => Ok(value.to_string()),
+                (None, None) => Err(LayoutError::MissingVariable(arg.to_string()).into()),
+            },
+            None => Ok(arg.to_string()),
+        }
+    }
+
+    /// Defines the variable unless it is already defined, as done by the
+    /// `default NAME VALUE` directive
+    pub fn set_default(&mut self, name: &str, value: Value) {
+        if self.vars.contains_key(name) || self.strings.contains_key(name) {
+            return;
+        }
+        match value {
+            Value::Int(value) => {
+                self.vars.insert(name.to_string(), value);
+            }
+            Value::Str(value) => {
+                self.strings.insert(name.to_string(), value);
+            }
+        }
+    }
+}
+
+/// An image expression functions can read
+pub trait Image: Read + Seek {}
+
+impl<T: Read + Seek> Image for T {}
+
+/// Input files and the image as seen by expression functions
+pub struct Memory<'a> {
+    base: path::PathBuf,
+    dry_run: bool,
+    strings: &'a HashMap<String, String>,
+    image: Option<&'a mut dyn Image>,
+}
+
+impl<'a> Memory<'a> {
+    pub fn new(ctx: &'a Context, image: Option<&'a mut dyn Image>) -> Memory<'a> {
+        Memory { base: ctx.base.clone(), dry_run: ctx.dry_run, strings: &ctx.strings, image }
+    }
+}
+
+impl expr::Env for Memory<'_> {
+    fn read_file(&mut self, file: &str, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let rpath = fixture::input(self.base.join(file))?;
+        let mut f = File::open(&rpath)
+            .with_context(
+                || format!("could not open file `{}`", rpath.display())
+            )?;
+        f.seek(SeekFrom::Start(offset))
+            .and_then(|_| f.read_exact(buf))
+            .with_context(
+                || format!("could not read 0x{:x} bytes at 0x{:x} of `{}`", buf.len(), offset, rpath.display())
+            )
+    }
+
+    fn peek(&mut self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        // Nothing is written by a dry run
+        if self.dry_run {
+            buf.fill(0);
+            return Ok(());
+        }
+        let image = match self.image.as_deref_mut() {
+            Some(image) => image,
+            None => bail!("There is no image to read here"),
+        };
+        image.seek(SeekFrom::Start(addr))
+            .and_then(|_| image.read_exact(buf))
+            .with_context(
+                || format!("could not read 0x{:x} bytes of the image at 0x{:x}", buf.len(), addr)
+            )
+    }
+
+    fn string(&self, name: &str) -> Option<String> {
+        self.strings.get(name).cloned()
+    }
+}
+
+/// Returns the directory of the layout file, the current one for a remote
+/// layout
+pub fn base_dir(rpath: &path::Path) -> path::PathBuf {
+    if remote::is_url(rpath) {
+        return path::PathBuf::new();
+    }
+    rpath.parent().map(path::Path::to_path_buf).unwrap_or_default()
+}
+
+/// Returns the offset right after the last byte written by the regions
+pub fn image_end(regions: &[Region]) -> u64 {
+    regions.iter()
+        .map(Region::end)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Raises `$IMAGE.end` and `$IMAGE.size`, the end of everything written so
+/// far, to the end of a region
+fn set_image_end(ctx: &mut Context, end: u64) {
+    if ctx.vars.get("IMAGE.end").is_none_or(|&known| known < end) {
+        ctx.vars.insert("IMAGE.end".to_string(), end);
+        ctx.vars.insert("IMAGE.size".to_string(), end);
+    }
+}
+
+/// Reads the statements of the layout along with their locations, skipping
+/// empty lines and comments
+pub fn read_located(rpath: &path::Path) -> Result<Vec<(Location, String)>> {
+    if remote::is_url(rpath) {
+        return Ok(parse_lines(rpath, Cursor::new(remote::fetch(rpath)?)));
+    }
+    let inf = File::open(rpath)
+        .with_context(
+            || format!("could not open file `{}`", rpath.display())
+        )?;
+
+    Ok(parse_lines(rpath, BufReader::new(inf)))
+}
+
+/// Splits layout text into statements, `rpath` is only used for locations.
+/// Statements on a line are separated by `;`, a statement continues on the
+/// next line after a trailing `,` or an unclosed bracket.
+pub fn parse_lines<R: BufRead>(rpath: &path::Path, reader: R) -> Vec<(Location, String)> {
+    let mut statements = Vec::new();
+    // Text of a statement continued on the next line, with where it starts
+    let mut pending: Option<(Location, String)> = None;
+
+    for (index, buf) in reader.lines().enumerate() {
+        if let Ok(sline) = buf {
+            let line = sline.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (location, text) = match pending.take() {
+                Some((location, mut text)) => {
+                    text.push(' ');
+                    text.push_str(line);
+                    (location, text)
+                }
+                None => {
+                    let location = Location {
+                        file: rpath.to_path_buf(),
+                        line: index + 1,
+                        column: sline.len() - sline.trim_start().len() + 1,
+                        parsing: false,
+                    };
+                    (location, line.to_string())
+                }
+            };
+            let parts = split_statements(&text);
+            if text.ends_with(',') || parts.is_none() {
+                pending = Some((location, text));
+                continue;
+            }
+            for (offset, part) in parts.unwrap_or_default() {
+                let location = Location { column: location.column + offset, ..location.clone() };
+                statements.push((location, part.to_string()));
+            }
+        }
+    }
+    // Left for parsing to report
+    statements.extend(pending);
+
+    statements
+}
+
+/// Splits the text at `;` outside of quotes and brackets into statements
+/// with their offsets, returns `None` if a bracket is left open
+fn split_statements(text: &str) -> Option<Vec<(usize, &str)>> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (index, c) in text.char_indices() {
+        match c {
+            // Byte strings escape quotes
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '(' | '[' | '{' if !quoted => depth += 1,
+            ')' | ']' | '}' if !quoted => depth = depth.saturating_sub(1),
+            ';' if !quoted && depth == 0 => {
+                parts.push((start, &text[start..index]));
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth > 0 {
+        return None;
+    }
+    parts.push((start, &text[start..]));
+
+    Some(parts.into_iter()
+        .map(|(offset, part)| (offset + part.len() - part.trim_start().len(), part.trim()))
+        .filter(|(_, part)| !part.is_empty())
+        .collect())
+}
+
+/// Returns the offsets of the pattern in the text outside of `"..."` and
+/// `b"..."` literals, whose backslashes escape quotes
+fn unquoted_offsets(text: &str, pattern: &str) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if !quoted && text[index..].starts_with(pattern) => offsets.push(index),
+            _ => {}
+        }
+    }
+
+    offsets
+}
+
+/// Splits the text at the separator outside of string literals
+pub fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for offset in unquoted_offsets(text, separator.encode_utf8(&mut [0; 4])) {
+        parts.push(&text[start..offset]);
+        start = offset + separator.len_utf8();
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Reads the statements of the layout merged with the overlays of the
+/// context. Statements of an overlay are appended, except for `default`
+/// directives which replace the ones of the same variable in place.
+pub fn statements(rpath: &path::Path, ctx: &Context) -> Result<Vec<(Location, String)>> {
+    let mut statements = read_located(rpath)?;
+
+    for opath in &ctx.overlays {
+        for (location, line) in read_located(opath)? {
+            let replaced = directive::default_name(&line).and_then(|name| {
+                statements.iter()
+                    .position(|(_, other)| directive::default_name(other) == Some(name))
+            });
+            match replaced {
+                Some(index) => statements[index] = (location, line),
+                None => statements.push((location, line)),
+            }
+        }
+    }
+
+    Ok(statements)
+}
+
+/// Number of planning passes after which forward references must be known
+const PLANNING_PASSES: usize = 8;
+
+/// Executes the layout. Variables of later statements such as `$APP.size`
+/// may be used before them, their values are learned by planning the layout
+/// first and must not change while it is executed.
+pub fn run<F>(rpath: &path::Path, ctx: &mut Context, outf: &mut F) -> Result<Vec<Region>>
+where
+    F: Seek + Read + Write,
+{
+    let statements = statements(rpath, ctx)?;
+    run_located(ctx, outf, &statements)
+}
+
+/// Executes the statements like `run`, for layouts which aren't read from
+/// their file such as the documents of the language server
+pub fn run_located<F>(ctx: &mut Context, outf: &mut F, statements: &[(Location, String)]) -> Result<Vec<Region>>
+where
+    F: Seek + Read + Write,
+{
+    directive::check_versions(statements)?;
+    directive::check_params(ctx, statements)?;
+    if let Some(only) = &ctx.only {
+        ctx.only = Some(checksum_dependencies(statements, only)?);
+    }
+
+    prefetch(ctx, statements);
+
+    let forward = plan(ctx, statements)?;
+    ctx.vars.extend(forward.clone());
+
+    let regions = execute(ctx, outf, statements)?;
+    if let Some(target) = &ctx.target {
+        target.check(&regions)?;
+    }
+
+    for (name, planned) in forward {
+        match ctx.vars.get(&name) {
+            Some(&value) if value == planned => {}
+            value => bail!(
+                "Variable ${} was used as 0x{:x} before it was defined, but is {}",
+                name, planned, value.map_or("undefined".to_string(), |value| format!("0x{:x}", value))
+            ),
+        }
+    }
+
+    Ok(regions)
+}
+
+/// Downloads the inputs of the `url` statements at the same time. Only the
+/// URLs known before the layout is executed are, those of statements with a
+/// guard or using variables defined by the layout are downloaded when the
+/// statement is reached.
+fn prefetch(ctx: &Context, statements: &[(Location, String)]) {
+    if ctx.check_only {
+        return;
+    }
+    let mut downloads = statements.iter()
+        .filter(|(_, line)| !directive::is_directive(line))
+        .filter_map(|(_, line)| Entry::from_str(line).ok())
+        .filter(|entry| entry.func == "url" && entry.guard.is_none())
+        .filter(|entry| ctx.only.as_ref().is_none_or(|only| only.iter().any(|name| name == entry.name)))
+        .filter_map(|entry| {
+            let (args, _) = split_max(ctx, &entry.args).ok()?;
+            let (args, _) = split_signature(ctx, &args).ok()?;
+            let (args, headers) = split_headers(ctx, &args).ok()?;
+            Some((ctx.text(args.first()?).ok()?, headers))
+        })
+        .collect::<Vec<(String, url::Headers)>>();
+    downloads.sort_by(|a, b| a.0.cmp(&b.0));
+    downloads.dedup_by(|a, b| a.0 == b.0);
+    if downloads.len() > 1 {
+        url::prefetch(&downloads);
+    }
+}
+
+/// Returns the named statements along with the ones whose `$NAME.value`
+/// they use, directly or through other statements
+fn checksum_dependencies(statements: &[(Location, String)], names: &[String]) -> Result<Vec<String>> {
+    let entries = statements.iter()
+        .filter(|(_, line)| !directive::is_directive(line))
+        .filter_map(|(_, line)| Entry::from_str(line).ok().map(|entry| (entry.name, line)))
+        .collect::<Vec<(&str, &String)>>();
+
+    for name in names {
+        if !entries.iter().any(|(other, _)| other == name) {
+            bail!("There is no statement named {}", name);
+        }
+    }
+
+    let mut selected = names.to_vec();
+    let mut index = 0;
+    while index < selected.len() {
+        let uses = entries.iter()
+            .filter(|(name, _)| *name == selected[index])
+            .flat_map(|(_, line)| {
+                entries.iter()
+                    .filter(move |(other, _)| uses_checksum(line, other))
+                    .map(|(other, _)| other.to_string())
+            })
+            .collect::<Vec<String>>();
+        for name in uses {
+            if !selected.contains(&name) {
+                selected.push(name);
+            }
+        }
+        index += 1;
+    }
+
+    Ok(selected)
+}
+
+/// Checks whether the statement uses the checksum or digest of statement `name`
+pub fn uses_checksum(line: &str, name: &str) -> bool {
+    line.contains(&format!("${}.value", name)) || line.contains(&format!("${}.digest", name))
+}
+
+/// Dry runs the layout until the variables used before their statements
+/// settle, returns their values
+fn plan(ctx: &Context, statements: &[(Location, String)]) -> Result<HashMap<String, u64>> {
+    let mut forward: HashMap<String, u64> = HashMap::new();
+
+    for _ in 0..PLANNING_PASSES {
+        let mut plan_ctx = ctx.planning();
+        plan_ctx.vars.extend(forward.clone());
+        plan_ctx.missing = Some(Vec::new());
+        execute(&mut plan_ctx, &mut Cursor::new(Vec::new()), statements)?;
+
+        let mut settled = forward.clone();
+        for name in plan_ctx.missing.unwrap_or_default() {
+            if let Some(&value) = plan_ctx.vars.get(&name) {
+                settled.insert(name, value);
+            }
+        }
+        for (name, value) in settled.iter_mut() {
+            if let Some(&planned) = plan_ctx.vars.get(name) {
+                *value = planned;
+            }
+        }
+        if settled == forward {
+            return Ok(forward);
+        }
+        forward = settled;
+    }
+
+    bail!("Variables used before they are defined don't settle after {} passes", PLANNING_PASSES)
+}
+
+fn execute<F>(ctx: &mut Context, outf: &mut F, statements: &[(Location, String)]) -> Result<Vec<Region>>
+where
+    F: Seek + Read + Write,
+{
+    let mut regions: Vec<Region> = Vec::new();
+
+    let slots = match directive::slots(ctx, statements)? {
+        Some(slots) => slots,
+        None => {
+            run_statements(ctx, outf, statements, None, &mut regions)?;
+            return Ok(regions);
+        }
+    };
+
+    // Every slot starts from the variables defined before the layout and
+    // the ones of earlier slots, which are kept as `$SLOT_NAME.NAME`
+    let vars = ctx.vars.clone();
+    let strings = ctx.strings.clone();
+    let structs = ctx.structs.clone();
+    let mut kept_vars = HashMap::new();
+    let mut kept_strings = HashMap::new();
+
+    for (index, slot) in slots.iter().enumerate() {
+        ctx.vars = vars.clone();
+        ctx.vars.extend(kept_vars.clone());
+        ctx.strings = strings.clone();
+        ctx.strings.extend(kept_strings.clone());
+        ctx.structs = structs.clone();
+        set_image_end(ctx, image_end(&regions));
+        directive::enter_slot(ctx, index, slot);
+        let (vars_before, strings_before) = (ctx.vars.clone(), ctx.strings.clone());
+
+        run_statements(ctx, outf, statements, Some(slot), &mut regions)?;
+
+        for (name, value) in &ctx.vars {
+            if vars_before.get(name) != Some(value) {
+                kept_vars.insert(format!("{}.{}", slot.0, name), *value);
+            }
+        }
+        for (name, value) in &ctx.strings {
+            if strings_before.get(name) != Some(value) {
+                kept_strings.insert(format!("{}.{}", slot.0, name), value.clone());
+            }
+        }
+    }
+
+    ctx.vars = vars;
+    ctx.vars.extend(kept_vars);
+    ctx.strings = strings;
+    ctx.strings.extend(kept_strings);
+    set_image_end(ctx, image_end(&regions));
+
+    Ok(regions)
+}
+
+/// Executes the statements, moved to the base address of the slot if any
+fn run_statements<F>(
+    ctx: &mut Context,
+    outf: &mut F,
+    statements: &[(Location, String)],
+    slot: Option<&directive::Slot>,
+    regions: &mut Vec<Region>,
+) -> Result<()>
+where
+    F: Seek + Read + Write,
+{
+    for (location, line) in statements {
+        let lineno = location.line;
+        if slot.is_some() && directive::is_slots(line) {
+            continue;
+        }
+        if let Some(result) = directive::execute(ctx, line, Some(&mut *outf)) {
+            if !skip_missing(ctx, &result) {
+                result.context(location.clone())?;
+            }
+            continue;
+        }
+        let mut entry = match Entry::from_str(line) {
+            Ok(entry) => entry,
+            Err(err) => return Err(err.context(Location { parsing: true, ..location.clone() })),
+        };
+        let enabled = entry.enabled(ctx);
+        if skip_missing(ctx, &enabled) {
+            continue;
+        }
+        if !enabled.context(location.clone())? {
+            continue;
+        }
+        if let Some((_, base)) = slot {
+            entry.addr = checked_end(*base, entry.addr)
+                .context(location.clone())?;
+        }
+        if ctx.verbose >= 2 {
+            trace_args(ctx, lineno, &entry);
+        }
+        let dry_run = ctx.dry_run;
+        let excluded = ctx.only.as_ref().is_some_and(|only| !only.iter().any(|name| name == entry.name));
+        if excluded {
+            ctx.dry_run = true;
+        }
+        let result = process_entry(ctx, outf, &entry);
+        ctx.dry_run = dry_run;
+        if skip_missing(ctx, &result) {
+            continue;
+        }
+        let mut region = result.context(location.clone())?;
+        if let Some((name, _)) = slot {
+            region.name = format!("{}.{}", name, region.name);
+        }
+        if ctx.verbose >= 1 {
+            trace_region(&region);
+        }
+        if region.func == "wipe" {
+            let earlier = regions.iter()
+                .map(|prev| (prev.addr, prev.size))
+                .collect::<Vec<(u64, u64)>>();
+            if let Some(&(start, len)) = covered(region.addr, region.size, &earlier)?.first() {
+                let message = format!(
+                    "Region {} wipes 0x{:x}..0x{:x}, which no earlier statement has written",
+                    region.name, start, start + len
+                );
+                return Err(anyhow!(CheckError::Placement(message)).context(location.clone()));
+            }
+        }
+        if region.size > 0 && region.addr < ctx.min_offset {
+            let message = format!(
+                "Region {} at 0x{:x}..0x{:x} starts below the minimum offset 0x{:x}",
+                region.name, region.addr, region.end(), ctx.min_offset
+            );
+            return Err(anyhow!(CheckError::Placement(message)).context(location.clone()));
+        }
+        if ctx.target.as_ref().is_some_and(|target| !target.aligned(&region)) {
+            let message = format!(
+                "region {} at 0x{:x}..0x{:x} does not start and end at sector boundaries",
+                region.name, region.addr, region.end()
+            );
+            if ctx.deny_unaligned {
+                return Err(anyhow!(CheckError::Placement(message)).context(location.clone()));
+            }
+            ctx.warnings.push(Warning { line: lineno, code: "unaligned", message });
+        }
+        // Transforms rewrite a range in place, overlapping it on purpose
+        let in_place = region.reads.contains(&(region.addr, region.size));
+        for prev in regions.iter() {
+            let overlaps = region.size > 0 && prev.size > 0
+                && region.addr < prev.end()
+                && prev.addr < region.end();
+            if overlaps && prev.sealed {
+                let message = format!(
+                    "Region {} at 0x{:x}..0x{:x} writes to the final region {} at 0x{:x}..0x{:x}",
+                    region.name, region.addr, region.end(), prev.name, prev.addr, prev.end()
+                );
+                return Err(anyhow!(CheckError::Placement(message)).context(location.clone()));
+            }
+            if overlaps && !in_place {
+                ctx.warnings.push(Warning {
+                    line: lineno,
+                    code: "overlap",
+                    message: format!("region {} overlaps {}", region.name, prev.name),
+                });
+            }
+        }
+        regions.push(region);
+    }
+
+    Ok(())
+}
+
+/// Records the variable the statement failed on while planning, returns
+/// whether the statement is to be skipped
+fn skip_missing<T>(ctx: &mut Context, result: &Result<T>) -> bool {
+    let (missing, err) = match (&mut ctx.missing, result) {
+        (Some(missing), Err(err)) => (missing, err),
+        _ => return false,
+    };
+    match err.downcast_ref::<LayoutError>() {
+        Some(LayoutError::MissingVariable(name)) => {
+            missing.push(name.trim_start_matches('$').to_string());
+            true
+        }
+        _ => false,
+    }
+}
+
+fn trace_args(ctx: &Context, lineno: usize, entry: &Entry) {
+    let args = entry.args.iter()
+        .map(|arg| match unpack_arg(&ctx.vars, arg) {
+            Ok(value) if arg.starts_with('$') => format!("{}=0x{:x}", arg, value),
+            _ => arg.to_string(),
+        })
+        .collect::<Vec<String>>();
+    eprintln!("line {}: {}({})", lineno, entry.func, args.join(", "));
+}
+
+pub fn trace_region(region: &Region) {
+    let mut line = format!(
+        "{}: {} 0x{:08x}..0x{:08x} ({} bytes)",
+        region.name, region.func, region.addr, region.end(), region.size
+    );
+    if let Some(source) = &region.source {
+        line.push_str(&format!(" from `{}`", source));
+    }
+    if let Some(checksum) = region.checksum {
+        line.push_str(&format!(" = 0x{:x}", checksum));
+    }
+    eprintln!("{}", line);
+}
+
+/// Executes the entry and returns the region of the image it has written
+pub fn process_entry<F>(ctx: &mut Context, outf: &mut F, entry: &Entry) -> Result<Region>
+where
+    F: Seek + Read + Write,
+{
+    let started = Instant::now();
+    let length: u64;
+    let written: u64;
+    let mut source: Option<String> = None;
+    let mut checksum: Option<u64> = None;
+    let mut reads: Vec<(u64, u64)> = Vec::new();
+    let mut var_name: String = entry.name.to_string();
+    var_name.push_str(".start");
+    ctx.vars.insert(var_name, entry.addr);
+
+    if entry.func == "file" {
+        let (args, max) = split_max(ctx, &entry.args)?;
+        let (args, signed) = split_signature(ctx, &args)?;
+        if args.is_empty() || args.len() > 2 {
+            bail!(LayoutError::Arguments);
+        }
+        let name = ctx.text(args[0])?;
+        let input = ctx.resolve(&name)?;
+        match args.get(1) {
+            Some(expected) if ctx.check_only => {
+                digest::parse(expected)?;
+            }
+            Some(expected) => digest::verify(&input, expected)?,
+            None => {}
+        }
+        if ctx.dry_run {
+            length = fs::metadata(&input)
+                .with_context(
+                    || format!("Could not open file {}", name)
+                )?
+                .len();
+            check_max(&name, length, max)?;
+        }
+        else {
+            let f = File::open(&input)
+                .with_context(
+                    || format!("Could not open file {}", name)
+                )?;
+            let total = f.metadata()?.len();
+            check_max(&name, total, max)?;
+            if let Some(signed) = &signed {
+                signed.verify(&name, &fs::read(&input)?)?;
+            }
+            let mut reader: Box<dyn Read> = if progress::wanted(ctx.progress, Some(total)) {
+                Box::new(progress::Reader::new(BufReader::new(f), entry.name, Some(total)))
+            }
+            else {
+                Box::new(BufReader::new(f))
+            };
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            length = copy(&mut reader, outf)?;
+            // The size was planned from the metadata, a file changing or
+            // read short would silently shift everything after it
+            if length != total {
+                bail!(
+                    "Expected {} bytes of {} at 0x{:x}, got {}",
+                    total, name, entry.addr, length
+                );
+            }
+        }
+        written = length;
+        source = Some(name);
+    }
+    else if entry.func == "git" {
+        let (args, max) = split_max(ctx, &entry.args)?;
+        let (args, signed) = split_signature(ctx, &args)?;
+        if args.len() != 3 {
+            bail!(LayoutError::Arguments);
+        }
+        let args = text_args(ctx, &args)?;
+        if ctx.reproducible && !git::is_commit(&args[1]) {
+            bail!("Reproducible builds need a full commit id instead of {}", args[1]);
+        }
+        let data = download(
+            ctx,
+            &format!("git:{}@{}:{}", args[0], args[1], args[2]),
+            || git::fetch(&args[0], &args[1], &args[2])
+        )?;
+        check_max(&args[2], data.len() as u64, max)?;
+        if let Some(signed) = &signed {
+            signed.verify(&args[2], &data)?;
+        }
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&data)?;
+        }
+        length = data.len() as u64;
+        written = length;
+        source = Some(format!("{}@{}:{}", args[0], args[1], args[2]));
+    }
+    else if entry.func == "release" {
+        let (args, max) = split_max(ctx, &entry.args)?;
+        let (args, signed) = split_signature(ctx, &args)?;
+        if args.len() != 3 {
+            bail!(LayoutError::Arguments);
+        }
+        let args = text_args(ctx, &args)?;
+        if ctx.reproducible {
+            bail!("Release assets can be replaced, reproducible builds can't download them");
+        }
+        let data = download(
+            ctx,
+            &format!("release:{}@{}:{}", args[0], args[1], args[2]),
+            || release::fetch(&args[0], &args[1], &args[2], ctx.progress)
+        )?;
+        check_max(&args[2], data.len() as u64, max)?;
+        if let Some(signed) = &signed {
+            signed.verify(&args[2], &data)?;
+        }
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&data)?;
+        }
+        length = data.len() as u64;
+        written = length;
+        source = Some(format!("{}@{}:{}", args[0], args[1], args[2]));
+    }
+    else if entry.func == "oci" {
+        let (args, max) = split_max(ctx, &entry.args)?;
+        let (args, signed) = split_signature(ctx, &args)?;
+        if args.len() != 2 {
+            bail!(LayoutError::Arguments);
+        }
+        let args = text_args(ctx, &args)?;
+        if ctx.reproducible && !args[0].contains('@') {
+            bail!("Reproducible builds need a reference by digest instead of {}", args[0]);
+        }
+        let data = download(
+            ctx,
+            &format!("oci:{}:{}", args[0], args[1]),
+            || oci::fetch(&args[0], &args[1], ctx.progress)
+        )?;
+        check_max(&args[1], data.len() as u64, max)?;
+        if let Some(signed) = &signed {
+            signed.verify(&args[1], &data)?;
+        }
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&data)?;
+        }
+        length = data.len() as u64;
+        written = length;
+        source = Some(format!("{}:{}", args[0], args[1]));
+    }
+    else if entry.func == "url" {
+        let (args, max) = split_max(ctx, &entry.args)?;
+        let (args, signed) = split_signature(ctx, &args)?;
+        let (args, headers) = split_headers(ctx, &args)?;
+        if args.is_empty() || args.len() > 2 {
+            bail!(LayoutError::Arguments);
+        }
+        let args = text_args(ctx, &args)?;
+        let expected = args.get(1).map(|expected| digest::parse(expected)).transpose()?;
+        if ctx.reproducible && expected.is_none() {
+            bail!("Downloads can change, reproducible builds need url,{},sha256:<hex>", args[0]);
+        }
+        let data = download(ctx, &url::name(&args[0]), || url::fetch(&args[0], &headers, ctx.progress))?;
+        if let Some(hex) = expected.filter(|_| !ctx.check_only) {
+            let actual = digest::to_hex(&Sha256::digest(&data));
+            if actual != hex {
+                bail!(CheckError::Mismatch(format!(
+                    "Checksum mismatch for `{}`: expected sha256:{}, got sha256:{}",
+                    args[0], hex, actual
+                )));
+            }
+        }
+        check_max(&args[0], data.len() as u64, max)?;
+        if let Some(signed) = &signed {
+            signed.verify(&args[0], &data)?;
+        }
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&data)?;
+        }
+        length = data.len() as u64;
+        written = length;
+        source = Some(args[0].clone());
+    }
+    else if entry.func == "crc16" || entry.func == "crc16_hex" {
+        // The `_hex` variant writes the value as ASCII hex digits
+        let as_hex = entry.func.ends_with("_hex");
+        let (args, excludes) = split_excludes(ctx, &entry.args)?;
+        if args.len() != 2 {
+            bail!(LayoutError::Arguments)
+        }
+
+        let addr = unpack_arg(&ctx.vars, args[0])?;
+        length = unpack_arg(&ctx.vars, args[1])?;
+        let segments = covered(addr, length, &excludes)?;
+        reads.extend(&segments);
+
+        if !ctx.dry_run {
+            let crc = crc::Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
+            let mut digest = crc.digest();
+            for &(start, len) in &segments {
+                outf.seek(SeekFrom::Start(start))?;
+                stream(outf, len, |chunk| digest.update(chunk))?;
+            }
+            let value = digest.finalize();
+            let result = if as_hex {
+                format!("{:04x}", value).into_bytes()
+            }
+            else {
+                value.to_le_bytes().to_vec()
+            };
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&result)?;
+            checksum = Some(value.into());
+        }
+        written = if as_hex { 4 } else { 2 };
+    }
+    else if entry.func == "crc16_var" || entry.func == "crc32_var" || entry.func == "digest_var" {
+        // Only defines the result, `$NAME.value` for CRCs and `$NAME.digest`
+        // as hex for digests
+        let (args, excludes) = split_excludes(ctx, &entry.args)?;
+        let algorithm = match (entry.func, args.get(2).copied()) {
+            (_, None) if args.len() == 2 => digest::Algorithm::Sha256,
+            ("digest_var", Some("sha256")) if args.len() == 3 => digest::Algorithm::Sha256,
+            ("digest_var", Some("md5")) if args.len() == 3 => digest::Algorithm::Md5,
+            ("digest_var", Some(name)) if args.len() == 3 => bail!("Unknown digest {}, expected sha256 or md5", name),
+            _ => bail!(LayoutError::Arguments),
+        };
+
+        let addr = unpack_arg(&ctx.vars, args[0])?;
+        length = unpack_arg(&ctx.vars, args[1])?;
+        let segments = covered(addr, length, &excludes)?;
+        reads.extend(&segments);
+
+        if !ctx.dry_run {
+            let crc16 = crc::Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
+            let crc32 = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+            let (mut crc16, mut crc32, mut hasher) = (crc16.digest(), crc32.digest(), algorithm.hasher());
+            for &(start, len) in &segments {
+                outf.seek(SeekFrom::Start(start))?;
+                stream(outf, len, |chunk| match entry.func {
+                    "crc16_var" => crc16.update(chunk),
+                    "crc32_var" => crc32.update(chunk),
+                    _ => hasher.update(chunk),
+                })?;
+            }
+            match entry.func {
+                "crc16_var" => checksum = Some(crc16.finalize().into()),
+                "crc32_var" => checksum = Some(crc32.finalize().into()),
+                _ => {
+                    let hex = digest::to_hex(&hasher.finalize());
+                    ctx.strings.insert(format!("{}.digest", entry.name), hex);
+                }
+            }
+        }
+        else if entry.func == "digest_var" {
+            let zeros = "0".repeat(algorithm.hasher().output_size() * 2);
+            ctx.strings.insert(format!("{}.digest", entry.name), zeros);
+        }
+        written = 0;
+    }
+    else if let Some(kind) = entry.func.strip_suffix("_hex")
+        .filter(|kind| !kind.ends_with("le") && !kind.ends_with("be"))
+        .and_then(fields::IntType::parse)
+    {
+        if entry.args.len() != 1 {
+            bail!(LayoutError::Arguments)
+        }
+
+        let value = unpack_arg(&ctx.vars, entry.args[0])?;
+        kind.encode(value)?;
+        let digits = format!("{:0width$x}", value, width = kind.width * 2);
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(digits.as_bytes())?;
+        }
+        length = digits.len() as u64;
+        written = length;
+    }
+    else if entry.func == "vector_checksum" {
+        if entry.args.len() != 1 {
+            bail!(LayoutError::Arguments)
+        }
+
+        // LPC parts boot only if the first 8 vectors sum to zero, the
+        // statement writes the 8th one at offset 0x1c of the table
+        let addr = unpack_arg(&ctx.vars, entry.args[0])?;
+        if addr.checked_add(VECTOR_CHECKSUM_OFFSET) != Some(entry.addr) {
+            bail!("The vector checksum must be placed at 0x{:x}", addr.saturating_add(VECTOR_CHECKSUM_OFFSET));
+        }
+        length = VECTOR_CHECKSUM_OFFSET;
+        reads.push((addr, length));
+
+        if !ctx.dry_run {
+            let mut vectors = Vec::new();
+            outf.seek(SeekFrom::Start(addr))?;
+            stream(outf, length, |chunk| vectors.extend_from_slice(chunk))?;
+            let sum = vectors.chunks(4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                .fold(0u32, |sum, word| sum.wrapping_add(word));
+            let value = sum.wrapping_neg();
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&value.to_le_bytes())?;
+            checksum = Some(value.into());
+        }
+        written = 4;
+    }
+    else if entry.func == "option_bytes" {
+        if entry.args.is_empty() {
+            bail!(LayoutError::Arguments)
+        }
+
+        // STM32 option bytes are stored each followed by its complement
+        let mut block = Vec::with_capacity(entry.args.len() * 2);
+        for arg in &entry.args {
+            let value: u8 = unpack_arg(&ctx.vars, arg)?
+                .try_into()
+                .with_context(
+                    || format!("Option byte {} does not fit in a byte", arg)
+                )?;
+            block.push(value);
+            block.push(!value);
+        }
+
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&block)?;
+        }
+        length = block.len() as u64;
+        written = length;
+    }
+    else if entry.func == "esp32_image" {
+        if entry.args.len() < 3 || entry.args.len() > 4 {
+            bail!(LayoutError::Arguments);
+        }
+        let name = ctx.text(entry.args[0])?;
+        let input = ctx.resolve(&name)?;
+        let load_addr = unpack_arg(&ctx.vars, entry.args[1])?.try_into()?;
+        let entry_point = unpack_arg(&ctx.vars, entry.args[2])?.try_into()?;
+        let chip_id = match entry.args.get(3) {
+            Some(arg) => unpack_arg(&ctx.vars, arg)?.try_into()?,
+            None => 0,
+        };
+
+        if ctx.dry_run {
+            let data_len = fs::metadata(&input)
+                .with_context(
+                    || format!("Could not open file {}", name)
+                )?
+                .len();
+            length = esp32::image_size(data_len);
+        }
+        else {
+            let data = fs::read(&input)
+                .with_context(
+                    || format!("Could not open file {}", name)
+                )?;
+            let image = esp32::image(&data, load_addr, entry_point, chip_id);
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&image)?;
+            length = image.len() as u64;
+        }
+        written = length;
+        source = Some(name);
+    }
+    else if entry.func == "partition_table" {
+        if entry.args.is_empty() {
+            bail!(LayoutError::Arguments);
+        }
+        let partitions = entry.args.iter()
+            .map(|row| partitions::Partition::parse(&ctx.vars, row))
+            .collect::<Result<Vec<partitions::Partition>>>()?;
+        let table = partitions::table(&partitions)?;
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&table)?;
+        }
+        for partition in &partitions {
+            ctx.vars.insert(format!("{}.{}.start", entry.name, partition.label), partition.offset);
+            ctx.vars.insert(format!("{}.{}.size", entry.name, partition.label), partition.size);
+        }
+        length = table.len() as u64;
+        written = length;
+    }
+    else if entry.func == "mbr" || entry.func == "gpt" {
+        let rows = match entry.func {
+            "gpt" => entry.args.get(1..).unwrap_or_default(),
+            _ => &entry.args[..],
+        };
+        if rows.is_empty() {
+            bail!(LayoutError::Arguments);
+        }
+        let partitions = rows.iter()
+            .map(|row| disk::Partition::parse(&ctx.vars, row))
+            .collect::<Result<Vec<disk::Partition>>>()?;
+        let table = match entry.func {
+            "gpt" => disk::gpt(&partitions, partitions::parse_size(&ctx.vars, entry.args[0])?)?,
+            _ => disk::mbr(&partitions)?,
+        };
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&table)?;
+        }
+        for partition in &partitions {
+            let start = checked_end(entry.addr, partition.start)?;
+            ctx.vars.insert(format!("{}.{}.start", entry.name, partition.label), start);
+            ctx.vars.insert(format!("{}.{}.size", entry.name, partition.label), partition.size);
+        }
+        length = table.len() as u64;
+        written = length;
+    }
+    else if entry.func == "gpt_backup" {
+        if entry.args.len() != 1 {
+            bail!(LayoutError::Arguments);
+        }
+
+        // The backup is built from the primary GPT at the start of the disk
+        let addr = unpack_arg(&ctx.vars, entry.args[0])?;
+        checked_end(addr, disk::GPT_SIZE)?;
+        reads.push((addr, disk::GPT_SIZE));
+
+        if !ctx.dry_run {
+            let mut primary = Vec::new();
+            outf.seek(SeekFrom::Start(addr))?;
+            stream(outf, disk::GPT_SIZE, |chunk| primary.extend_from_slice(chunk))?;
+            let expected = checked_end(addr, disk::gpt_backup_offset(&primary)?)?;
+            if entry.addr != expected {
+                bail!("The backup GPT must be placed at 0x{:x}", expected);
+            }
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&disk::gpt_backup(&primary)?)?;
+        }
+        length = disk::GPT_BACKUP_SIZE;
+        written = length;
+    }
+    else if entry.func == "fatfs" {
+        if entry.args.len() != 2 {
+            bail!(LayoutError::Arguments);
+        }
+        length = partitions::parse_size(&ctx.vars, entry.args[0])?;
+        let name = ctx.text(entry.args[1])?;
+        let dir = ctx.resolve(&name)?;
+
+        if !ctx.dry_run {
+            let volume = fat::image(length, &dir)?;
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&volume)?;
+        }
+        written = length;
+        source = Some(name);
+    }
+    else if entry.func == "littlefs" {
+        if entry.args.len() != 3 {
+            bail!(LayoutError::Arguments);
+        }
+        length = partitions::parse_size(&ctx.vars, entry.args[0])?;
+        let block_size = partitions::parse_size(&ctx.vars, entry.args[1])?;
+        let name = ctx.text(entry.args[2])?;
+        let dir = ctx.resolve(&name)?;
+        if ctx.reproducible {
+            bail!("The layout of littlefs volumes depends on the order directories are listed in, reproducible builds can't create them");
+        }
+
+        if !ctx.dry_run {
+            let volume = littlefs::image(length, block_size, &dir)?;
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&volume)?;
+        }
+        written = length;
+        source = Some(name);
+    }
+    else if entry.func == "archive" {
+        if entry.args.len() != 2 {
+            bail!(LayoutError::Arguments);
+        }
+        let name = ctx.text(entry.args[0])?;
+        let dir = ctx.resolve(&name)?;
+        let format = archive::Format::parse(&ctx.text(entry.args[1])?)?;
+        let entries = archive::entries(&dir)?;
+
+        if ctx.dry_run {
+            length = archive::size(&entries, format);
+        }
+        else {
+            let data = archive::pack(&entries, format, ctx.epoch)?;
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&data)?;
+            length = data.len() as u64;
+        }
+        written = length;
+        source = Some(name);
+    }
+    else if entry.func == "dtb" {
+        if entry.args.is_empty() {
+            bail!(LayoutError::Arguments);
+        }
+        let name = ctx.text(entry.args[0])?;
+        let input = ctx.resolve(&name)?;
+        let data = fs::read(&input)
+            .with_context(
+                || format!("Could not open file {}", name)
+            )?;
+
+        let mut fdt = fdt::Fdt::parse(&data)
+            .with_context(
+                || format!("Could not parse {}", name)
+            )?;
+        for arg in &entry.args[1..] {
+            let (path, value) = arg.split_once('=')
+                .ok_or(LayoutError::Syntax("Expected /node/property=VALUE"))?;
+            fdt.set(path, &typed_value(ctx, value)?)?;
+        }
+
+        let blob = fdt.to_bytes();
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&blob)?;
+        }
+        length = blob.len() as u64;
+        written = length;
+        source = Some(name);
+    }
+    else if entry.func == "cbor" {
+        let mut map = Vec::new();
+        for arg in &entry.args {
+            let (key, value) = arg.split_once('=')
+                .ok_or(LayoutError::Syntax("Expected KEY=VALUE"))?;
+            map.push((key.to_string(), typed_value(ctx, value)?));
+        }
+        let encoded = cbor::map(&map)?;
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&encoded)?;
+        }
+        length = encoded.len() as u64;
+        written = length;
+    }
+    else if entry.func == "tlv" {
+        if entry.args.len() < 3 {
+            bail!(LayoutError::Arguments);
+        }
+        let type_field = fields::int_type(entry.args[0])?;
+        let length_field = fields::int_type(entry.args[1])?;
+
+        // Integers of the payload need a type like `u32=VALUE`, strings and
+        // bytes are copied as they are
+        let mut payload = Vec::new();
+        for arg in &entry.args[3..] {
+            let typed = arg.split_once('=')
+                .and_then(|(kind, value)| Some((fields::IntType::parse(kind)?, value)));
+            match typed {
+                Some((kind, value)) => payload.extend(kind.encode(unpack_arg(&ctx.vars, value)?)?),
+                None => match typed_value(ctx, arg)? {
+                    value::Value::Str(text) => payload.extend_from_slice(text.as_bytes()),
+                    value::Value::Bytes(bytes) => payload.extend(bytes),
+                    value::Value::Int(_) => bail!("Integer {} needs a type like u32={}", arg, arg),
+                },
+            }
+        }
+
+        let mut record = type_field.encode(unpack_arg(&ctx.vars, entry.args[2])?)?;
+        record.extend(length_field.encode(payload.len() as u64)?);
+        record.extend(payload);
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&record)?;
+        }
+        length = record.len() as u64;
+        written = length;
+    }
+    else if entry.func == "struct" {
+        if entry.args.is_empty() {
+            bail!(LayoutError::Arguments);
+        }
+        let declared = ctx.structs.get(entry.args[0])
+            .ok_or_else(|| anyhow!("Unknown struct {}", entry.args[0]))?;
+
+        let mut values = HashMap::new();
+        for arg in &entry.args[1..] {
+            let (field, value) = arg.split_once('=')
+                .ok_or(LayoutError::Syntax("Expected FIELD=VALUE"))?;
+            if !declared.iter().any(|(name, _)| name == field) {
+                bail!("Struct {} has no field {}", entry.args[0], field);
+            }
+            if values.insert(field, typed_value(ctx, value)?).is_some() {
+                bail!("Field {} is given twice", field);
+            }
+        }
+
+        // Fields without a value are left zeroed
+        let record = fields::encode_record(declared, |field, _| Ok(values.remove(field)))?;
+
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&record)?;
+        }
+        length = record.len() as u64;
+        written = length;
+    }
+    else if entry.func == "table" {
+        if entry.args.len() != 2 {
+            bail!(LayoutError::Arguments);
+        }
+        let name = ctx.text(entry.args[0])?;
+        let input = ctx.resolve(&name)?;
+        let declared = ctx.structs.get(entry.args[1])
+            .ok_or_else(|| anyhow!("Unknown struct {}", entry.args[1]))?;
+
+        let rows = table::read(&input)?;
+        let data = table::encode(&rows, declared)?;
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&data)?;
+        }
+        ctx.vars.insert(format!("{}.count", entry.name), rows.len() as u64);
+        length = data.len() as u64;
+        written = length;
+        source = Some(name);
+    }
+    else if entry.func == "json" {
+        if entry.args.is_empty() {
+            bail!(LayoutError::Arguments);
+        }
+        let name = ctx.text(entry.args[0])?;
+        let input = ctx.resolve(&name)?;
+        let mut minify = false;
+        let mut terminate = false;
+        for option in &entry.args[1..] {
+            match *option {
+                "minify" => minify = true,
+                "nul" => terminate = true,
+                _ => bail!("Unknown option {}, expected minify or nul", option),
+            }
+        }
+
+        let text = fs::read_to_string(&input)
+            .with_context(
+                || format!("Could not open file {}", name)
+            )?;
+        let yaml = input.extension().is_some_and(|ext| ext == "yaml" || ext == "yml");
+        let document: serde_json::Value = if yaml {
+            serde_yaml::from_str(&text).map_err(anyhow::Error::from)
+        }
+        else {
+            serde_json::from_str(&text).map_err(anyhow::Error::from)
+        }
+        .with_context(
+            || format!("Could not parse {}", name)
+        )?;
+
+        // Minified documents have their keys sorted, JSON files are kept as
+        // they are otherwise
+        let mut data = if minify {
+            serde_json::to_vec(&document)?
+        }
+        else if yaml {
+            serde_json::to_vec_pretty(&document)?
+        }
+        else {
+            text.into_bytes()
+        };
+        ctx.vars.insert(format!("{}.length", entry.name), data.len() as u64);
+        if terminate {
+            data.push(0);
+        }
+
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&data)?;
+        }
+        length = data.len() as u64;
+        written = length;
+        source = Some(name);
+    }
+    else if entry.func == "cstring" {
+        if entry.args.len() != 2 {
+            bail!(LayoutError::Arguments);
+        }
+        // Raw byte strings are written as they are, other text as UTF-8
+        let text = match value::parse_byte_string(entry.args[0])? {
+            Some(bytes) => bytes,
+            None => ctx.text(entry.args[0])?.into_bytes(),
+        };
+        let shown = String::from_utf8_lossy(&text);
+        let width = unpack_arg(&ctx.vars, entry.args[1])?;
+        if text.contains(&0) {
+            bail!("String {:?} contains a NUL byte", shown);
+        }
+        // The terminating NUL must fit as well
+        if text.len() as u64 >= width {
+            bail!("String {:?} of {} bytes does not fit a field of {} bytes with its NUL", shown, text.len(), width);
+        }
+
+        if !ctx.dry_run {
+            let mut data = text;
+            data.resize(width.try_into()?, 0);
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&data)?;
+        }
+        length = width;
+        written = length;
+    }
+    else if entry.func == "wipe" {
+        if entry.args.is_empty() || entry.args.len() > 2 {
+            bail!(LayoutError::Arguments)
+        }
+
+        // Overwrites data of earlier statements, see `run_statements`
+        length = unpack_arg(&ctx.vars, entry.args[0])?;
+        let value = match entry.args.get(1) {
+            Some(arg) => unpack_arg(&ctx.vars, arg)?
+                .try_into()
+                .map_err(|_| anyhow!("Wipe value must fit a byte"))?,
+            None => 0,
+        };
+        checked_end(entry.addr, length)?;
+        reads.push((entry.addr, length));
+
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            let chunk = [value; CHUNK_SIZE];
+            let mut left = length;
+            while left > 0 {
+                let n = left.min(CHUNK_SIZE as u64) as usize;
+                outf.write_all(&chunk[..n])?;
+                left -= n as u64;
+            }
+        }
+        written = length;
+    }
+    else if entry.func == "byteswap" {
+        if entry.args.len() != 2 {
+            bail!(LayoutError::Arguments)
+        }
+
+        // The region at the address of the statement is transformed in place
+        length = unpack_arg(&ctx.vars, entry.args[0])?;
+        let word_size = unpack_arg(&ctx.vars, entry.args[1])?;
+        if word_size != 2 && word_size != 4 && word_size != 8 {
+            bail!("Word size must be 2, 4 or 8, got {}", word_size);
+        }
+        if !length.is_multiple_of(word_size) {
+            bail!("Length 0x{:x} is not a multiple of the word size {}", length, word_size);
+        }
+        checked_end(entry.addr, length)?;
+        reads.push((entry.addr, length));
+
+        if !ctx.dry_run {
+            let mut data = Vec::new();
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            stream(outf, length, |chunk| data.extend_from_slice(chunk))?;
+            for word in data.chunks_mut(word_size as usize) {
+                word.reverse();
+            }
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&data)?;
+        }
+        written = length;
+    }
+    else if entry.func == "bitrev" {
+        if entry.args.len() != 1 {
+            bail!(LayoutError::Arguments)
+        }
+
+        // The region at the address of the statement is transformed in place
+        length = unpack_arg(&ctx.vars, entry.args[0])?;
+        checked_end(entry.addr, length)?;
+        reads.push((entry.addr, length));
+
+        if !ctx.dry_run {
+            let mut data = Vec::new();
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            stream(outf, length, |chunk| {
+                data.extend(chunk.iter().map(|byte| byte.reverse_bits()))
+            })?;
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&data)?;
+        }
+        written = length;
+    }
+    else if entry.func == "mcuboot_tlv" {
+        let (args, excludes) = split_excludes(ctx, &entry.args)?;
+        if args.len() < 2 || args.len() > 3 {
+            bail!(LayoutError::Arguments);
+        }
+
+        let addr = unpack_arg(&ctx.vars, args[0])?;
+        let hashed = unpack_arg(&ctx.vars, args[1])?;
+        let segments = covered(addr, hashed, &excludes)?;
+        reads.extend(&segments);
+
+        let key = match args.get(2) {
+            Some(arg) => {
+                let kpath = ctx.resolve(arg)?;
+                let data = fs::read(&kpath)
+                    .with_context(
+                        || format!("Could not open file {}", kpath.display())
+                    )?;
+                Some(mcuboot::SigningKey::parse(&data)
+                    .with_context(
+                        || format!("Could not sign the image with {}", kpath.display())
+                    )?)
+            }
+            None => None,
+        };
+
+        let mut hash = [0; 32];
+        if !ctx.dry_run {
+            let mut hasher = Sha256::new();
+            for &(start, len) in &segments {
+                outf.seek(SeekFrom::Start(start))?;
+                stream(outf, len, |chunk| hasher.update(chunk))?;
+            }
+            hash.copy_from_slice(&hasher.finalize());
+        }
+
+        let area = mcuboot::tlv_area(&hash, key.as_ref());
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&area)?;
+        }
+        length = area.len() as u64;
+        written = length;
+    }
+    else if let Some(plugin) = ctx.plugins.iter().find(|plugin| plugin.provides(entry.func)).cloned() {
+        let args = text_args(ctx, &entry.args)?;
+        let output = plugin.call(entry.func, &args, entry.addr, ctx.dry_run, outf)?;
+        if !ctx.dry_run {
+            outf.seek(SeekFrom::Start(entry.addr))?;
+            outf.write_all(&output.data)?;
+        }
+        length = output.data.len() as u64;
+        written = length;
+        checksum = output.value;
+        reads = output.reads;
+        source = Some(plugin.source());
+    }
+    else {
+        bail!(LayoutError::UnknownFunction(entry.func.to_string()));
+    }
+
+    let end = checked_end(entry.addr, written)?;
+
+    let mut var_name: String = entry.name.to_string();
+    var_name.push_str(".size");
+    ctx.vars.insert(var_name, length);
+    let mut var_name: String = entry.name.to_string();
+    var_name.push_str(".end");
+    ctx.vars.insert(var_name, end);
+    // Nothing is read by a dry run, like peeks the checksums are zeros
+    let checksum_value = match checksum {
+        None if ctx.dry_run && CHECKSUM_FUNCTIONS.contains(&entry.func) => Some(0),
+        value => value,
+    };
+    if let Some(checksum) = checksum_value {
+        let mut var_name: String = entry.name.to_string();
+        var_name.push_str(".value");
+        ctx.vars.insert(var_name, checksum);
+    }
+    if written > 0 {
+        set_image_end(ctx, end);
+    }
+
+    Ok(Region {
+        name: entry.name.to_string(),
+        addr: entry.addr,
+        size: written,
+        func: entry.func.to_string(),
+        source,
+        checksum,
+        reads,
+        elapsed: started.elapsed(),
+        sealed: entry.sealed,
+    })
+}
+
+/// Returns the end of the range, which must fit the 64-bit address space
+/// so that region ends can be computed without overflowing anywhere else
+fn checked_end(addr: u64, length: u64) -> Result<u64> {
+    addr.checked_add(length)
+        .ok_or_else(|| anyhow!("Range 0x{:x} of {} bytes exceeds the 64-bit address space", addr, length))
+}
+
+/// Ranges of the image as start and length
+type Ranges = Vec<(u64, u64)>;
+
+/// Separates `exclude=ADDR:LEN` arguments of checksum functions from the
+/// others
+fn split_excludes<'a>(ctx: &Context, args: &[&'a str]) -> Result<(Vec<&'a str>, Ranges)> {
+    let mut rest = Vec::new();
+    let mut excludes = Vec::new();
+    for arg in args {
+        match arg.strip_prefix("exclude=") {
+            Some(range) => {
+                let (addr, len) = range.split_once(':')
+                    .ok_or(LayoutError::Syntax("Expected exclude=ADDR:LEN"))?;
+                excludes.push((unpack_arg(&ctx.vars, addr)?, unpack_arg(&ctx.vars, len)?));
+            }
+            None => rest.push(*arg),
+        }
+    }
+    Ok((rest, excludes))
+}
+
+/// Returns the parts of the range left once the excluded ranges are cut out
+fn covered(addr: u64, len: u64, excludes: &[(u64, u64)]) -> Result<Ranges> {
+    let end = checked_end(addr, len)?;
+    let mut cuts = excludes.iter()
+        .map(|&(start, len)| Ok((start, checked_end(start, len)?)))
+        .collect::<Result<Vec<(u64, u64)>>>()?;
+    cuts.sort_unstable();
+
+    let mut segments = Vec::new();
+    let mut pos = addr;
+    for (start, stop) in cuts {
+        if start > pos && pos < end {
+            segments.push((pos, start.min(end) - pos));
+        }
+        pos = pos.max(stop);
+    }
+    if pos < end {
+        segments.push((pos, end - pos));
+    }
+    Ok(segments)
+}
+
+/// Resolves an argument that may be a number, a string or bytes
+fn typed_value(ctx: &Context, arg: &str) -> Result<value::Value> {
+    if let Some(value) = value::Value::parse_literal(arg)? {
+        return match value {
+            value::Value::Str(text) if ctx.expand_env => Ok(value::Value::Str(expand_env(&text)?)),
+            value => Ok(value),
+        };
+    }
+    match arg.strip_prefix('$').and_then(|name| ctx.strings.get(name)) {
+        Some(text) => Ok(value::Value::Str(text.clone())),
+        None => Ok(value::Value::Int(unpack_arg(&ctx.vars, arg)?)),
+    }
+}
+
+fn text_args(ctx: &Context, args: &[&str]) -> Result<Vec<String>> {
+    args.iter().map(|arg| ctx.text(arg)).collect()
+}
+
+/// Separates the `max=SIZE` argument of input functions from the others
+fn split_max<'a>(ctx: &Context, args: &[&'a str]) -> Result<(Vec<&'a str>, Option<u64>)> {
+    let mut rest = Vec::new();
+    let mut max = None;
+    for arg in args {
+        match arg.strip_prefix("max=") {
+            Some(value) => max = Some(unpack_arg(&ctx.vars, value)?),
+            None => rest.push(*arg),
+        }
+    }
+    Ok((rest, max))
+}
+
+/// Separates the `verify=SIG` and `key=PUBKEY` arguments of input functions
+/// from the others
+fn split_signature<'a>(ctx: &Context, args: &[&'a str]) -> Result<(Vec<&'a str>, Option<Signed>)> {
+    let mut rest = Vec::new();
+    let mut signature = None;
+    let mut key = None;
+    for arg in args {
+        if let Some(value) = arg.strip_prefix("verify=") {
+            signature = Some(ctx.resolve(value)?);
+        }
+        else if let Some(value) = arg.strip_prefix("key=") {
+            key = Some(ctx.resolve(value)?);
+        }
+        else {
+            rest.push(*arg);
+        }
+    }
+
+    match (signature, key) {
+        // Checking the layout doesn't read the input the signature is of
+        (Some(signature), Some(key)) if ctx.check_only => {
+            for fpath in [&signature, &key] {
+                fs::metadata(fpath)
+                    .with_context(
+                        || format!("Could not open file {}", fpath.display())
+                    )?;
+            }
+            Ok((rest, None))
+        }
+        (Some(signature), Some(key)) => Ok((rest, Some(Signed { signature, key }))),
+        (None, None) => Ok((rest, None)),
+        _ => bail!(LayoutError::Syntax("Expected both verify=SIG and key=PUBKEY")),
+    }
+}
+
+/// Separates the `header=NAME:VALUE` and `bearer=TOKEN` arguments of
+/// downloads from the others. The value and token may be variables.
+fn split_headers<'a>(ctx: &Context, args: &[&'a str]) -> Result<(Vec<&'a str>, url::Headers)> {
+    let mut rest = Vec::new();
+    let mut headers = Vec::new();
+    for arg in args {
+        if let Some(header) = arg.strip_prefix("header=") {
+            let (name, value) = url::parse_header(header)?;
+            headers.push((name, ctx.text(&value)?));
+        }
+        else if let Some(token) = arg.strip_prefix("bearer=") {
+            headers.push(("Authorization".to_string(), format!("Bearer {}", ctx.text(token)?)));
+        }
+        else {
+            rest.push(*arg);
+        }
+    }
+    Ok((rest, headers))
+}
+
+/// Downloads the input once per build, nothing when only checking the layout
+fn download<F>(ctx: &Context, name: &str, fetch: F) -> Result<Vec<u8>>
+where
+    F: FnOnce() -> Result<Vec<u8>>,
+{
+    if ctx.check_only {
+        return Ok(Vec::new());
+    }
+    fixture::fetch(name, fetch)
+}
+
+fn check_max(name: &str, length: u64, max: Option<u64>) -> Result<()> {
+    match max {
+        Some(max) if length > max => {
+            bail!("{} is {} bytes, more than the limit of {} bytes", name, length, max)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Feeds `length` bytes from the current position to `update` in chunks,
+/// failing if the image ends before that. Short reads are retried.
+fn stream<R: Read + Seek>(reader: &mut R, length: u64, mut update: impl FnMut(&[u8])) -> Result<()> {
+    let start = reader.stream_position()?;
+    let mut buf = [0; CHUNK_SIZE];
+    let mut left = length;
+
+    while left > 0 {
+        let want = left.min(CHUNK_SIZE as u64) as usize;
+        let n = match reader.read(&mut buf[..want]) {
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if n == 0 {
+            bail!(
+                "Expected {} bytes of the image at 0x{:x}, got {}, the range ends past the end of the image",
+                length, start, length - left
+            );
+        }
+        update(&buf[..n]);
+        left -= n as u64;
+    }
+
+    Ok(())
+}
+
+pub fn parse_uint(s: &str) -> Result<u64> {
+    let hex_prefix = "0x";
+    let mut value = s;
+    let mut base = 10;
+
+    if let Some(hex) = s.strip_prefix(hex_prefix) {
+        value = hex;
+        base = 16;
+    }
+
+    Ok(u64::from_str_radix(value, base)?)
+}
+
+/// Checks whether the text is a decimal number without leading zeros or a
+/// `0x` hex number
+fn is_number_literal(text: &str) -> bool {
+    match text.strip_prefix("0x") {
+        Some(hex) => !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => text == "0" || (!text.starts_with('0') && !text.is_empty() && text.chars().all(|c| c.is_ascii_digit())),
+    }
+}
+
+/// Parses a `NAME=VALUE` definition given on the command line. The value is
+/// an integer if it is a number like `0x8004000`, the result of the
+/// expression after `expr:` like `expr:0x8000000 + 0x4000`, and a string
+/// otherwise, such as `2024-01-05` or `08`. `@PATH` reads the value from a
+/// file.
+pub fn parse_define(s: &str) -> Result<(String, Value)> {
+    let (name, value) = s.split_once('=')
+        .ok_or_else(|| anyhow!("Expected NAME=VALUE"))?;
+    let name = name.trim();
+
+    if !valid_name(name) {
+        bail!("Invalid name '{}'", name);
+    }
+
+    let value = match value.trim().strip_prefix('@') {
+        Some(vpath) => fs::read_to_string(vpath)
+            .with_context(
+                || format!("could not read the value of {} from `{}`", name, vpath)
+            )?,
+        None => value.to_string(),
+    };
+
+    let text = value.trim();
+    let value = if let Some(expression) = text.strip_prefix("expr:") {
+        expr::eval_value(expression, &HashMap::new(), None)
+            .with_context(
+                || format!("could not evaluate the value of {}", name)
+            )?
+    }
+    else if is_number_literal(text) {
+        Value::Int(parse_uint(text)?)
+    }
+    else {
+        let unquoted = text.strip_prefix('"').and_then(|text| text.strip_suffix('"'));
+        Value::Str(unquoted.unwrap_or(text).to_string())
+    };
+
+    Ok((name.to_string(), value))
+}
+
+/// Replaces every `${NAME}` in the text with the environment variable,
+/// failing if it is not set
+pub fn expand_env(text: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        let (name, after) = rest[start + 2..].split_once('}')
+            .ok_or(LayoutError::Syntax("Missing } after ${"))?;
+        let value = env::var(name)
+            .with_context(
+                || format!("environment variable {} is not set", name)
+            )?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = after;
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Checks that the name can be used as a variable, names may be namespaced
+/// with dots like `app.version`
+pub fn valid_name(name: &str) -> bool {
+    name.split('.').all(|part| {
+        let mut chars = part.chars();
+        chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// Parses a double-quoted string or evaluates an expression, which reads
+/// files, the image and string variables only with the environment
+pub fn parse_value(text: &str, vars: &HashMap<String, u64>, env: Option<&mut dyn expr::Env>) -> Result<Value> {
+    let text = text.trim();
+    match text.strip_prefix('"').and_then(|text| text.strip_suffix('"')) {
+        Some(string) if !string.contains('"') => Ok(Value::Str(string.to_string())),
+        _ => expr::eval_value(text, vars, env),
+    }
+}
+
+pub fn unpack_arg(vars: &HashMap<String, u64>, arg: &str) -> Result<u64> {
+    if let Some(name) = arg.strip_prefix('$') {
+        if let Some(&value) = vars.get(name) {
+            return Ok(value)
+        }
+        Err(LayoutError::MissingVariable(arg.to_string()).into())
+    }
+    else {
+        parse_uint(arg)
+    }
+}
+
+impl<'a> Entry<'a> {
+    // Borrows from the line, which `FromStr` can't
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(line: &'a str) -> Result<Entry<'a>> {
+        let (sealed, line) = match line.trim_start().strip_prefix("final ") {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let values = line.splitn(3, ':').map(|el| el.trim()).collect::<Vec<&str>>();
+
+        if values.len() != 3 {
+            bail!(LayoutError::Syntax("Error number values"));
+        }
+
+        if values[2].is_empty() {
+            bail!(LayoutError::Syntax("Function name cannot be empty"));
+        }
+
+        let address = parse_uint(values[0])?;
+
+        let (call, guard) = match unquoted_offsets(values[2], " if ").last() {
+            Some(&offset) => (&values[2][..offset], Some(values[2][offset + 4..].trim())),
+            None => (values[2], None),
+        };
+        if guard == Some("") {
+            bail!(LayoutError::Syntax("Expected a condition after if"));
+        }
+
+        let func = split_unquoted(call, ',')
+            .into_iter()
+            .map(|el| el.trim())
+            .collect::<Vec<&str>>();
+
+        Ok(Entry {
+            addr: address,
+            name: values[1],
+            func: func[0],
+            args: func[1..].to_vec(),
+            guard,
+            sealed,
+        })
+    }
+
+    /// Evaluates the guard of the statement, which is executed unless it
+    /// has one that is zero
+    pub fn enabled(&self, ctx: &Context) -> Result<bool> {
+        match self.guard {
+            Some(guard) => Ok(expr::eval_with(guard, &ctx.vars, &mut Memory::new(ctx, None))? != 0),
+            None => Ok(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defines() {
+        let define = |s| parse_define(s).unwrap().1;
+        assert_eq!(define("BASE=0x8004000"), Value::Int(0x800_4000));
+        assert_eq!(define("COUNT=42"), Value::Int(42));
+        assert_eq!(define("ZERO=0"), Value::Int(0));
+        assert_eq!(define("END=expr:0x8000000 + 0x4000"), Value::Int(0x800_4000));
+        assert_eq!(define("DATE=2024-01-05"), Value::Str("2024-01-05".to_string()));
+        assert_eq!(define("REV=08"), Value::Str("08".to_string()));
+        assert_eq!(define("SUM=1+2"), Value::Str("1+2".to_string()));
+        assert_eq!(define("NAME=\"board\""), Value::Str("board".to_string()));
+        assert!(parse_define("BAD=expr:1 +").is_err());
+        assert!(parse_define("1BAD=1").is_err());
+    }
+
+    #[test]
+    fn entry_arguments() {
+        let entry = Entry::from_str("0x10:NAME:u32_hex, 0x1234").unwrap();
+        assert_eq!((entry.addr, entry.name, entry.func), (0x10, "NAME", "u32_hex"));
+        assert_eq!(entry.args, ["0x1234"]);
+        assert!(entry.guard.is_none() && !entry.sealed);
+    }
+
+    #[test]
+    fn entry_commas_in_literals() {
+        let entry = Entry::from_str(r#"0x0:S:cstring,"a, b",8"#).unwrap();
+        assert_eq!(entry.args, [r#""a, b""#, "8"]);
+
+        let entry = Entry::from_str(r#"0x0:S:cstring,b"a,b\x00",8"#).unwrap();
+        assert_eq!(entry.args, [r#"b"a,b\x00""#, "8"]);
+
+        // An escaped quote doesn't end the literal
+        let entry = Entry::from_str(r#"0x0:S:cstring,b"\",",8"#).unwrap();
+        assert_eq!(entry.args, [r#"b"\",""#, "8"]);
+    }
+
+    #[test]
+    fn entry_guards() {
+        let entry = Entry::from_str("0x0:A:file,a.bin if $DEBUG").unwrap();
+        assert_eq!((entry.args.as_slice(), entry.guard), (["a.bin"].as_slice(), Some("$DEBUG")));
+
+        let entry = Entry::from_str(r#"0x0:S:cstring,"go if ready",16"#).unwrap();
+        assert_eq!((entry.args.as_slice(), entry.guard), ([r#""go if ready""#, "16"].as_slice(), None));
+
+        let entry = Entry::from_str(r#"0x0:S:cstring,b"go if ready",16 if $DEBUG"#).unwrap();
+        assert_eq!(entry.args, [r#"b"go if ready""#, "16"]);
+        assert_eq!(entry.guard, Some("$DEBUG"));
+    }
+
+    #[test]
+    fn statements_split() {
+        let parts = split_statements("0x0:A:file,a.bin; 0x10:B:file,b.bin;").unwrap();
+        assert_eq!(parts, [(0, "0x0:A:file,a.bin"), (18, "0x10:B:file,b.bin")]);
+
+        let parts = split_statements(r#"0x0:S:cstring,"a;b",8 ;; 0x8:T:cstring,b"\";",8"#).unwrap();
+        assert_eq!(parts, [(0, r#"0x0:S:cstring,"a;b",8"#), (25, r#"0x8:T:cstring,b"\";",8"#)]);
+
+        // Brackets hold statements spanning lines together
+        let parts = split_statements("0x0:M:cbor,{a: 1; b: 2}; 0x40:C:crc16,0x0,4").unwrap();
+        assert_eq!(parts, [(0, "0x0:M:cbor,{a: 1; b: 2}"), (25, "0x40:C:crc16,0x0,4")]);
+        assert_eq!(split_statements("0x0:M:cbor,{a: 1;"), None);
+        assert_eq!(split_statements("  ;  "), Some(Vec::new()));
+    }
+
+    #[test]
+    fn unquoted_splits() {
+        assert_eq!(split_unquoted("a,b,,c", ','), ["a", "b", "", "c"]);
+        assert_eq!(split_unquoted(r#""a,b",c"#, ','), [r#""a,b""#, "c"]);
+        assert_eq!(split_unquoted(r#""a\",b",c"#, ','), [r#""a\",b""#, "c"]);
+        assert_eq!(split_unquoted("", ','), [""]);
+    }
+
+    #[test]
+    fn entry_guard_edge_cases() {
+        // Only a whole ` if ` word starts the guard
+        let entry = Entry::from_str("0x0:A:file,diff.bin").unwrap();
+        assert_eq!((entry.args.as_slice(), entry.guard), (["diff.bin"].as_slice(), None));
+
+        let entry = Entry::from_str(r#"0x0:S:cstring,"a\" if b",8 if defined(X)"#).unwrap();
+        assert_eq!(entry.args, [r#""a\" if b""#, "8"]);
+        assert_eq!(entry.guard, Some("defined(X)"));
+    }
+
+    /// Executes the statement on a copy of the image
+    fn execute_on(image: &[u8], line: &str) -> Result<(Vec<u8>, Region)> {
+        let mut ctx = Context::new(&[]);
+        let mut outf = Cursor::new(image.to_vec());
+        let region = process_entry(&mut ctx, &mut outf, &Entry::from_str(line)?)?;
+        Ok((outf.into_inner(), region))
+    }
+
+    #[test]
+    fn vector_checksums() {
+        let vectors = [0x1000_1000u32, 0xd5, 0xdd, 0xdf, 0xe1, 0xe3, 0xe5, 0];
+        let table = vectors.iter().flat_map(|vector| vector.to_le_bytes()).collect::<Vec<u8>>();
+
+        let (image, region) = execute_on(&table, "0x1c:VC:vector_checksum,0x0").unwrap();
+        assert_eq!(&image[0x1c..], &0xefff_eac6u32.to_le_bytes());
+        assert_eq!(region.checksum, Some(0xefff_eac6));
+        assert_eq!(region.reads, [(0, 0x1c)]);
+        let sum = image.chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .fold(0u32, |sum, word| sum.wrapping_add(word));
+        assert_eq!(sum, 0);
+
+        assert!(execute_on(&table, "0x18:VC:vector_checksum,0x0").is_err());
+    }
+
+    #[test]
+    fn option_bytes() {
+        // Read protection level 0 and the default user options of an STM32F1
+        let (image, region) = execute_on(&[], "0x0:OB:option_bytes,0xa5,0xff").unwrap();
+        assert_eq!(image, [0xa5, 0x5a, 0xff, 0x00]);
+        assert_eq!((region.addr, region.size), (0, 4));
+
+        assert!(execute_on(&[], "0x0:OB:option_bytes,0x100").is_err());
+        assert!(execute_on(&[], "0x0:OB:option_bytes").is_err());
+    }
+
+    #[test]
+    fn byteswaps() {
+        let data = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0xaa];
+
+        let (image, region) = execute_on(&data, "0x0:SW:byteswap,8,2").unwrap();
+        assert_eq!(image, [0x02, 0x01, 0x04, 0x03, 0x06, 0x05, 0x08, 0x07, 0xaa]);
+        assert_eq!((region.size, region.reads.as_slice()), (8, [(0, 8)].as_slice()));
+        let (image, _) = execute_on(&data, "0x0:SW:byteswap,8,4").unwrap();
+        assert_eq!(image, [0x04, 0x03, 0x02, 0x01, 0x08, 0x07, 0x06, 0x05, 0xaa]);
+        let (image, _) = execute_on(&data, "0x0:SW:byteswap,8,8").unwrap();
+        assert_eq!(image, [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0xaa]);
+
+        assert!(execute_on(&data, "0x0:SW:byteswap,6,4").is_err());
+        assert!(execute_on(&data, "0x0:SW:byteswap,6,3").is_err());
+    }
+
+    #[test]
+    fn bit_reversals() {
+        let (image, region) = execute_on(&[0x01, 0x80, 0xf0, 0x35, 0xaa], "0x1:R:bitrev,3").unwrap();
+        assert_eq!(image, [0x01, 0x01, 0x0f, 0xac, 0xaa]);
+        assert_eq!((region.addr, region.size, region.reads.as_slice()), (1, 3, [(1, 3)].as_slice()));
+
+        // Past the end of the image
+        assert!(execute_on(&[0x01], "0x0:R:bitrev,2").is_err());
+    }
+}
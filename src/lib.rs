@@ -0,0 +1,49 @@
+//! The engine of the `bincomb` command. It is also built as a C library
+//! for build systems, see `capi`.
+
+pub mod archive;
+pub mod bootcheck;
+pub mod capi;
+pub mod cbor;
+pub mod defines;
+pub mod delta;
+pub mod diagnostics;
+pub mod digest;
+pub mod directive;
+pub mod disk;
+pub mod emit;
+pub mod esp32;
+pub mod expr;
+pub mod fat;
+pub mod fdt;
+pub mod fields;
+pub mod fixture;
+pub mod git;
+pub mod hook;
+pub mod http;
+pub mod layout;
+pub mod littlefs;
+pub mod lsp;
+pub mod map;
+pub mod mapped;
+pub mod mcuboot;
+pub mod oci;
+pub mod output;
+pub mod parallel;
+pub mod partitions;
+pub mod plugin;
+pub mod progress;
+pub mod provenance;
+pub mod release;
+pub mod remote;
+pub mod repl;
+pub mod s3;
+pub mod signature;
+pub mod state;
+pub mod stats;
+pub mod summary;
+pub mod table;
+pub mod target;
+pub mod templates;
+pub mod url;
+pub mod value;
@@ -0,0 +1,72 @@
+//! littlefs images of a directory, built with the `mklittlefs` command.
+//!
+//! The command is looked up in `PATH` unless `MKLITTLEFS` names it.
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use std::env;
+use std::fs;
+use std::io;
+use std::path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static IMAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Where `mklittlefs` is released
+const MKLITTLEFS_URL: &str = "https://github.com/earlephilhower/mklittlefs";
+
+/// Size of the pages `mklittlefs` programs
+const PAGE_SIZE: u64 = 256;
+
+/// Builds a littlefs image of `size` bytes holding the directory tree
+pub fn image(size: u64, block_size: u64, dir: &path::Path) -> Result<Vec<u8>> {
+    if block_size == 0 || !size.is_multiple_of(block_size) {
+        bail!("The size 0x{:x} is not a multiple of the block size 0x{:x}", size, block_size);
+    }
+
+    let tool = env::var_os("MKLITTLEFS").unwrap_or_else(|| "mklittlefs".into());
+    let ipath = env::temp_dir().join(format!(
+        "bincomb-littlefs-{}-{}.bin",
+        std::process::id(),
+        IMAGES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = Command::new(&tool)
+        .arg("-c").arg(dir)
+        .arg("-b").arg(block_size.to_string())
+        .arg("-p").arg(PAGE_SIZE.to_string())
+        .arg("-s").arg(size.to_string())
+        .arg(&ipath)
+        .output()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => anyhow!(
+                "`{}` was not found, install mklittlefs from {} or set MKLITTLEFS to its path",
+                tool.to_string_lossy(), MKLITTLEFS_URL
+            ),
+            _ => anyhow!(err).context(format!("could not run `{}`", tool.to_string_lossy())),
+        })
+        .and_then(|output| {
+            if !output.status.success() {
+                bail!(
+                    "`{}` failed: {}",
+                    tool.to_string_lossy(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            fs::read(&ipath)
+                .with_context(
+                    || format!("could not open file `{}`", ipath.display())
+                )
+        })
+        .with_context(
+            || format!("Could not build a littlefs image of `{}`", dir.display())
+        );
+    let _ = fs::remove_file(&ipath);
+
+    let data = result?;
+    if data.len() as u64 != size {
+        bail!("The littlefs image is {} bytes instead of {}", data.len(), size);
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,347 @@
+//! A minimal language server for layout files speaking LSP over stdio.
+//! Supports diagnostics, hover with resolved values and go-to-definition
+//! of variables.
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Cursor, Write};
+use std::path;
+
+use crate::diagnostics;
+use crate::directive;
+use crate::layout::{self, Context, Entry, Location};
+
+/// Statement defining a region, used for hover and go-to-definition
+struct Definition {
+    line: usize,
+    start: usize,
+    end: usize,
+    addr: u64,
+    size: u64,
+}
+
+/// An open document along with the analysis of its version
+struct Document {
+    version: i64,
+    text: String,
+    analysis: Analysis,
+}
+
+/// Results of evaluating a document
+struct Analysis {
+    diagnostics: Vec<Value>,
+    vars: HashMap<String, u64>,
+    definitions: HashMap<String, Definition>,
+}
+
+pub fn serve(defines: &[(String, layout::Value)]) -> Result<()> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let mut documents: HashMap<String, Document> = HashMap::new();
+
+    while let Some(message) = receive(&mut input)? {
+        let method = message["method"].as_str().unwrap_or("");
+        let id = message.get("id").cloned();
+        let params = &message["params"];
+
+        match method {
+            "initialize" => {
+                respond(&mut output, id, json!({
+                    "capabilities": {
+                        "textDocumentSync": 1,
+                        "hoverProvider": true,
+                        "definitionProvider": true,
+                    },
+                    "serverInfo": { "name": "bincomb" },
+                }))?;
+            }
+            "shutdown" => respond(&mut output, id, Value::Null)?,
+            "exit" => break,
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+                let version = params["textDocument"]["version"].as_i64().unwrap_or(0);
+                // Full document sync, the last change holds the whole text
+                let text = match method {
+                    "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+                    _ => params["contentChanges"]
+                        .as_array()
+                        .and_then(|changes| changes.last())
+                        .and_then(|change| change["text"].as_str()),
+                };
+                let text = match text {
+                    Some(text) => text.to_string(),
+                    None => continue,
+                };
+                // Evaluated once per version, hovers reuse the analysis
+                let current = documents.get(&uri)
+                    .is_some_and(|document| document.version == version && document.text == text);
+                if !current {
+                    let analysis = analyze(&uri, &text, defines);
+                    documents.insert(uri.clone(), Document { version, text, analysis });
+                }
+                publish(&mut output, &uri, &documents[&uri].analysis)?;
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                documents.remove(uri);
+                notify(&mut output, "textDocument/publishDiagnostics", json!({
+                    "uri": uri,
+                    "diagnostics": [],
+                }))?;
+            }
+            "textDocument/hover" | "textDocument/definition" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+                let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+                let result = match documents.get(uri) {
+                    Some(document) => {
+                        let word = document.text.lines().nth(line)
+                            .and_then(|raw| word_at(raw, character));
+                        match (method, word) {
+                            ("textDocument/hover", Some(word)) => hover(&document.analysis, &word),
+                            (_, Some(word)) => definition(&document.analysis, uri, &word),
+                            _ => Value::Null,
+                        }
+                    }
+                    None => Value::Null,
+                };
+                respond(&mut output, id, result)?;
+            }
+            _ => {
+                // Requests must be answered, notifications are ignored
+                if let Some(id) = id {
+                    write_message(&mut output, &json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": -32601, "message": "Method not found" },
+                    }))?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn receive<R: BufRead>(input: &mut R) -> Result<Option<Value>> {
+    let mut length: Option<usize> = None;
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse()?);
+        }
+    }
+
+    let length = length.ok_or_else(|| anyhow!("Missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    Ok(Some(serde_json::from_slice(&body).context("Invalid JSON-RPC message")?))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Value) -> Result<()> {
+    let body = serde_json::to_string(message)?;
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn respond<W: Write>(output: &mut W, id: Option<Value>, result: Value) -> Result<()> {
+    let id = match id {
+        Some(id) => id,
+        None => bail!("Request without id"),
+    };
+    write_message(output, &json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+}
+
+fn notify<W: Write>(output: &mut W, method: &str, params: Value) -> Result<()> {
+    write_message(output, &json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+}
+
+fn publish<W: Write>(output: &mut W, uri: &str, analysis: &Analysis) -> Result<()> {
+    notify(output, "textDocument/publishDiagnostics", json!({
+        "uri": uri,
+        "diagnostics": analysis.diagnostics,
+    }))
+}
+
+/// Evaluates the document without reading or writing any data
+fn analyze(uri: &str, text: &str, defines: &[(String, layout::Value)]) -> Analysis {
+    let file = path::PathBuf::from(uri.strip_prefix("file://").unwrap_or(uri));
+    let raw_lines = text.lines().collect::<Vec<&str>>();
+    let mut ctx = Context::new(defines);
+    ctx.base = layout::base_dir(&file);
+    ctx.dry_run = true;
+    ctx.check_only = true;
+    let statements = layout::parse_lines(&file, text.as_bytes());
+    let mut analysis = Analysis {
+        diagnostics: Vec::new(),
+        vars: HashMap::new(),
+        definitions: HashMap::new(),
+    };
+
+    match layout::run_located(&mut ctx, &mut Cursor::new(Vec::new()), &statements) {
+        Ok(regions) => {
+            for region in regions {
+                let location = statements.iter()
+                    .filter(|(_, line)| !directive::is_directive(line))
+                    .find(|(_, line)| Entry::from_str(line).is_ok_and(|entry| entry.name == region.name))
+                    .map(|(location, _)| location);
+                let location = match location {
+                    Some(location) => location,
+                    None => continue,
+                };
+                let raw = raw_lines.get(location.line - 1).copied().unwrap_or("");
+                let start = raw.find(':').map_or(0, |colon| {
+                    colon + 1 + (raw[colon + 1..].len() - raw[colon + 1..].trim_start().len())
+                });
+                analysis.definitions.insert(region.name.clone(), Definition {
+                    line: location.line - 1,
+                    start,
+                    end: start + region.name.len(),
+                    addr: region.addr,
+                    size: region.size,
+                });
+            }
+        }
+        Err(err) => {
+            // Errors without a line of the document, such as of variables
+            // which don't settle, are shown on its first line
+            let (line, column) = err.downcast_ref::<Location>()
+                .filter(|location| location.file == file)
+                .map_or((0, 0), |location| (location.line - 1, location.column.saturating_sub(1)));
+            let raw = raw_lines.get(line).copied().unwrap_or("");
+            analysis.diagnostics.push(json!({
+                "range": {
+                    "start": { "line": line, "character": column },
+                    "end": { "line": line, "character": raw.len() },
+                },
+                "severity": 1,
+                "code": diagnostics::code(&err),
+                "source": "bincomb",
+                "message": format!("{}", err.root_cause()),
+            }));
+        }
+    }
+
+    for warning in &ctx.warnings {
+        let raw = raw_lines.get(warning.line - 1).copied().unwrap_or("");
+        analysis.diagnostics.push(json!({
+            "range": {
+                "start": { "line": warning.line - 1, "character": 0 },
+                "end": { "line": warning.line - 1, "character": raw.len() },
+            },
+            "severity": 2,
+            "code": warning.code,
+            "source": "bincomb",
+            "message": warning.message,
+        }));
+    }
+
+    analysis.vars = ctx.vars;
+    analysis
+}
+
+/// Returns the variable reference or name under the cursor
+fn word_at(raw: &str, character: usize) -> Option<String> {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$';
+    let chars = raw.chars().collect::<Vec<char>>();
+    if character > chars.len() {
+        return None;
+    }
+
+    let mut start = character;
+    while start > 0 && is_word(chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = character;
+    while end < chars.len() && is_word(chars[end]) {
+        end += 1;
+    }
+
+    if start == end {
+        return None;
+    }
+    Some(chars[start..end].iter().collect())
+}
+
+/// Strips the `$` and the `.start`/`.size` suffix of a variable reference
+fn region_name(word: &str) -> &str {
+    let name = word.trim_start_matches('$');
+    name.strip_suffix(".start")
+        .or_else(|| name.strip_suffix(".size"))
+        .unwrap_or(name)
+}
+
+fn hover(analysis: &Analysis, word: &str) -> Value {
+    let text = if let Some(var) = word.strip_prefix('$') {
+        match analysis.vars.get(var) {
+            Some(value) => format!("`{}` = {} (0x{:x})", word, value, value),
+            None => format!("`{}` is undefined", word),
+        }
+    }
+    else {
+        match analysis.definitions.get(word) {
+            Some(def) => format!(
+                "`{}`: 0x{:08x}..0x{:08x} ({} bytes)",
+                word, def.addr, def.addr + def.size, def.size
+            ),
+            None => return Value::Null,
+        }
+    };
+
+    json!({ "contents": { "kind": "markdown", "value": text } })
+}
+
+fn definition(analysis: &Analysis, uri: &str, word: &str) -> Value {
+    match analysis.definitions.get(region_name(word)) {
+        Some(def) => json!({
+            "uri": uri,
+            "range": {
+                "start": { "line": def.line, "character": def.start },
+                "end": { "line": def.line, "character": def.end },
+            },
+        }),
+        None => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlaps_are_warnings() {
+        let text = "0x0:A:option_bytes,1,2,3,4\n0x2:B:option_bytes,5,6\n";
+        let analysis = analyze("file:///tmp/layout.txt", text, &[]);
+
+        assert_eq!(analysis.diagnostics.len(), 1, "{:?}", analysis.diagnostics);
+        let diagnostic = &analysis.diagnostics[0];
+        assert_eq!(diagnostic["severity"], 2);
+        assert_eq!(diagnostic["code"], "overlap");
+        assert_eq!(diagnostic["range"]["start"]["line"], 1);
+        assert_eq!(analysis.definitions["B"].addr, 2);
+    }
+
+    #[test]
+    fn errors_are_located() {
+        let text = "0x0:A:option_bytes,1\n0x2:B:nonsense,5\n";
+        let analysis = analyze("file:///tmp/layout.txt", text, &[]);
+
+        assert_eq!(analysis.diagnostics.len(), 1, "{:?}", analysis.diagnostics);
+        let diagnostic = &analysis.diagnostics[0];
+        assert_eq!(diagnostic["severity"], 1);
+        assert_eq!(diagnostic["range"]["start"]["line"], 1);
+    }
+}
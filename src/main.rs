@@ -1,171 +1,1207 @@
-use anyhow::{anyhow, bail, Context, Result};
-use clap::Parser;
-use std::fs::{File, OpenOptions};
-use std::io::prelude::*;
-use std::io::{copy, SeekFrom, Seek, Read, Write, BufReader};
+use anyhow::{bail, Context as _, Result};
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Cursor, IsTerminal, Write};
 use std::path;
+use std::process;
+use std::sync::Arc;
 use std::convert::TryInto;
-use std::collections::HashMap;
-use crc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Debug)]
-struct Entry<'a> {
-    addr: u64,
-    name: &'a str,
-    func: &'a str,
-    args: Vec<&'a str>,
-}
+use bincomb::{archive, bootcheck, defines, delta, diagnostics, digest, emit, fixture, hook, http, layout, lsp, map, mapped, output, parallel, plugin, provenance, repl, state, stats, summary, target, templates, url};
+
+use layout::{parse_define, parse_uint, CheckError, Context, Region, Value, Warning};
+
+/// Commands run by some functions, listed in the help
+const EXTERNAL_TOOLS: &str = "\
+External tools:
+  git         fetches the files of `git` statements
+  mklittlefs  builds the volumes of `littlefs` statements, MKLITTLEFS may
+              name another command";
 
 /// A tool to combine binary files
 #[derive(Parser)]
+#[command(
+    args_conflicts_with_subcommands = true, subcommand_negates_reqs = true,
+    after_help = format!("{}\n\n{}", diagnostics::EXIT_CODES, EXTERNAL_TOOLS)
+)]
 struct Cli {
-    /// The path to the file to read layout
-    layout: path::PathBuf,
-    /// The path to the file to output
-    output: path::PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    build: BuildArgs,
+    #[command(flatten)]
+    global: GlobalArgs,
+}
+
+/// Options shared by the build and all subcommands
+#[derive(Args)]
+struct GlobalArgs {
+    /// Define a variable available to the layout as `$NAME`, an integer for
+    /// numbers like 0x8004000 or `NAME=expr:EXPRESSION`, a string otherwise.
+    /// `NAME=@PATH` reads the value from a file
+    #[arg(short = 'D', value_name = "NAME=VALUE", value_parser = parse_define, global = true)]
+    defines: Vec<(String, Value)>,
+    /// Read variables from a flat TOML, JSON or YAML table, `-D` overrides them
+    #[arg(long, value_name = "PATH", global = true)]
+    defines_file: Vec<path::PathBuf>,
+    /// Read `KEY=value` variables from this file instead of the `.env` next
+    /// to the layout
+    #[arg(long, value_name = "PATH", global = true)]
+    env_file: Option<path::PathBuf>,
+    /// The format of reported errors and warnings
+    #[arg(long, value_enum, default_value = "human", global = true)]
+    diagnostics_format: diagnostics::Format,
+    /// Resolve relative input paths against the working directory instead
+    /// of the directory of the layout
+    #[arg(long, global = true)]
+    relative_to_cwd: bool,
+    /// Load layout functions from a shared library, see the `plugins` feature
+    #[arg(long, value_name = "PATH", global = true)]
+    plugin: Vec<path::PathBuf>,
+    /// Replace `${NAME}` in define values, text arguments and strings of the
+    /// layout with the environment variable
+    #[arg(long, global = true)]
+    expand_env: bool,
+    /// Retry downloads failing with a connection error or a 5xx status this
+    /// many times. `url` downloads whose connection breaks are resumed
+    #[arg(long, value_name = "COUNT", default_value = "0", global = true)]
+    retries: u32,
+    /// Milliseconds to wait before the first retry of a download, doubled
+    /// for each next one
+    #[arg(long, value_name = "MS", default_value = "1000", global = true)]
+    retry_delay: u64,
+    /// Give up on a download attempt after this many seconds
+    #[arg(long, value_name = "SECS", global = true)]
+    http_timeout: Option<u64>,
+    /// Follow at most this many redirects of a download
+    #[arg(long, value_name = "COUNT", default_value = "5", global = true)]
+    max_redirects: u32,
+    /// The User-Agent header of downloads
+    #[arg(long, value_name = "TEXT", default_value = http::USER_AGENT, global = true)]
+    user_agent: String,
+    /// Also trust the CA certificates of this PEM bundle for downloads
+    #[arg(long, value_name = "PATH", global = true)]
+    ca_cert: Option<path::PathBuf>,
+    /// Present the PEM certificate chain to servers asking for a client
+    /// certificate
+    #[arg(long, value_name = "PATH", requires = "client_key", global = true)]
+    client_cert: Option<path::PathBuf>,
+    /// The PEM private key of `--client-cert`
+    #[arg(long, value_name = "PATH", requires = "client_cert", global = true)]
+    client_key: Option<path::PathBuf>,
+    /// Send this header with downloads of `url` inputs, such as
+    /// `Authorization: Bearer TOKEN`
+    #[arg(long, value_name = "NAME: VALUE", value_parser = url::parse_header, global = true)]
+    http_header: Vec<(String, String)>,
+    /// Keep the downloads of `url` inputs in this directory, by default
+    /// `bincomb/downloads` in the cache directory of the user
+    #[arg(long, value_name = "PATH", global = true)]
+    cache_dir: Option<path::PathBuf>,
+    /// Download `url` inputs every time instead of caching them
+    #[arg(long, conflicts_with = "refresh", global = true)]
+    no_cache: bool,
+    /// Download `url` inputs again instead of revalidating the cached ones
+    #[arg(long, global = true)]
+    refresh: bool,
+    /// The plugins once loaded
+    #[arg(skip)]
+    plugins: Vec<Arc<plugin::Plugin>>,
 }
 
-fn main() -> Result<()> {
+impl GlobalArgs {
+    /// Returns the directory relative input paths of the layout are resolved against
+    fn base(&self, rpath: &path::Path) -> path::PathBuf {
+        if self.relative_to_cwd {
+            path::PathBuf::new()
+        }
+        else {
+            layout::base_dir(rpath)
+        }
+    }
+
+    /// Creates the context to execute the layout with
+    fn context(&self, rpath: &path::Path) -> Context {
+        let mut ctx = Context::new(&self.defines);
+        ctx.base = self.base(rpath);
+        ctx.plugins = self.plugins.clone();
+        ctx.expand_env = self.expand_env;
+        ctx
+    }
+}
+
+#[derive(Args)]
+struct BuildArgs {
+    /// The path to the file to read layout, or an http(s) URL optionally
+    /// pinned with `#sha256=<hex>`, followed by overlays which add
+    /// statements to it and override its `default` values
+    #[arg(required = true, num_args = 1..)]
+    layout: Vec<path::PathBuf>,
+    /// The path to the file to output, `-` for the standard output
+    #[arg(required = true)]
+    output: Option<path::PathBuf>,
+    /// Modify the existing output file in place instead of recreating it
+    #[arg(long)]
+    patch: bool,
+    /// Keep a copy of the existing output with the suffix appended to its name
+    #[arg(
+        long, value_name = "SUFFIX", num_args = 0..=1, require_equals = true,
+        default_missing_value = ".bak"
+    )]
+    backup: Option<String>,
+    /// Overwrite the output if it already exists
+    #[arg(short, long)]
+    force: bool,
+    /// Skip the build if the layout, defines, inputs and files written
+    /// besides the output didn't change since the last incremental build.
+    /// Builds with --stats, --list-vars, --summary-json or --post-cmd always
+    /// run, as those report on the build
+    #[arg(long, conflicts_with_all = ["patch", "watch"])]
+    incremental: bool,
+    /// Leave blocks of zeros as holes in the output file instead of writing them
+    #[arg(long)]
+    sparse: bool,
+    /// Execute up to N independent statements at the same time
+    #[arg(short, long, value_name = "N", default_value = "1")]
+    jobs: usize,
+    /// Write the resulting image map to a file (JSON for `.json`, text otherwise)
+    #[arg(long, value_name = "PATH")]
+    map: Option<path::PathBuf>,
+    /// Write a C header with region offsets and sizes
+    #[arg(long, value_name = "PATH")]
+    emit_header: Option<path::PathBuf>,
+    /// Write a Rust module with region offsets and sizes
+    #[arg(long, value_name = "PATH")]
+    emit_rust: Option<path::PathBuf>,
+    /// Write a linker script fragment with memory regions and symbols
+    #[arg(long, value_name = "PATH")]
+    emit_ld: Option<path::PathBuf>,
+    /// Write a Graphviz graph of the statements, the variables they use and
+    /// the files they read
+    #[arg(long, value_name = "PATH")]
+    emit_graph: Option<path::PathBuf>,
+    /// The address the image is placed at, added to linker script origins
+    #[arg(long, value_name = "ADDR", value_parser = parse_uint, default_value = "0")]
+    ld_base: u64,
+    /// Rebuild the output whenever the layout or its input files change
+    #[arg(long)]
+    watch: bool,
+    /// Evaluate the layout and print what would be written without writing anything
+    #[arg(long, conflicts_with = "watch")]
+    dry_run: bool,
+    /// Trace every statement, repeat to also show arguments and variables
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Don't print anything but errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// When to color the table of regions printed after the build
+    #[arg(long, value_enum, default_value = "auto")]
+    color: summary::ColorChoice,
+    /// Print the time and bytes processed by every statement after the build
+    #[arg(long)]
+    stats: bool,
+    /// Write all variables after the build to a JSON file
+    #[arg(long, value_name = "PATH")]
+    vars_out: Option<path::PathBuf>,
+    /// Print all variables with their resolved values to stdout after the
+    /// build, or after evaluating the layout with `--dry-run`
+    #[arg(long)]
+    list_vars: bool,
+    /// Print a JSON summary of the build to stdout
+    #[arg(long)]
+    summary_json: bool,
+    /// Don't show progress of large copies and downloads, it is only shown
+    /// when stderr is a terminal anyway
+    #[arg(long)]
+    no_progress: bool,
+    /// Fail on functions whose result may change between builds, such as
+    /// downloads of moving tags, and date archives with `SOURCE_DATE_EPOCH`
+    #[arg(long)]
+    reproducible: bool,
+    /// Write the digest of the output to `OUTPUT.sha256` and `OUTPUT.md5`,
+    /// or only to the files of the given algorithms
+    #[arg(
+        long, value_enum, value_name = "ALGORITHM", value_delimiter = ',', num_args = 0..,
+        require_equals = true, default_missing_values = ["sha256", "md5"]
+    )]
+    emit_checksums: Vec<digest::Algorithm>,
+    /// Build the layout again in memory after writing and compare every
+    /// region with the output read back from the disk
+    #[arg(long, conflicts_with = "patch")]
+    verify: bool,
+    /// Warn about regions which don't start and end at a multiple of SIZE,
+    /// such as the erase sector of the flash
+    #[arg(long, value_name = "SIZE", value_parser = parse_uint, group = "sectors")]
+    sector_size: Option<u64>,
+    /// Check that the regions fit the flash geometry described by the TOML
+    /// file, are aligned to its sectors and keep out of its reserved areas
+    #[arg(long, value_name = "PATH", group = "sectors")]
+    target: Option<path::PathBuf>,
+    /// Fail instead of warning about regions not aligned to sectors
+    #[arg(long, requires = "sectors")]
+    deny_unaligned: bool,
+    /// Fail on regions written below the address, such as into the
+    /// bootloader at the start of the flash
+    #[arg(long, value_name = "ADDR", value_parser = parse_uint, default_value = "0")]
+    min_offset: u64,
+    /// Rewrite only the named statements of the existing output and those
+    /// whose checksums they use, the others are evaluated without reading
+    /// or writing any data
+    #[arg(long, value_name = "NAME", value_delimiter = ',', requires = "patch")]
+    only: Option<Vec<String>>,
+    /// Write the layouts, defines and hashes of the inputs of the build to a
+    /// JSON manifest
+    #[arg(long, value_name = "PATH")]
+    emit_provenance: Option<path::PathBuf>,
+    /// Fail before writing the output unless the layouts, defines and inputs
+    /// match the manifest written by `--emit-provenance`
+    #[arg(long, value_name = "PATH")]
+    locked: Option<path::PathBuf>,
+    /// Cut trailing bytes of the value off the output, by default the erase
+    /// value of `--target` or 0xff
+    #[arg(
+        long, value_name = "BYTE", value_parser = parse_byte, num_args = 0..=1, require_equals = true,
+        conflicts_with_all = ["verify", "emit_provenance"]
+    )]
+    trim: Option<Option<u8>>,
+    /// Set the permissions of the output to the octal MODE, such as 0644
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    mode: Option<u32>,
+    /// Set the modification time of the output to TIME seconds since the
+    /// Unix epoch, or to the value of the `SOURCE_DATE_EPOCH` variable
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    touch: Option<u64>,
+    /// Copy the input files and downloads of the build to the directory,
+    /// stored by their SHA-256, for `--replay`
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["replay", "watch", "incremental", "dry_run"])]
+    record: Option<path::PathBuf>,
+    /// Build from the inputs recorded in the directory by `--record` alone,
+    /// without reading the original files or the network
+    #[arg(long, value_name = "DIR")]
+    replay: Option<path::PathBuf>,
+    /// Run the shell command before the build, may be repeated
+    #[arg(long, value_name = "COMMAND")]
+    pre_cmd: Vec<String>,
+    /// Run the shell command after the build with the variables of the
+    /// layout in the environment as `BINCOMB_VAR_<NAME>`, may be repeated
+    #[arg(long, value_name = "COMMAND")]
+    post_cmd: Vec<String>,
+}
+
+/// Machine-readable result of a build printed by `--summary-json`
+#[derive(Serialize)]
+struct Summary<'a> {
+    output: &'a path::Path,
+    size: u64,
+    regions: &'a [Region],
+    duration_ms: u128,
+    warnings: &'a [Warning],
+    sha256: &'a str,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Slice a combined image back into its named regions
+    Extract {
+        /// The path to the file to read layout
+        layout: path::PathBuf,
+        /// The path to the combined image
+        image: path::PathBuf,
+        /// The directory to write regions to
+        outdir: path::PathBuf,
+    },
+    /// Compare two images region by region
+    Diff {
+        /// The path to the file to read layout
+        layout: path::PathBuf,
+        /// The path to the first image
+        a: path::PathBuf,
+        /// The path to the second image
+        b: path::PathBuf,
+    },
+    /// Write a VCDIFF (RFC 3284) patch turning one image into another
+    Delta {
+        /// The path to the old image
+        old: path::PathBuf,
+        /// The path to the new image
+        new: path::PathBuf,
+        /// The path to write the patch to
+        patch: path::PathBuf,
+    },
+    /// Apply a VCDIFF patch, such as one written by `delta`, to the old image
+    Apply {
+        /// The path to the old image
+        old: path::PathBuf,
+        /// The path to the patch
+        patch: path::PathBuf,
+        /// The path to write the new image to
+        new: path::PathBuf,
+    },
+    /// Print the memory map of the image without writing it
+    Map {
+        /// The path to the file to read layout
+        layout: path::PathBuf,
+    },
+    /// Parse and evaluate the layout without downloading, reading or writing
+    /// any data
+    Check {
+        /// The path to the file to read layout
+        layout: path::PathBuf,
+    },
+    /// Emulate the checks a bootloader does on the image before booting it
+    Bootcheck {
+        /// The path to the file to read layout
+        layout: path::PathBuf,
+        /// The path to the combined image
+        image: path::PathBuf,
+        /// The bootloader whose checks are emulated
+        #[arg(long, value_enum)]
+        profile: bootcheck::Profile,
+    },
+    /// Run a language server for layout files over stdio
+    Lsp,
+    /// Execute statements typed interactively against an image
+    Repl {
+        /// The image to work on, an in-memory one is used if omitted
+        image: Option<path::PathBuf>,
+    },
+    /// Write a starter layout
+    Init {
+        /// The template to start from
+        #[arg(long, value_enum, default_value = "single-app-crc")]
+        template: templates::Template,
+        /// Overwrite the layout if it already exists
+        #[arg(long)]
+        force: bool,
+        /// The path to the layout to create
+        #[arg(default_value = "layout.bcl")]
+        layout: path::PathBuf,
+    },
+}
+
+impl Cli {
+    /// Returns the layout the command works on, if any
+    fn layout(&self) -> Option<&path::Path> {
+        match &self.command {
+            Some(Command::Extract { layout, .. })
+            | Some(Command::Diff { layout, .. })
+            | Some(Command::Map { layout })
+            | Some(Command::Check { layout })
+            | Some(Command::Bootcheck { layout, .. }) => Some(layout),
+            Some(Command::Delta { .. })
+            | Some(Command::Apply { .. })
+            | Some(Command::Lsp)
+            | Some(Command::Repl { .. })
+            | Some(Command::Init { .. }) => None,
+            None => self.build.layout.first().map(path::PathBuf::as_path),
+        }
+    }
+}
+
+fn main() {
     let args = Cli::parse();
+    let format = args.global.diagnostics_format;
 
-    let mut variables: HashMap<String, u64> = HashMap::new();
+    let result = run(args);
+    fixture::remove_restored();
+    if let Err(err) = result {
+        diagnostics::error(format, &err);
+        process::exit(diagnostics::exit_code(&err));
+    }
+}
+
+fn run(mut args: Cli) -> Result<()> {
+    let env = args.global.env_file.clone().or_else(|| {
+        args.layout()
+            .map(|layout| layout::base_dir(layout).join(".env"))
+            .filter(|env| env.is_file())
+    });
+    args.global.defines = defines::merge(
+        env.as_deref(), &args.global.defines_file, &args.global.defines
+    )?;
+    if args.global.expand_env {
+        for (_, value) in args.global.defines.iter_mut() {
+            if let Value::Str(text) = value {
+                *text = layout::expand_env(text)?;
+            }
+        }
+    }
+    let tls = if args.global.ca_cert.is_some() || args.global.client_cert.is_some() {
+        let client = args.global.client_cert.as_deref().zip(args.global.client_key.as_deref());
+        Some(http::tls_config(args.global.ca_cert.as_deref(), client)?)
+    }
+    else {
+        None
+    };
+    http::configure(http::Options {
+        retries: args.global.retries,
+        retry_delay: Duration::from_millis(args.global.retry_delay),
+        timeout: args.global.http_timeout.map(Duration::from_secs),
+        max_redirects: args.global.max_redirects,
+        user_agent: args.global.user_agent.clone(),
+        report: Some(args.global.diagnostics_format).filter(|_| !args.build.quiet),
+        tls,
+    });
+    url::configure(url::Options {
+        headers: args.global.http_header.clone(),
+        cache: if args.global.no_cache {
+            None
+        }
+        else {
+            args.global.cache_dir.clone().or_else(url::default_cache)
+        },
+        refresh: args.global.refresh,
+    });
+    if let Some(dir) = &args.build.record {
+        fixture::start(fixture::Mode::Record(dir.clone()))?;
+    }
+    if let Some(dir) = &args.build.replay {
+        fixture::start(fixture::Mode::Replay(dir.clone()))?;
+    }
+    args.global.plugins = args.global.plugin.iter()
+        .map(|ppath| plugin::load(ppath).map(Arc::new))
+        .collect::<Result<Vec<Arc<plugin::Plugin>>>>()?;
+
+    match args.command {
+        Some(Command::Extract { layout, image, outdir }) => {
+            extract(&layout, &image, &outdir, &args.global)
+        }
+        Some(Command::Diff { layout, a, b }) => {
+            diff(&layout, &a, &b, &args.global)
+        }
+        Some(Command::Delta { old, new, patch }) => {
+            delta(&old, &new, &patch)
+        }
+        Some(Command::Apply { old, patch, new }) => {
+            apply(&old, &patch, &new)
+        }
+        Some(Command::Map { layout }) => {
+            let regions = evaluate(&layout, &args.global)?;
+            map::print(&regions);
+            Ok(())
+        }
+        Some(Command::Check { layout }) => {
+            check(&layout, &args.global)
+        }
+        Some(Command::Bootcheck { layout, image, profile }) => {
+            bootcheck::run(&layout, &image, profile, args.global.context(&layout))
+        }
+        Some(Command::Lsp) => {
+            lsp::serve(&args.global.defines)
+        }
+        Some(Command::Repl { image }) => {
+            repl::run(image.as_deref(), &args.global.defines)
+        }
+        Some(Command::Init { template, force, layout }) => {
+            init(&layout, template, force)
+        }
+        None => {
+            // clap guarantees both positionals without a subcommand
+            let layout = &args.build.layout[0];
+            let output = args.build.output.as_deref().unwrap();
+            if args.build.dry_run {
+                return dry_run(layout, output, &args.build, &args.global);
+            }
+
+            let mut digest = None;
+            if args.build.incremental {
+                // Missing inputs are reported by the build itself
+                let (options, files) = state_options(&args.build, &args.global);
+                digest = state::compute(
+                    layout, &args.build.layout[1..], &args.global.defines, &options, &files, args.global.expand_env
+                ).ok();
+                let reports = args.build.stats || args.build.list_vars || args.build.summary_json
+                    || !args.build.post_cmd.is_empty();
+                let outputs = side_outputs(&args.build, output);
+                if !reports && digest.as_deref().is_some_and(|digest| state::up_to_date(output, digest, &outputs)) {
+                    if !args.build.quiet {
+                        eprintln!("`{}` is up to date", output.display());
+                    }
+                    return Ok(());
+                }
+            }
+
+            let exists = output != path::Path::new("-") && output.exists();
+            if exists && !args.build.force && !args.build.patch {
+                bail!("`{}` already exists, use --force to overwrite", output.display());
+            }
+
+            if args.build.watch {
+                watch(layout, output, &args.build, &args.global)
+            }
+            else {
+                combine(layout, output, &args.build, &args.global)?;
+                fixture::finish()?;
+                if let Some(digest) = digest {
+                    state::save(output, &digest, &fixture::take_opened(), &side_outputs(&args.build, output))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Returns the options which change the output or the files written besides
+/// it for `--incremental`, along with the files they name
+fn state_options(opts: &BuildArgs, global: &GlobalArgs) -> (Vec<String>, Vec<path::PathBuf>) {
+    let options = vec![
+        format!("trim={:?}", opts.trim),
+        format!("sector-size={:?}", opts.sector_size),
+        format!("deny-unaligned={}", opts.deny_unaligned),
+        format!("min-offset={}", opts.min_offset),
+        format!("reproducible={}", opts.reproducible),
+        format!("expand-env={}", global.expand_env),
+        format!("relative-to-cwd={}", global.relative_to_cwd),
+        format!("sparse={}", opts.sparse),
+        format!("mode={:?}", opts.mode),
+        format!("touch={:?}", opts.touch),
+        format!("replay={:?}", opts.replay),
+        format!("pre-cmd={:?}", opts.pre_cmd),
+        format!("map={:?}", opts.map),
+        format!("emit-header={:?}", opts.emit_header),
+        format!("emit-rust={:?}", opts.emit_rust),
+        format!("emit-ld={:?}", opts.emit_ld),
+        format!("ld-base={}", opts.ld_base),
+        format!("emit-graph={:?}", opts.emit_graph),
+        format!("vars-out={:?}", opts.vars_out),
+        format!("emit-provenance={:?}", opts.emit_provenance),
+        format!("emit-checksums={:?}", opts.emit_checksums.iter().map(|algorithm| algorithm.suffix()).collect::<Vec<_>>()),
+    ];
+    let mut files = global.plugin.clone();
+    files.extend(opts.target.clone());
+    files.extend(opts.locked.clone());
+    (options, files)
+}
 
-    let wpath = &args.output;
-    let mut outf = OpenOptions::new()
-        .write(true)
-        .read(true)
-        .create(true)
-        .truncate(true)
-        .open(wpath)
+/// Returns the files a build writes besides the output
+fn side_outputs(opts: &BuildArgs, wpath: &path::Path) -> Vec<path::PathBuf> {
+    let mut outputs = [&opts.map, &opts.emit_header, &opts.emit_rust, &opts.emit_ld, &opts.emit_graph, &opts.vars_out, &opts.emit_provenance]
+        .iter()
+        .filter_map(|opath| opath.as_ref().cloned())
+        .collect::<Vec<path::PathBuf>>();
+    outputs.extend(opts.emit_checksums.iter().map(|&algorithm| output::checksum_path(wpath, algorithm)));
+    outputs
+}
+
+fn parse_byte(s: &str) -> Result<u8> {
+    Ok(parse_uint(s)?.try_into()?)
+}
+
+fn parse_mode(s: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(s.strip_prefix("0o").unwrap_or(s), 8)
         .with_context(
-            || format!("could not create file `{}`", wpath.display())
+            || format!("invalid mode `{}`, expected an octal number", s)
         )?;
-    let rpath = &args.layout;
-    let inf = File::open(rpath)
+    if mode > 0o7777 {
+        bail!("Mode {:o} has more than the permission bits", mode);
+    }
+    Ok(mode)
+}
+
+fn parse_time(s: &str) -> Result<u64> {
+    let time = match s {
+        "SOURCE_DATE_EPOCH" => env::var(s).context("SOURCE_DATE_EPOCH is not set")?,
+        _ => s.to_string(),
+    };
+    time.trim().parse()
         .with_context(
-            || format!("could not open file `{}`", rpath.display())
-        )?;
+            || format!("invalid time `{}`, expected seconds since the Unix epoch", time)
+        )
+}
+
+/// Applies the build options affecting how statements are executed
+fn configure(ctx: &mut Context, opts: &BuildArgs) -> Result<()> {
+    ctx.overlays = opts.layout[1..].to_vec();
+    ctx.only = opts.only.clone();
+    ctx.target = match (&opts.target, opts.sector_size) {
+        (Some(tpath), _) => Some(target::Target::load(tpath)?),
+        (None, Some(sector_size)) => Some(target::Target::uniform(sector_size)?),
+        (None, None) => None,
+    };
+    if let Some((size, erase_value)) = ctx.target.as_ref().map(|target| (target.size, target.erase_value)) {
+        if let Some(size) = size {
+            ctx.set_default("TARGET.size", Value::Int(size));
+        }
+        ctx.set_default("TARGET.erase_value", Value::Int(erase_value.into()));
+    }
+    ctx.deny_unaligned = opts.deny_unaligned;
+    ctx.min_offset = opts.min_offset;
+    if !opts.reproducible {
+        return Ok(());
+    }
+    ctx.reproducible = true;
+    if let Ok(epoch) = env::var("SOURCE_DATE_EPOCH") {
+        ctx.epoch = epoch.trim().parse()
+            .with_context(
+                || format!("invalid SOURCE_DATE_EPOCH `{}`", epoch)
+            )?;
+    }
+    Ok(())
+}
 
-    let reader = BufReader::new(inf);
+fn combine(rpath: &path::Path, wpath: &path::Path, opts: &BuildArgs, global: &GlobalArgs) -> Result<()> {
+    let started = Instant::now();
+    let mut ctx = global.context(rpath);
+    ctx.verbose = opts.verbose;
+    configure(&mut ctx, opts)?;
+    let trim = opts.trim.map(|value| {
+        value.unwrap_or_else(|| ctx.target.as_ref().map_or(0xff, |target| target.erase_value))
+    });
+    ctx.progress = !opts.quiet && !opts.no_progress && io::stderr().is_terminal();
+    let to_stdout = wpath == path::Path::new("-");
+    // Size of the output after trimming
+    let mut trimmed = None;
 
-    for (index, buf) in reader.lines().enumerate() {
-        if let Ok(sline) = buf {
-            let line = sline.trim();
-            if line.is_empty() || line.starts_with("#") {
-                continue;
-            }
+    if to_stdout && opts.summary_json {
+        bail!("--summary-json cannot be used when writing to the standard output");
+    }
+
+    if to_stdout && opts.patch {
+        bail!("--patch cannot be used when writing to the standard output");
+    }
+    if to_stdout && opts.sparse {
+        bail!("--sparse cannot be used when writing to the standard output");
+    }
+    if to_stdout && (opts.mode.is_some() || opts.touch.is_some()) {
+        bail!("--mode and --touch cannot be used when writing to the standard output");
+    }
+    if opts.only.is_some() && opts.jobs > 1 {
+        bail!("--only cannot be used with --jobs");
+    }
+    if to_stdout && opts.verify {
+        bail!("--verify cannot be used when writing to the standard output");
+    }
+    if to_stdout && !opts.emit_checksums.is_empty() {
+        bail!("--emit-checksums cannot be used when writing to the standard output");
+    }
+    if to_stdout && !(opts.pre_cmd.is_empty() && opts.post_cmd.is_empty()) {
+        bail!("--pre-cmd and --post-cmd cannot be used when writing to the standard output");
+    }
 
-            let entry = Entry::from_str(&line)?;
-            process_entry(&mut variables, &mut outf, &entry)
+    let paths = vec![
+        ("BINCOMB_LAYOUT".to_string(), rpath.display().to_string()),
+        ("BINCOMB_OUTPUT".to_string(), wpath.display().to_string()),
+    ];
+    for command in &opts.pre_cmd {
+        hook::run(command, &paths)?;
+    }
+    if !to_stdout {
+        if let Some(suffix) = &opts.backup {
+            output::backup(wpath, suffix)?;
+        }
+    }
+
+    // Images too large to be assembled in memory are built in a mapped file
+    // Locked builds are checked in memory before anything is written
+    let locked = opts.locked.is_some();
+    let mapped_size = if mapped::AVAILABLE && !to_stdout && opts.jobs <= 1 && !locked {
+        Some(mapped::planned_size(rpath, &ctx)?).filter(|&size| size >= mapped::THRESHOLD)
+    }
+    else {
+        None
+    };
+
+    let mut manifest = None;
+    let parallel = opts.jobs > 1 && !to_stdout && !locked;
+    let regions = if parallel {
+        // Concurrent statements write to the temporary file directly. In
+        // patch mode only the regions of the layout are rewritten, the rest
+        // of the existing image is kept as is.
+        output::atomic(wpath, opts.patch, |outf| {
+            let regions = parallel::run(rpath, &mut ctx, outf, opts.jobs)?;
+            if let Some(value) = trim {
+                trimmed = Some(output::trim(outf, value)?);
+            }
+            Ok(regions)
+        })?
+    }
+    else if let Some(size) = mapped_size {
+        output::atomic(wpath, opts.patch, |outf| {
+            let regions = mapped::run(rpath, &mut ctx, outf, size)?;
+            if let Some(value) = trim {
+                trimmed = Some(output::trim(outf, value)?);
+            }
+            Ok(regions)
+        })?
+    }
+    else {
+        // The image is assembled in memory and written out at once
+        let mut image = Cursor::new(Vec::new());
+        if opts.patch {
+            *image.get_mut() = fs::read(wpath)
                 .with_context(
-                    || format!("Failed on line {}", index + 1)
+                    || format!("could not open file `{}` for patching", wpath.display())
                 )?;
         }
+        let regions = layout::run(rpath, &mut ctx, &mut image)?;
+        if locked || opts.emit_provenance.is_some() {
+            let built = provenance::collect(&opts.layout, &global.defines, &regions, &mut image)?;
+            if let Some(lpath) = &opts.locked {
+                provenance::check(lpath, &built)?;
+            }
+            manifest = Some(built);
+        }
+        if let Some(value) = trim {
+            let len = output::trimmed_len(image.get_ref(), value);
+            image.get_mut().truncate(len);
+            trimmed = Some(len as u64);
+        }
+
+        if to_stdout {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(image.get_ref())
+                .and_then(|_| stdout.flush())
+                .context("could not write to the standard output")?;
+        }
+        else {
+            output::atomic(wpath, false, |outf| {
+                let written = if opts.sparse {
+                    output::write_sparse(outf, image.get_ref())
+                }
+                else {
+                    outf.write_all(image.get_ref())
+                };
+                written
+                    .with_context(
+                        || format!("could not write file `{}`", wpath.display())
+                    )
+            })?;
+        }
+        regions
+    };
+    // Images built in the file have their zeros written out
+    if opts.sparse && (parallel || mapped_size.is_some()) {
+        output::sparsify(wpath)?;
     }
 
-    println!("{:?}", variables);
+    if opts.verify {
+        verify(rpath, wpath, opts, global)?;
+    }
+    output::set_metadata(wpath, opts.mode, opts.touch)?;
+
+    if let Some(mpath) = &opts.map {
+        map::write(mpath, &regions)?;
+    }
+    if let Some(hpath) = &opts.emit_header {
+        emit::header(hpath, &regions)?;
+    }
+    if let Some(rspath) = &opts.emit_rust {
+        emit::rust(rspath, &regions)?;
+    }
+    if let Some(ldpath) = &opts.emit_ld {
+        emit::ld(ldpath, &regions, opts.ld_base)?;
+    }
+    if let Some(vpath) = &opts.vars_out {
+        emit::vars(vpath, &ctx)?;
+    }
+    if let Some(gpath) = &opts.emit_graph {
+        emit::graph(gpath, &layout::statements(rpath, &ctx)?, &regions)?;
+    }
+    if let Some(ppath) = &opts.emit_provenance {
+        let manifest = match manifest {
+            Some(manifest) => manifest,
+            None => {
+                let mut image = File::open(wpath)
+                    .with_context(
+                        || format!("could not open file `{}`", wpath.display())
+                    )?;
+                provenance::collect(&opts.layout, &global.defines, &regions, &mut image)?
+            }
+        };
+        provenance::write(ppath, &manifest)?;
+    }
+
+    if opts.stats {
+        stats::print(&regions);
+    }
+    if opts.list_vars {
+        list_vars(&ctx);
+    }
+
+    if opts.verbose >= 2 {
+        let mut names = ctx.vars.keys().collect::<Vec<&String>>();
+        names.sort();
+        for name in names {
+            eprintln!("${} = 0x{:x}", name, ctx.vars[name]);
+        }
+        let mut names = ctx.strings.keys().collect::<Vec<&String>>();
+        names.sort();
+        for name in names {
+            eprintln!("${} = {:?}", name, ctx.strings[name]);
+        }
+    }
+
+    let end = layout::image_end(&regions);
+    let size = trimmed.unwrap_or(end);
+    if !opts.quiet && size < end {
+        eprintln!("Trimmed 0x{:x} trailing bytes, the output is 0x{:x} bytes", end - size, size);
+    }
+
+    let sha256 = if to_stdout { Vec::new() } else { digest::sha256_file(wpath)? };
+    for &algorithm in &opts.emit_checksums {
+        let hash = match algorithm {
+            digest::Algorithm::Sha256 => sha256.clone(),
+            _ => algorithm.file(wpath)?,
+        };
+        output::checksum_file(wpath, algorithm, &hash)?;
+    }
+
+    if !opts.post_cmd.is_empty() {
+        let mut env = paths;
+        env.push(("BINCOMB_SIZE".to_string(), size.to_string()));
+        env.push(("BINCOMB_SHA256".to_string(), digest::to_hex(&sha256)));
+        env.extend(hook::variables(&ctx));
+        for command in &opts.post_cmd {
+            hook::run(command, &env)?;
+        }
+    }
+
+    if opts.summary_json {
+        let summary = Summary {
+            output: wpath,
+            size,
+            regions: &regions,
+            duration_ms: started.elapsed().as_millis(),
+            warnings: &ctx.warnings,
+            sha256: &digest::to_hex(&sha256),
+        };
+        println!("{}", serde_json::to_string(&summary)?);
+    }
+
+    if !opts.quiet {
+        for warning in &ctx.warnings {
+            diagnostics::warning(global.diagnostics_format, rpath, warning);
+        }
+        let sha256 = Some(digest::to_hex(&sha256)).filter(|_| !to_stdout);
+        summary::print(&regions, wpath, size, sha256.as_deref(), opts.color.enabled());
+    }
+
+    Ok(())
+}
+
+fn dry_run(rpath: &path::Path, wpath: &path::Path, opts: &BuildArgs, global: &GlobalArgs) -> Result<()> {
+    let mut ctx = global.context(rpath);
+    ctx.dry_run = true;
+    configure(&mut ctx, opts)?;
+    let mut scratch = Cursor::new(Vec::new());
+    let regions = layout::run(rpath, &mut ctx, &mut scratch)?;
+
+    for region in &regions {
+        let what = match &region.source {
+            Some(source) => format!("`{}`", source),
+            None => region.func.clone(),
+        };
+        println!(
+            "Would write {} bytes of {} to `{}` at 0x{:08x} ({})",
+            region.size, what, wpath.display(), region.addr, region.name
+        );
+    }
+
+    let total = layout::image_end(&regions);
+    println!("Resulting image size would be at least {} bytes", total);
+
+    if opts.list_vars {
+        list_vars(&ctx);
+    }
 
     Ok(())
 }
 
-fn process_entry<F>(vars: &mut HashMap<String, u64>, outf: &mut F, entry: &Entry) -> Result<()>
-where
-    F: Seek + Read + Write,
-{
-    let mut length: u64 = 0;
-    let mut var_name: String = entry.name.to_string();
-    var_name.push_str(".start");
-    vars.insert(var_name, entry.addr);
+/// Builds the layout again in memory, reading every input once more, and
+/// checks that the output on the disk has the same content
+fn verify(rpath: &path::Path, wpath: &path::Path, opts: &BuildArgs, global: &GlobalArgs) -> Result<()> {
+    let mut ctx = global.context(rpath);
+    configure(&mut ctx, opts)?;
+    let mut expected = Cursor::new(Vec::new());
+    let regions = layout::run(rpath, &mut ctx, &mut expected)?;
+    let expected = expected.into_inner();
+    let actual = read_image(wpath)?;
 
-    if entry.func == "file" {
-        if entry.args.len() != 1 {
-            bail!("Error number of arguments");
+    let mut differing = Vec::new();
+    for region in &regions {
+        let start: usize = region.addr.try_into()?;
+        let end: usize = region.end().try_into()?;
+        if !diff_ranges(&expected, &actual, start, end).is_empty() {
+            differing.push(region.name.as_str());
+        }
+    }
+    if !differing.is_empty() {
+        bail!(CheckError::Mismatch(format!(
+            "Verification of `{}` failed, regions differ: {}", wpath.display(), differing.join(", ")
+        )));
+    }
+    if expected != actual {
+        bail!(CheckError::Mismatch(format!(
+            "Verification of `{}` failed, it differs outside of regions", wpath.display()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Prints every variable, defined or generated by statements, sorted by name
+fn list_vars(ctx: &Context) {
+    let mut rows = ctx.vars.iter()
+        .map(|(name, value)| (name, format!("0x{:08x}", value), value.to_string()))
+        .chain(ctx.strings.iter().map(|(name, value)| (name, format!("{:?}", value), String::new())))
+        .collect::<Vec<(&String, String, String)>>();
+    rows.sort();
+
+    let width = rows.iter()
+        .map(|(name, _, _)| name.len() + 1)
+        .max()
+        .unwrap_or(0)
+        .max("NAME".len());
+    println!("{:<width$}  {:<18}  DECIMAL", "NAME", "VALUE", width = width);
+    for (name, value, decimal) in rows {
+        println!("{:<width$}  {:<18}  {}", format!("${}", name), value, decimal, width = width);
+    }
+}
+
+/// Interval between checks for modified files in watch mode
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+fn watch(rpath: &path::Path, wpath: &path::Path, opts: &BuildArgs, global: &GlobalArgs) -> Result<()> {
+    loop {
+        match combine(rpath, wpath, opts, global) {
+            Ok(()) => {
+                let size = fs::metadata(wpath).map(|meta| meta.len()).unwrap_or(0);
+                println!("Built `{}` ({} bytes)", wpath.display(), size);
+            }
+            Err(err) => diagnostics::error(global.diagnostics_format, &err),
+        }
+
+        // The inputs resolved by the build, up to its error if it failed
+        let mut files = opts.layout.clone();
+        files.extend(fixture::take_opened());
+        println!("Watching {} files for changes...", files.len());
+
+        let snapshot = modification_times(&files);
+        while modification_times(&files) == snapshot {
+            thread::sleep(WATCH_INTERVAL);
         }
-        let f = File::open(entry.args[0])
-            .with_context(
-                || format!("Could not open file {}", entry.args[0])
-            )?;
-        let mut reader = BufReader::new(f);
-        outf.seek(SeekFrom::Start(entry.addr))?;
-        length = copy(&mut reader, outf)?;
     }
-    else if entry.func == "crc16" {
-        if entry.args.len() != 2 {
-            bail!("Error number of arguments")
+}
+
+/// Returns the modification times of the files, and of everything in the
+/// directories among them
+fn modification_times(files: &[path::PathBuf]) -> Vec<(path::PathBuf, Option<SystemTime>)> {
+    let mut times = Vec::new();
+    for file in files {
+        let modified = |fpath: &path::Path| fs::metadata(fpath).and_then(|meta| meta.modified()).ok();
+        times.push((file.clone(), modified(file)));
+        if file.is_dir() {
+            for entry in archive::entries(file).unwrap_or_default() {
+                times.push((entry.path.clone(), modified(&entry.path)));
+            }
         }
+    }
+    times
+}
 
-        let addr = unpack_arg(&vars, &entry.args[0])?;
-        length = unpack_arg(&vars, &entry.args[1])?;
+/// Evaluates the layout without writing any data and returns the regions
+/// it would write, the input files referenced by the layout must be
+/// available to tell their sizes
+fn evaluate(rpath: &path::Path, global: &GlobalArgs) -> Result<Vec<Region>> {
+    let mut ctx = global.context(rpath);
+    ctx.dry_run = true;
+    let mut scratch = Cursor::new(Vec::new());
+    layout::run(rpath, &mut ctx, &mut scratch)
+}
 
-        outf.seek(SeekFrom::Start(addr))?;
-        let mut bin = vec![0; length.try_into()?];
-        outf.read(&mut bin)?;
+fn check(rpath: &path::Path, global: &GlobalArgs) -> Result<()> {
+    let mut ctx = global.context(rpath);
+    ctx.dry_run = true;
+    ctx.check_only = true;
+    layout::run(rpath, &mut ctx, &mut Cursor::new(Vec::new()))?;
 
-        let crc = crc::Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
-        let result = crc.checksum(&bin).to_le_bytes();
-        outf.seek(SeekFrom::Start(entry.addr))?;
-        let _ = outf.write(&result[..2])?;
+    for warning in &ctx.warnings {
+        diagnostics::warning(global.diagnostics_format, rpath, warning);
     }
-    else {
-        bail!("Unknown function name '{}'", entry.func);
+    println!("{}: OK", rpath.display());
+
+    Ok(())
+}
+
+fn init(wpath: &path::Path, template: templates::Template, force: bool) -> Result<()> {
+    if wpath.exists() && !force {
+        bail!("`{}` already exists, use --force to overwrite", wpath.display());
     }
 
-    let mut var_name: String = entry.name.to_string();
-    var_name.push_str(".size");
-    vars.insert(var_name, length);
+    fs::write(wpath, template.content())
+        .with_context(
+            || format!("could not write file `{}`", wpath.display())
+        )?;
+    println!("Created {}", wpath.display());
+
+    Ok(())
+}
+
+fn read_image(rpath: &path::Path) -> Result<Vec<u8>> {
+    fs::read(rpath)
+        .with_context(
+            || format!("could not read file `{}`", rpath.display())
+        )
+}
+
+fn delta(old: &path::Path, new: &path::Path, wpath: &path::Path) -> Result<()> {
+    let patch = delta::diff(&read_image(old)?, &read_image(new)?);
+    output::atomic(wpath, false, |outf| Ok(outf.write_all(&patch)?))?;
+    println!("Written a patch of {} bytes to `{}`", patch.len(), wpath.display());
+
+    Ok(())
+}
+
+fn apply(old: &path::Path, ppath: &path::Path, wpath: &path::Path) -> Result<()> {
+    let image = delta::apply(&read_image(old)?, &read_image(ppath)?)
+        .with_context(
+            || format!("could not apply `{}`", ppath.display())
+        )?;
+    output::atomic(wpath, false, |outf| Ok(outf.write_all(&image)?))?;
+    println!("Written {} bytes to `{}`", image.len(), wpath.display());
 
     Ok(())
 }
 
-fn parse_uint(s: &str) -> Result<u64> {
-    let hex_prefix = "0x";
-    let mut value = s;
-    let mut base = 10;
+fn extract(rpath: &path::Path, image: &path::Path, outdir: &path::Path, global: &GlobalArgs) -> Result<()> {
+    let regions = evaluate(rpath, global)?;
+
+    let bin = fs::read(image)
+        .with_context(
+            || format!("could not read file `{}`", image.display())
+        )?;
+
+    fs::create_dir_all(outdir)
+        .with_context(
+            || format!("could not create directory `{}`", outdir.display())
+        )?;
+
+    for region in regions {
+        let start: usize = region.addr.try_into()?;
+        let end: usize = region.end().try_into()?;
+        if end > bin.len() {
+            bail!(
+                "Region '{}' (0x{:x}..0x{:x}) is outside of the image",
+                region.name, start, end
+            );
+        }
 
-    if s.starts_with(hex_prefix) {
-        value = &value[2..];
-        base = 16;
+        // Statement names may contain anything but colons, such as `../`
+        if !layout::valid_name(&region.name) {
+            bail!("Region name '{}' can't be used as a file name", region.name);
+        }
+        let wpath = outdir.join(format!("{}.bin", region.name));
+        fs::write(&wpath, &bin[start..end])
+            .with_context(
+                || format!("could not write file `{}`", wpath.display())
+            )?;
+        println!("{}: 0x{:x}..0x{:x} -> {}", region.name, start, end, wpath.display());
     }
 
-    Ok(u64::from_str_radix(&value, base)?)
+    Ok(())
 }
 
-fn unpack_arg(vars: &HashMap<String, u64>, arg: &str) -> Result<u64> {
-    if arg.starts_with("$") {
-        if let Some(&value) = vars.get(&arg[1..]) {
-            return Ok(value)
+fn diff(rpath: &path::Path, apath: &path::Path, bpath: &path::Path, global: &GlobalArgs) -> Result<()> {
+    let regions = evaluate(rpath, global)?;
+
+    let a = fs::read(apath)
+        .with_context(
+            || format!("could not read file `{}`", apath.display())
+        )?;
+    let b = fs::read(bpath)
+        .with_context(
+            || format!("could not read file `{}`", bpath.display())
+        )?;
+
+    let total = a.len().max(b.len());
+    let mut covered = vec![false; total];
+
+    for region in &regions {
+        let start: usize = region.addr.try_into()?;
+        let end: usize = region.end().try_into()?;
+        for flag in covered.iter_mut().take(end).skip(start) {
+            *flag = true;
+        }
+
+        let ranges = diff_ranges(&a, &b, start, end);
+        if ranges.is_empty() {
+            println!("{} identical", region.name);
+        }
+        else {
+            println!("{} differs in {} ranges", region.name, ranges.len());
+            for (start, end) in ranges {
+                println!("  0x{:x}..0x{:x}", start, end);
+            }
         }
-        Err(anyhow!("Missing variable: {}", arg))
     }
-    else {
-        parse_uint(arg)
+
+    let unmapped = diff_ranges(&a, &b, 0, total)
+        .into_iter()
+        .flat_map(|(start, end)| split_by_mask(&covered, start, end, false))
+        .collect::<Vec<(usize, usize)>>();
+    if !unmapped.is_empty() {
+        println!("Outside of regions differs in {} ranges", unmapped.len());
+        for (start, end) in unmapped {
+            println!("  0x{:x}..0x{:x}", start, end);
+        }
     }
+
+    Ok(())
 }
 
-impl<'a> Entry<'a> {
-    fn from_str(line: &str) -> Result<Entry> {
-        let values = line.split(':').map(|el| el.trim()).collect::<Vec<&str>>();
+/// Returns ranges within `start..end` where the images differ. Bytes present
+/// in only one of the images count as different.
+fn diff_ranges(a: &[u8], b: &[u8], start: usize, end: usize) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut range_start: Option<usize> = None;
 
-        if values.len() != 3 {
-            bail!("Error number values");
+    for offset in start..end {
+        let same = match (a.get(offset), b.get(offset)) {
+            (Some(x), Some(y)) => x == y,
+            (None, None) => true,
+            _ => false,
+        };
+        match (same, range_start) {
+            (false, None) => range_start = Some(offset),
+            (true, Some(first)) => {
+                ranges.push((first, offset));
+                range_start = None;
+            }
+            _ => {}
         }
+    }
 
-        if values[2].is_empty() {
-            bail!("Function name cannot be empty");
-        }
+    if let Some(first) = range_start {
+        ranges.push((first, end));
+    }
 
-        let address = parse_uint(&values[0])?;
+    ranges
+}
 
-        let func = values[2]
-            .split(",")
-            .map(|el| el.trim())
-            .collect::<Vec<&str>>();
+/// Splits `start..end` into subranges where `mask` equals `value`
+fn split_by_mask(mask: &[bool], start: usize, end: usize, value: bool) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut range_start: Option<usize> = None;
 
-        Ok(Entry {
-            addr: address,
-            name: &values[1],
-            func: &func[0],
-            args: func[1..].to_vec(),
-        })
+    for (offset, &flag) in mask.iter().enumerate().take(end).skip(start) {
+        match (flag == value, range_start) {
+            (true, None) => range_start = Some(offset),
+            (false, Some(first)) => {
+                ranges.push((first, offset));
+                range_start = None;
+            }
+            _ => {}
+        }
     }
+
+    if let Some(first) = range_start {
+        ranges.push((first, end));
+    }
+
+    ranges
 }
+
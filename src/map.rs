@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path;
+
+use crate::layout::Region;
+
+#[derive(Serialize)]
+struct Map<'a> {
+    regions: &'a [Region],
+}
+
+/// Writes the map of the image regions. The format is chosen by the file
+/// extension: JSON for `.json`, a human-readable table otherwise.
+pub fn write(wpath: &path::Path, regions: &[Region]) -> Result<()> {
+    let f = File::create(wpath)
+        .with_context(
+            || format!("could not create file `{}`", wpath.display())
+        )?;
+    let mut writer = BufWriter::new(f);
+
+    if wpath.extension().is_some_and(|ext| ext == "json") {
+        serde_json::to_writer_pretty(&mut writer, &Map { regions })?;
+        writeln!(writer)?;
+    }
+    else {
+        write_text(&mut writer, regions)?;
+    }
+
+    writer.flush()
+        .with_context(
+            || format!("could not write file `{}`", wpath.display())
+        )?;
+
+    Ok(())
+}
+
+fn write_text<W: Write>(writer: &mut W, regions: &[Region]) -> Result<()> {
+    let width = regions.iter()
+        .map(|region| region.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("Name".len());
+
+    writeln!(
+        writer,
+        "{:<width$}  {:<10}  {:<10}  {:<10}  {:<8}  {:<6}  Source",
+        "Name", "Start", "End", "Size", "Function", "Check", width = width
+    )?;
+
+    for region in regions {
+        let checksum = region.checksum
+            .map(|value| format!("0x{:04x}", value))
+            .unwrap_or_default();
+        writeln!(
+            writer,
+            "{:<width$}  0x{:08x}  0x{:08x}  {:<10}  {:<8}  {:<6}  {}",
+            region.name,
+            region.addr,
+            region.end(),
+            region.size,
+            region.func,
+            checksum,
+            region.source.as_deref().unwrap_or(""),
+            width = width
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Width of the bar chart in characters
+const BAR_WIDTH: u64 = 32;
+
+/// Prints the regions sorted by address as a table with a bar chart, marking
+/// gaps between regions and regions overlapping each other.
+pub fn print(regions: &[Region]) {
+    let mut sorted = regions.iter().collect::<Vec<&Region>>();
+    sorted.sort_by_key(|region| (region.addr, region.end()));
+
+    let total = sorted.iter()
+        .map(|region| region.end())
+        .max()
+        .unwrap_or(0);
+    let width = sorted.iter()
+        .map(|region| region.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("<gap>".len());
+
+    let mut end = 0;
+    let mut last: Option<&Region> = None;
+
+    for region in sorted {
+        if region.addr > end {
+            println!(
+                "0x{:08x}..0x{:08x}  {:<width$}  {:>10}  {}",
+                end, region.addr, "<gap>", region.addr - end,
+                bar(end, region.addr, total, '.'),
+                width = width
+            );
+        }
+
+        let note = match last {
+            Some(prev) if region.addr < end => format!("  ! overlaps {}", prev.name),
+            _ => String::new(),
+        };
+        println!(
+            "0x{:08x}..0x{:08x}  {:<width$}  {:>10}  {}{}",
+            region.addr, region.end(), region.name, region.size,
+            bar(region.addr, region.end(), total, '#'),
+            note,
+            width = width
+        );
+
+        if region.end() >= end {
+            end = region.end();
+            last = Some(region);
+        }
+    }
+
+    println!("Total size: {} bytes", total);
+}
+
+fn bar(start: u64, end: u64, total: u64, fill: char) -> String {
+    if total == 0 {
+        return String::new();
+    }
+
+    let first = start * BAR_WIDTH / total;
+    let last = (end * BAR_WIDTH).div_ceil(total).max(first + 1);
+
+    let cells = (0..BAR_WIDTH)
+        .map(|cell| if cell >= first && cell < last { fill } else { ' ' })
+        .collect::<String>();
+
+    format!("|{}|", cells)
+}
@@ -0,0 +1,42 @@
+//! Building huge images in a memory-mapped output file, available with the
+//! `mmap` feature.
+
+use anyhow::Result;
+use std::fs::File;
+use std::io::Cursor;
+use std::path;
+
+use crate::layout::{self, Context, Region};
+
+/// Whether the crate was built with the `mmap` feature
+pub const AVAILABLE: bool = cfg!(feature = "mmap");
+
+/// Images at least this large are built in a memory-mapped file
+pub const THRESHOLD: u64 = 1024 * 1024 * 1024;
+
+/// Returns the size of the image the layout is going to write
+pub fn planned_size(rpath: &path::Path, ctx: &Context) -> Result<u64> {
+    let mut plan_ctx = ctx.planning();
+    let regions = layout::run(rpath, &mut plan_ctx, &mut Cursor::new(Vec::new()))?;
+    Ok(layout::image_end(&regions))
+}
+
+/// Executes the layout on the file mapped into memory, the file is grown to
+/// `size` bytes first
+#[cfg(feature = "mmap")]
+pub fn run(rpath: &path::Path, ctx: &mut Context, outf: &mut File, size: u64) -> Result<Vec<Region>> {
+    outf.set_len(size.max(outf.metadata()?.len()))?;
+
+    // Safety: the file is the private temporary output, nothing else
+    // modifies or truncates it while it is mapped
+    let mut map = unsafe { memmap2::MmapMut::map_mut(&*outf)? };
+    let regions = layout::run(rpath, ctx, &mut Cursor::new(&mut map[..]))?;
+    map.flush()?;
+
+    Ok(regions)
+}
+
+#[cfg(not(feature = "mmap"))]
+pub fn run(_rpath: &path::Path, _ctx: &mut Context, _outf: &mut File, _size: u64) -> Result<Vec<Region>> {
+    anyhow::bail!("bincomb was built without the `mmap` feature")
+}
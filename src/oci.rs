@@ -0,0 +1,170 @@
+//! Input files stored as layers of OCI artifacts, as pushed by ORAS.
+//!
+//! Registries asking for a token are handled with the bearer token flow,
+//! anonymously or with `OCI_USERNAME` and `OCI_PASSWORD` if they are set.
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::env;
+
+use crate::digest;
+use crate::http;
+use crate::progress;
+
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// A parsed `registry/repository:tag` or `registry/repository@digest`
+struct Reference<'a> {
+    registry: &'a str,
+    repository: String,
+    tag: &'a str,
+}
+
+fn parse_reference(reference: &str) -> Reference<'_> {
+    let (registry, rest) = match reference.split_once('/') {
+        Some((host, rest)) if host.contains('.') || host.contains(':') || host == "localhost" => {
+            (host, rest)
+        }
+        _ => ("registry-1.docker.io", reference),
+    };
+
+    let (repository, tag) = match rest.split_once('@') {
+        Some((repository, digest)) => (repository, digest),
+        None => match rest.rsplit_once(':') {
+            Some((repository, tag)) => (repository, tag),
+            None => (rest, "latest"),
+        },
+    };
+
+    let repository = if registry == "registry-1.docker.io" && !repository.contains('/') {
+        format!("library/{}", repository)
+    }
+    else {
+        repository.to_string()
+    };
+
+    Reference { registry, repository, tag }
+}
+
+/// Client for a single repository, remembering the token once obtained
+struct Registry<'a> {
+    reference: Reference<'a>,
+    token: Option<String>,
+}
+
+impl Registry<'_> {
+    fn get(&mut self, path: &str, accept: &str) -> Result<ureq::Response> {
+        let url = format!(
+            "https://{}/v2/{}/{}",
+            self.reference.registry, self.reference.repository, path
+        );
+
+        match http::call(self.request(&url, accept)) {
+            Err(ureq::Error::Status(401, response)) if self.token.is_none() => {
+                let challenge = response.header("www-authenticate").unwrap_or("").to_string();
+                self.token = Some(authenticate(&challenge)?);
+                Ok(http::call(self.request(&url, accept))?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    fn request(&self, url: &str, accept: &str) -> ureq::Request {
+        let request = http::get(url).set("Accept", accept);
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+}
+
+/// Obtains a token as described by the `WWW-Authenticate` challenge
+fn authenticate(challenge: &str) -> Result<String> {
+    let params = challenge.strip_prefix("Bearer ")
+        .ok_or_else(|| anyhow!("Unsupported authentication '{}'", challenge))?;
+
+    let mut realm = None;
+    let mut query = Vec::new();
+    for param in params.split(',') {
+        if let Some((key, value)) = param.trim().split_once('=') {
+            let value = value.trim_matches('"');
+            if key == "realm" {
+                realm = Some(value);
+            }
+            else {
+                query.push((key, value));
+            }
+        }
+    }
+
+    let realm = realm.ok_or_else(|| anyhow!("No realm in '{}'", challenge))?;
+    let mut request = http::get(realm);
+    for (key, value) in query {
+        request = request.query(key, value);
+    }
+    if let (Ok(user), Ok(password)) = (env::var("OCI_USERNAME"), env::var("OCI_PASSWORD")) {
+        request = request.set("Authorization", &http::basic_auth(&user, &password));
+    }
+
+    let response: Value = serde_json::from_str(&http::call(request)?.into_string()?)?;
+    response["token"].as_str()
+        .or_else(|| response["access_token"].as_str())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("No token in the response of {}", realm))
+}
+
+/// Downloads the layer titled `name` from the artifact
+pub fn fetch(reference: &str, name: &str, show_progress: bool) -> Result<Vec<u8>> {
+    let mut registry = Registry { reference: parse_reference(reference), token: None };
+
+    let result = (|| {
+        let tag = registry.reference.tag;
+        let manifest: Value = serde_json::from_str(
+            &registry.get(&format!("manifests/{}", tag), MANIFEST_TYPES)?.into_string()?
+        )?;
+        let layer_digest = manifest["layers"].as_array()
+            .into_iter()
+            .flatten()
+            .find(|layer| layer["annotations"]["org.opencontainers.image.title"] == name)
+            .and_then(|layer| layer["digest"].as_str())
+            .ok_or_else(|| anyhow!("Artifact has no layer titled '{}'", name))?
+            .to_string();
+
+        let response = registry.get(&format!("blobs/{}", layer_digest), "*/*")?;
+        let total = response.header("content-length").and_then(|len| len.parse().ok());
+        let data = progress::read_to_end(response.into_reader(), name, total, show_progress)?;
+
+        let actual = format!("sha256:{}", digest::to_hex(&Sha256::digest(&data)));
+        if actual != layer_digest {
+            bail!("Layer digest mismatch: expected {}, got {}", layer_digest, actual);
+        }
+
+        Ok(data)
+    })();
+
+    result.with_context(
+        || format!("Could not fetch {} from {}", name, reference)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references() {
+        let reference = parse_reference("ghcr.io/acme/firmware:v1.2");
+        assert_eq!((reference.registry, reference.repository.as_str(), reference.tag), ("ghcr.io", "acme/firmware", "v1.2"));
+
+        let reference = parse_reference("localhost:5000/fw@sha256:abcd");
+        assert_eq!((reference.registry, reference.repository.as_str(), reference.tag), ("localhost:5000", "fw", "sha256:abcd"));
+
+        let reference = parse_reference("busybox");
+        assert_eq!(
+            (reference.registry, reference.repository.as_str(), reference.tag),
+            ("registry-1.docker.io", "library/busybox", "latest")
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{Context as _, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::digest;
+
+/// Size of the blocks `write_sparse` leaves as holes when they are all zeros
+const HOLE_SIZE: usize = 4096;
+
+/// Returns the path with the suffix appended to its file name
+fn with_suffix(wpath: &path::Path, suffix: &str) -> path::PathBuf {
+    let mut name = wpath.file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_default();
+    name.push(suffix);
+    wpath.with_file_name(name)
+}
+
+/// Returns the path of the temporary file the image is built in
+pub fn temp_path(wpath: &path::Path) -> path::PathBuf {
+    with_suffix(wpath, ".tmp")
+}
+
+/// Copies the existing output to a file with the suffix appended to its
+/// name, does nothing if there is no output yet
+pub fn backup(wpath: &path::Path, suffix: &str) -> Result<()> {
+    if !wpath.exists() {
+        return Ok(());
+    }
+
+    let bpath = with_suffix(wpath, suffix);
+    fs::copy(wpath, &bpath)
+        .with_context(
+            || format!("could not back up `{}` to `{}`", wpath.display(), bpath.display())
+        )?;
+
+    Ok(())
+}
+
+/// Returns the path of the file `checksum_file` writes
+pub fn checksum_path(wpath: &path::Path, algorithm: digest::Algorithm) -> path::PathBuf {
+    with_suffix(wpath, algorithm.suffix())
+}
+
+/// Writes the digest of the output to a file with the suffix of the
+/// algorithm appended to its name, in the format `sha256sum -c` checks
+pub fn checksum_file(wpath: &path::Path, algorithm: digest::Algorithm, hash: &[u8]) -> Result<()> {
+    let cpath = checksum_path(wpath, algorithm);
+    let name = wpath.file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    fs::write(&cpath, format!("{}  {}\n", digest::to_hex(hash), name))
+        .with_context(
+            || format!("could not write file `{}`", cpath.display())
+        )?;
+
+    Ok(())
+}
+
+/// Builds the image in a temporary file next to the output and renames it
+/// over the output only once `build` succeeds, so a failed build never leaves
+/// a half-written image behind. In patch mode the temporary file starts as a
+/// copy of the existing output.
+pub fn atomic<T, B>(wpath: &path::Path, patch: bool, build: B) -> Result<T>
+where
+    B: FnOnce(&mut File) -> Result<T>,
+{
+    let tpath = temp_path(wpath);
+
+    if patch {
+        fs::copy(wpath, &tpath)
+            .with_context(
+                || format!("could not open file `{}` for patching", wpath.display())
+            )?;
+    }
+
+    let mut outf = OpenOptions::new()
+        .write(true)
+        .read(true)
+        .create(true)
+        .truncate(!patch)
+        .open(&tpath)
+        .with_context(
+            || format!("could not create file `{}`", tpath.display())
+        )?;
+
+    let result = build(&mut outf)
+        .and_then(|value| {
+            outf.sync_all()
+                .with_context(
+                    || format!("could not write file `{}`", tpath.display())
+                )?;
+            Ok(value)
+        });
+    drop(outf);
+
+    match result {
+        Ok(value) => {
+            fs::rename(&tpath, wpath)
+                .with_context(
+                    || format!("could not replace file `{}`", wpath.display())
+                )?;
+            Ok(value)
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&tpath);
+            Err(err)
+        }
+    }
+}
+
+/// Sets the permissions and the modification time of the output, for
+/// archives of it to be reproducible
+pub fn set_metadata(wpath: &path::Path, mode: Option<u32>, mtime: Option<u64>) -> Result<()> {
+    if let Some(mtime) = mtime {
+        File::options()
+            .write(true)
+            .open(wpath)
+            .and_then(|outf| outf.set_modified(UNIX_EPOCH + Duration::from_secs(mtime)))
+            .with_context(
+                || format!("could not set the modification time of `{}`", wpath.display())
+            )?;
+    }
+    // After the time, the mode may not allow writing
+    if let Some(mode) = mode {
+        set_mode(wpath, mode)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(wpath: &path::Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(wpath, fs::Permissions::from_mode(mode))
+        .with_context(
+            || format!("could not set the permissions of `{}`", wpath.display())
+        )
+}
+
+#[cfg(not(unix))]
+fn set_mode(_wpath: &path::Path, _mode: u32) -> Result<()> {
+    anyhow::bail!("--mode is only supported on Unix")
+}
+
+/// Writes the image to the empty file, seeking over blocks of zeros instead
+/// of writing them so the file system can leave them unallocated
+pub fn write_sparse(outf: &mut File, data: &[u8]) -> io::Result<()> {
+    write_blocks(outf, data)?;
+    outf.set_len(data.len() as u64)
+}
+
+fn write_blocks(outf: &mut File, data: &[u8]) -> io::Result<()> {
+    for block in data.chunks(HOLE_SIZE) {
+        if block.iter().all(|&byte| byte == 0) {
+            outf.seek(SeekFrom::Current(block.len() as i64))?;
+        }
+        else {
+            outf.write_all(block)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the output over itself like `write_sparse`, for images which were
+/// built in the file and had their zeros written out
+pub fn sparsify(wpath: &path::Path) -> Result<()> {
+    let mut inf = File::open(wpath)
+        .with_context(
+            || format!("could not open file `{}`", wpath.display())
+        )?;
+
+    atomic(wpath, false, |outf| {
+        let mut chunk = Vec::with_capacity(HOLE_SIZE * 256);
+        let mut size = 0;
+        loop {
+            chunk.clear();
+            (&mut inf).take((HOLE_SIZE * 256) as u64).read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            write_blocks(outf, &chunk)?;
+            size += chunk.len() as u64;
+        }
+        outf.set_len(size)
+            .with_context(
+                || format!("could not write file `{}`", wpath.display())
+            )
+    })
+}
+
+/// Returns the length of the data without the trailing bytes of the value
+pub fn trimmed_len(data: &[u8], value: u8) -> usize {
+    data.iter().rposition(|&byte| byte != value).map_or(0, |pos| pos + 1)
+}
+
+/// Truncates trailing bytes of the value off the file, returns its new size
+pub fn trim(outf: &mut File, value: u8) -> io::Result<u64> {
+    let mut end = outf.seek(SeekFrom::End(0))?;
+    let mut buf = [0; HOLE_SIZE];
+
+    while end > 0 {
+        let len = end.min(HOLE_SIZE as u64) as usize;
+        outf.seek(SeekFrom::Start(end - len as u64))?;
+        outf.read_exact(&mut buf[..len])?;
+        let kept = trimmed_len(&buf[..len], value);
+        end -= (len - kept) as u64;
+        if kept > 0 {
+            break;
+        }
+    }
+
+    outf.set_len(end)?;
+    Ok(end)
+}
@@ -0,0 +1,230 @@
+//! Concurrent execution of statements which don't depend on each other.
+//!
+//! The layout is first evaluated without touching any data to learn every
+//! variable and the ranges each statement reads and writes. Statements are
+//! then grouped into waves: a statement joins the wave after the last earlier
+//! statement it conflicts with, so statements within a wave touch disjoint
+//! parts of the image and can run at the same time.
+
+use anyhow::{anyhow, Context as _, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path;
+use std::thread;
+
+use crate::directive;
+use crate::layout::{self, Context, Entry, Location, Region};
+
+/// A handle with its own position, so that several threads can access the
+/// same file without sharing the cursor
+struct PositionedFile {
+    file: File,
+    pos: u64,
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, pos)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], pos: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, pos)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, pos)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, buf: &[u8], pos: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, pos)
+}
+
+impl Read for PositionedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = read_at(&self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for PositionedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = write_at(&self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PositionedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let base = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(delta) => (self.pos, delta),
+            SeekFrom::End(delta) => (self.file.metadata()?.len(), delta),
+        };
+        self.pos = base.0.checked_add_signed(base.1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))?;
+        Ok(self.pos)
+    }
+}
+
+fn overlaps(a: (u64, u64), b: (u64, u64)) -> bool {
+    a.1 > 0 && b.1 > 0 && a.0 < b.0 + b.1 && b.0 < a.0 + a.1
+}
+
+/// Checks whether `later` has to wait for `earlier` to finish
+fn conflicts(earlier: &Region, later: &Region) -> bool {
+    let earlier_write = (earlier.addr, earlier.size);
+    let later_write = (later.addr, later.size);
+
+    overlaps(earlier_write, later_write)
+        || later.reads.iter().any(|&range| overlaps(earlier_write, range))
+        || earlier.reads.iter().any(|&range| overlaps(range, later_write))
+}
+
+/// Executes the layout on the file using up to `jobs` threads
+pub fn run(rpath: &path::Path, ctx: &mut Context, outf: &mut File, jobs: usize) -> Result<Vec<Region>> {
+    // Statements run once per slot, they are built one after another, and
+    // values peeked from the image are only known once it is written, as is
+    // `$IMAGE.end` at every statement and any checksum used by a directive
+    let statements = layout::statements(rpath, ctx)?;
+    let peeks = statements.iter()
+        .any(|(_, line)| {
+            (directive::is_directive(line) && (line.contains("peek_") || line.contains(".value") || line.contains(".digest")))
+                || line.contains("$IMAGE.")
+        });
+    // Plan regions are matched to statements by position, which skipped
+    // statements would shift
+    let guards = statements.iter()
+        .any(|(_, line)| Entry::from_str(line).is_ok_and(|entry| entry.guard.is_some()));
+    if peeks || guards || directive::slots(ctx, &statements)?.is_some() {
+        return layout::run(rpath, ctx, outf);
+    }
+
+    let mut plan_ctx = ctx.planning();
+    let plan = layout::run(rpath, &mut plan_ctx, &mut Cursor::new(Vec::new()))?;
+
+    let statements = statements
+        .into_iter()
+        .filter(|(_, line)| !directive::is_directive(line))
+        .collect::<Vec<_>>();
+    // The plan only has zeros for checksums, statements using one wait for
+    // the statement computing it and get the real value
+    let uses = statements.iter()
+        .map(|(_, line)| {
+            plan.iter()
+                .enumerate()
+                .filter(|(_, region)| layout::uses_checksum(line, &region.name))
+                .map(|(index, _)| index)
+                .collect::<Vec<usize>>()
+        })
+        .collect::<Vec<Vec<usize>>>();
+    if uses.iter().enumerate().any(|(index, used)| used.iter().any(|&other| other >= index)) {
+        return layout::run(rpath, ctx, outf);
+    }
+
+    let mut waves: Vec<usize> = Vec::with_capacity(plan.len());
+    for (index, region) in plan.iter().enumerate() {
+        let wave = plan[..index].iter()
+            .zip(&waves)
+            .enumerate()
+            .filter(|(other, (earlier, _))| conflicts(earlier, region) || uses[index].contains(other))
+            .map(|(_, (_, &wave))| wave + 1)
+            .max()
+            .unwrap_or(0);
+        waves.push(wave);
+    }
+
+    let mut results: Vec<Option<Region>> = plan.iter().map(|_| None).collect();
+    let mut digests: HashMap<String, String> = HashMap::new();
+    let last_wave = waves.iter().copied().max().unwrap_or(0);
+
+    for wave in 0..=last_wave {
+        let members = (0..plan.len())
+            .filter(|&index| waves[index] == wave)
+            .collect::<Vec<usize>>();
+
+        for batch in members.chunks(jobs.max(1)) {
+            let done = thread::scope(|scope| {
+                let handles = batch.iter()
+                    .map(|&index| {
+                        let (location, line) = &statements[index];
+                        let file = outf.try_clone();
+                        let mut worker_ctx = plan_ctx.planning();
+                        worker_ctx.dry_run = false;
+                        for region in results.iter().flatten() {
+                            if let Some(checksum) = region.checksum {
+                                worker_ctx.vars.insert(format!("{}.value", region.name), checksum);
+                            }
+                        }
+                        worker_ctx.strings.extend(digests.clone());
+                        let planned = &plan[index];
+                        let depends = !uses[index].is_empty();
+                        scope.spawn(move || -> Result<(usize, Region, Option<String>)> {
+                            let entry = Entry::from_str(line)?;
+                            let mut handle = PositionedFile { file: file?, pos: 0 };
+                            let region = layout::process_entry(&mut worker_ctx, &mut handle, &entry)
+                                .context(Location::clone(location))?;
+                            // Later statements were placed with the size
+                            // planned from the zero checksum
+                            if depends && (region.size != planned.size || region.reads != planned.reads) {
+                                return Err(anyhow!(
+                                    "The size of `{}` depends on a checksum, build the layout with --jobs 1",
+                                    entry.name
+                                ))
+                                .context(Location::clone(location));
+                            }
+                            let digest = worker_ctx.strings.remove(&format!("{}.digest", entry.name));
+                            Ok((index, region, digest))
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                handles.into_iter()
+                    .map(|handle| {
+                        handle.join()
+                            .unwrap_or_else(|_| Err(anyhow!("Worker thread panicked")))
+                    })
+                    .collect::<Result<Vec<(usize, Region, Option<String>)>>>()
+            })?;
+
+            for (index, region, digest) in done {
+                if let Some(digest) = digest {
+                    digests.insert(format!("{}.digest", region.name), digest);
+                }
+                results[index] = Some(region);
+            }
+        }
+    }
+
+    let regions = results.into_iter().flatten().collect::<Vec<Region>>();
+    if ctx.verbose >= 1 {
+        for region in &regions {
+            layout::trace_region(region);
+        }
+    }
+
+    ctx.vars = plan_ctx.vars;
+    ctx.strings = plan_ctx.strings;
+    // Checksums are only known after the statements were executed
+    for region in &regions {
+        if let Some(checksum) = region.checksum {
+            ctx.vars.insert(format!("{}.value", region.name), checksum);
+        }
+    }
+    ctx.strings.extend(digests);
+    ctx.warnings.append(&mut plan_ctx.warnings);
+
+    Ok(regions)
+}
@@ -0,0 +1,191 @@
+//! ESP-IDF partition tables.
+//!
+//! Every partition is a 32-byte entry, the entries are followed by one
+//! holding the MD5 of all previous ones and the rest of the table is filled
+//! with 0xff. A row in the layout is written as
+//! `LABEL TYPE SUBTYPE OFFSET SIZE [encrypted|readonly...]` like in the CSV
+//! files of ESP-IDF, sizes may use `K` and `M` suffixes.
+
+use anyhow::{anyhow, bail, Result};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+
+use crate::layout::{unpack_arg, LayoutError};
+
+/// Size of the flash area reserved for the table
+pub const TABLE_SIZE: u64 = 0xc00;
+
+const ENTRY_MAGIC: [u8; 2] = [0xaa, 0x50];
+const MD5_MAGIC: [u8; 2] = [0xeb, 0xeb];
+const LABEL_SIZE: usize = 16;
+
+/// A partition of the table
+pub struct Partition {
+    pub label: String,
+    kind: u8,
+    subtype: u8,
+    pub offset: u64,
+    pub size: u64,
+    flags: u32,
+}
+
+fn parse_kind(kind: &str) -> Result<u8> {
+    match kind {
+        "app" => Ok(0x00),
+        "data" => Ok(0x01),
+        _ => Ok(unpack_arg(&HashMap::new(), kind)?.try_into()?),
+    }
+}
+
+fn parse_subtype(kind: u8, subtype: &str) -> Result<u8> {
+    let known = match (kind, subtype) {
+        (0x00, "factory") => Some(0x00),
+        (0x00, "test") => Some(0x20),
+        (0x00, ota) if ota.starts_with("ota_") => {
+            let slot = ota["ota_".len()..].parse::<u8>()
+                .ok()
+                .filter(|&slot| slot < 16)
+                .ok_or_else(|| anyhow!("Invalid OTA slot '{}'", ota))?;
+            Some(0x10 + slot)
+        }
+        (0x01, "ota") => Some(0x00),
+        (0x01, "phy") => Some(0x01),
+        (0x01, "nvs") => Some(0x02),
+        (0x01, "coredump") => Some(0x03),
+        (0x01, "nvs_keys") => Some(0x04),
+        (0x01, "efuse") => Some(0x05),
+        (0x01, "undefined") => Some(0x06),
+        (0x01, "esphttpd") => Some(0x80),
+        (0x01, "fat") => Some(0x81),
+        (0x01, "spiffs") => Some(0x82),
+        (0x01, "littlefs") => Some(0x83),
+        _ => None,
+    };
+
+    match known {
+        Some(subtype) => Ok(subtype),
+        None => Ok(unpack_arg(&HashMap::new(), subtype)?.try_into()?),
+    }
+}
+
+/// Parses a size or offset, with an optional `K`, `M` or `G` suffix
+pub fn parse_size(vars: &HashMap<String, u64>, s: &str) -> Result<u64> {
+    let (number, unit) = if let Some(number) = s.strip_suffix(['K', 'k']) {
+        (number, 1024)
+    }
+    else if let Some(number) = s.strip_suffix(['M', 'm']) {
+        (number, 1024 * 1024)
+    }
+    else if let Some(number) = s.strip_suffix(['G', 'g']) {
+        (number, 1024 * 1024 * 1024)
+    }
+    else {
+        (s, 1)
+    };
+
+    unpack_arg(vars, number)?
+        .checked_mul(unit)
+        .ok_or_else(|| anyhow!("Size {} is too large", s))
+}
+
+impl Partition {
+    pub fn parse(vars: &HashMap<String, u64>, row: &str) -> Result<Partition> {
+        let fields = row.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() < 5 {
+            bail!(LayoutError::Syntax("Expected LABEL TYPE SUBTYPE OFFSET SIZE [FLAGS]"));
+        }
+        if fields[0].len() >= LABEL_SIZE {
+            bail!("Partition label '{}' is longer than {} characters", fields[0], LABEL_SIZE - 1);
+        }
+
+        let kind = parse_kind(fields[1])?;
+        let mut flags = 0;
+        for flag in &fields[5..] {
+            flags |= match *flag {
+                "encrypted" => 1,
+                "readonly" => 2,
+                _ => bail!("Unknown partition flag '{}'", flag),
+            };
+        }
+
+        Ok(Partition {
+            label: fields[0].to_string(),
+            kind,
+            subtype: parse_subtype(kind, fields[2])?,
+            offset: parse_size(vars, fields[3])?,
+            size: parse_size(vars, fields[4])?,
+            flags,
+        })
+    }
+}
+
+/// Builds the binary table of the partitions
+pub fn table(partitions: &[Partition]) -> Result<Vec<u8>> {
+    let mut table = Vec::new();
+
+    for partition in partitions {
+        let mut label = [0; LABEL_SIZE];
+        label[..partition.label.len()].copy_from_slice(partition.label.as_bytes());
+
+        table.extend_from_slice(&ENTRY_MAGIC);
+        table.push(partition.kind);
+        table.push(partition.subtype);
+        table.extend_from_slice(&u32::try_from(partition.offset)?.to_le_bytes());
+        table.extend_from_slice(&u32::try_from(partition.size)?.to_le_bytes());
+        table.extend_from_slice(&label);
+        table.extend_from_slice(&partition.flags.to_le_bytes());
+    }
+
+    let md5 = Md5::digest(&table);
+    table.extend_from_slice(&MD5_MAGIC);
+    table.extend_from_slice(&[0xff; 14]);
+    table.extend_from_slice(&md5);
+
+    if table.len() as u64 > TABLE_SIZE {
+        bail!("Too many partitions for a table of {} bytes", TABLE_SIZE);
+    }
+    table.resize(TABLE_SIZE as usize, 0xff);
+
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest;
+
+    /// The table of `partitions_singleapp.csv` of ESP-IDF
+    const SINGLE_APP: &[&str] = &[
+        "nvs data nvs 0x9000 0x6000",
+        "phy_init data phy 0xf000 0x1000",
+        "factory app factory 0x10000 1M",
+    ];
+
+    #[test]
+    fn entries() {
+        let partitions = SINGLE_APP.iter()
+            .map(|row| Partition::parse(&HashMap::new(), row))
+            .collect::<Result<Vec<Partition>>>()
+            .unwrap();
+        let table = table(&partitions).unwrap();
+
+        assert_eq!(table.len() as u64, TABLE_SIZE);
+        assert_eq!(&table[..16], &[0xaa, 0x50, 0x01, 0x02, 0x00, 0x90, 0x00, 0x00, 0x00, 0x60, 0x00, 0x00, b'n', b'v', b's', 0]);
+        assert_eq!(&table[64..76], &[0xaa, 0x50, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00]);
+        assert_eq!(&table[96..112], &[0xeb, 0xeb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(digest::to_hex(&table[112..128]), "f4ad4f4538564b5d7435b62c75b69524");
+        assert!(table[128..].iter().all(|&byte| byte == 0xff));
+    }
+
+    #[test]
+    fn rows() {
+        let partition = Partition::parse(&HashMap::new(), "ota_1 app ota_1 0x110000 1M readonly encrypted").unwrap();
+        assert_eq!((partition.kind, partition.subtype, partition.flags), (0x00, 0x11, 3));
+        assert_eq!(parse_size(&HashMap::new(), "64K").unwrap(), 0x10000);
+
+        assert!(Partition::parse(&HashMap::new(), "app app ota_16 0x10000 1M").is_err());
+        assert!(Partition::parse(&HashMap::new(), "a_label_too_long app factory 0x10000 1M").is_err());
+        assert!(Partition::parse(&HashMap::new(), "nvs data nvs 0x9000").is_err());
+    }
+}
@@ -0,0 +1,213 @@
+//! Layout functions provided by shared libraries given with `--plugin`,
+//! available with the `plugins` feature.
+//!
+//! A plugin exports two C functions:
+//!
+//! - `const char *bincomb_plugin_functions(void)` returns the names of the
+//!   functions it provides separated by spaces
+//! - `int bincomb_plugin_call(const struct bincomb_host *host,
+//!   const char *func, size_t argc, const char *const *argv)` executes a
+//!   statement and returns 0 on success
+//!
+//! Arguments are passed as text with variables already replaced by their
+//! values. The host structure, see `Host`, gives the address of the statement
+//! and callbacks to read the image, write the region and set `$NAME.value`.
+//! Everything written goes to the region at the statement address.
+
+use anyhow::{bail, Result};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::io::{Read, Seek, SeekFrom};
+use std::path;
+use std::slice;
+
+/// Version of the host structure passed to plugins
+pub const ABI_VERSION: u32 = 1;
+
+/// What a plugin gets to execute a statement
+#[repr(C)]
+pub struct Host {
+    pub version: u32,
+    /// Address of the statement
+    pub addr: u64,
+    /// Nonzero when only the size of the region is needed, the image reads
+    /// as zeros
+    pub dry_run: c_int,
+    pub handle: *mut c_void,
+    /// Reads `len` bytes of the image at `offset`, returns 0 on success
+    pub read: extern "C" fn(*mut c_void, u64, *mut u8, usize) -> c_int,
+    /// Appends the bytes to the region
+    pub write: extern "C" fn(*mut c_void, *const u8, usize),
+    /// Sets the checksum of the region, defined as `$NAME.value`
+    pub set_value: extern "C" fn(*mut c_void, u64),
+    /// Sets the message of the error the call is going to return
+    pub error: extern "C" fn(*mut c_void, *const c_char),
+}
+
+#[cfg(feature = "plugins")]
+type FunctionsFn = unsafe extern "C" fn() -> *const c_char;
+type CallFn = unsafe extern "C" fn(*const Host, *const c_char, usize, *const *const c_char) -> c_int;
+
+trait Image: Read + Seek {}
+
+impl<T: Read + Seek> Image for T {}
+
+/// State behind the handle of `Host` while a plugin runs
+struct Call<'a> {
+    image: &'a mut dyn Image,
+    dry_run: bool,
+    data: Vec<u8>,
+    reads: Vec<(u64, u64)>,
+    value: Option<u64>,
+    error: Option<String>,
+}
+
+fn call_state<'a, 'b>(handle: *mut c_void) -> &'a mut Call<'b> {
+    // Safety: the handle is the `Call` of the running `Plugin::call`
+    unsafe { &mut *(handle as *mut Call) }
+}
+
+extern "C" fn read(handle: *mut c_void, offset: u64, buf: *mut u8, len: usize) -> c_int {
+    let state = call_state(handle);
+    // Safety: the plugin passes a buffer of `len` bytes
+    let buf = unsafe { slice::from_raw_parts_mut(buf, len) };
+    state.reads.push((offset, len as u64));
+    if state.dry_run {
+        buf.fill(0);
+        return 0;
+    }
+    let result = state.image.seek(SeekFrom::Start(offset))
+        .and_then(|_| state.image.read_exact(buf));
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            state.error = Some(format!("Could not read 0x{:x} bytes at 0x{:x}: {}", len, offset, err));
+            -1
+        }
+    }
+}
+
+extern "C" fn write(handle: *mut c_void, data: *const u8, len: usize) {
+    // Safety: the plugin passes `len` bytes
+    call_state(handle).data.extend_from_slice(unsafe { slice::from_raw_parts(data, len) });
+}
+
+extern "C" fn set_value(handle: *mut c_void, value: u64) {
+    call_state(handle).value = Some(value);
+}
+
+extern "C" fn error(handle: *mut c_void, message: *const c_char) {
+    // Safety: the plugin passes a NUL-terminated string
+    let message = unsafe { CStr::from_ptr(message) };
+    call_state(handle).error = Some(message.to_string_lossy().into_owned());
+}
+
+/// A loaded plugin library
+pub struct Plugin {
+    path: path::PathBuf,
+    functions: Vec<String>,
+    call: CallFn,
+    #[cfg(feature = "plugins")]
+    _library: libloading::Library,
+}
+
+/// Region written by a plugin function
+pub struct Output {
+    pub data: Vec<u8>,
+    pub value: Option<u64>,
+    /// Ranges of the image the function has read
+    pub reads: Vec<(u64, u64)>,
+}
+
+impl Plugin {
+    /// Returns the path the plugin was loaded from
+    pub fn source(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    /// Checks whether the plugin provides the layout function
+    pub fn provides(&self, func: &str) -> bool {
+        self.functions.iter().any(|name| name == func)
+    }
+
+    /// Executes the function, the image is only read
+    pub fn call<F: Read + Seek>(
+        &self,
+        func: &str,
+        args: &[String],
+        addr: u64,
+        dry_run: bool,
+        image: &mut F,
+    ) -> Result<Output> {
+        let func_c = CString::new(func)?;
+        let args_c = args.iter()
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<Vec<CString>, _>>()?;
+        let argv = args_c.iter().map(|arg| arg.as_ptr()).collect::<Vec<*const c_char>>();
+
+        let mut state = Call {
+            image,
+            dry_run,
+            data: Vec::new(),
+            reads: Vec::new(),
+            value: None,
+            error: None,
+        };
+        let host = Host {
+            version: ABI_VERSION,
+            addr,
+            dry_run: dry_run.into(),
+            handle: &mut state as *mut Call as *mut c_void,
+            read,
+            write,
+            set_value,
+            error,
+        };
+
+        // Safety: the function was exported by the plugin with this
+        // signature, the host and arguments outlive the call
+        let status = unsafe { (self.call)(&host, func_c.as_ptr(), argv.len(), argv.as_ptr()) };
+        if status != 0 {
+            let message = state.error.take()
+                .unwrap_or_else(|| format!("failed with status {}", status));
+            bail!("Plugin `{}`: {}", self.path.display(), message);
+        }
+
+        Ok(Output { data: state.data, value: state.value, reads: state.reads })
+    }
+}
+
+/// Loads the plugin library
+#[cfg(feature = "plugins")]
+pub fn load(rpath: &path::Path) -> Result<Plugin> {
+    use anyhow::Context as _;
+
+    // Safety: plugins are trusted like the layout itself, loading runs
+    // their initializers
+    let library = unsafe { libloading::Library::new(rpath) }
+        .with_context(
+            || format!("could not load plugin `{}`", rpath.display())
+        )?;
+
+    // Safety: the symbols are declared with these signatures by the ABI
+    let (functions, call) = unsafe {
+        let functions = library.get::<FunctionsFn>(b"bincomb_plugin_functions\0")?;
+        let call = library.get::<CallFn>(b"bincomb_plugin_call\0")?;
+        let names = functions();
+        if names.is_null() {
+            bail!("Plugin `{}` provides no functions", rpath.display());
+        }
+        (CStr::from_ptr(names).to_string_lossy().into_owned(), *call)
+    };
+
+    Ok(Plugin {
+        path: rpath.to_path_buf(),
+        functions: functions.split_whitespace().map(str::to_string).collect(),
+        call,
+        _library: library,
+    })
+}
+
+#[cfg(not(feature = "plugins"))]
+pub fn load(_rpath: &path::Path) -> Result<Plugin> {
+    bail!("bincomb was built without the `plugins` feature")
+}
@@ -0,0 +1,103 @@
+//! Progress of long copies and downloads shown on stderr.
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// Transfers smaller than this finish too quickly to be worth reporting
+pub const THRESHOLD: u64 = 16 * 1024 * 1024;
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const BAR_WIDTH: usize = 30;
+
+/// A reader reporting how much of `total` bytes it has read so far
+pub struct Reader<R> {
+    inner: R,
+    label: String,
+    total: Option<u64>,
+    done: u64,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl<R> Reader<R> {
+    pub fn new(inner: R, label: &str, total: Option<u64>) -> Reader<R> {
+        Reader {
+            inner,
+            label: label.to_string(),
+            total,
+            done: 0,
+            started: Instant::now(),
+            drawn: None,
+        }
+    }
+
+    fn draw(&mut self) {
+        let elapsed = self.started.elapsed().as_secs_f64().max(0.001);
+        let rate = format!("{}/s", human(self.done as f64 / elapsed));
+        let line = match self.total {
+            Some(total) if total > 0 => {
+                let filled = (BAR_WIDTH as u64 * self.done.min(total) / total) as usize;
+                format!(
+                    "{} [{}{}] {:3}% {}/{} {}",
+                    self.label, "#".repeat(filled), " ".repeat(BAR_WIDTH - filled),
+                    100 * self.done.min(total) / total,
+                    human(self.done as f64), human(total as f64), rate
+                )
+            }
+            _ => format!("{} {} {}", self.label, human(self.done as f64), rate),
+        };
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r{}\x1b[K", line);
+        let _ = stderr.flush();
+        self.drawn = Some(Instant::now());
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.done += n as u64;
+        if self.drawn.is_none_or(|drawn| drawn.elapsed() >= REDRAW_INTERVAL) {
+            self.draw();
+        }
+        Ok(n)
+    }
+}
+
+impl<R> Drop for Reader<R> {
+    fn drop(&mut self) {
+        if self.drawn.is_some() {
+            self.draw();
+            eprintln!();
+        }
+    }
+}
+
+/// Checks whether a transfer of `total` bytes should report progress
+pub fn wanted(enabled: bool, total: Option<u64>) -> bool {
+    enabled && total.is_none_or(|total| total >= THRESHOLD)
+}
+
+/// Reads everything from the reader, reporting progress if `enabled`
+pub fn read_to_end<R: Read>(reader: R, label: &str, total: Option<u64>, enabled: bool) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    if wanted(enabled, total) {
+        Reader::new(reader, label, total).read_to_end(&mut data)?;
+    }
+    else {
+        let mut reader = reader;
+        reader.read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+fn human(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
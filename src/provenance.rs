@@ -0,0 +1,155 @@
+//! Manifests of what an image was built from, written with
+//! `--emit-provenance` and checked by `--locked`.
+//!
+//! A manifest lists the layout and its overlays, the defines and every region
+//! filled from a source such as a file, a git repository or a release asset,
+//! with the SHA-256 of the bytes it contributed to the image.
+
+use anyhow::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path;
+
+use crate::digest;
+use crate::layout::{CheckError, Region, Value};
+use crate::remote;
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Layout {
+    path: String,
+    sha256: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Input {
+    region: String,
+    source: String,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    layouts: Vec<Layout>,
+    defines: BTreeMap<String, String>,
+    inputs: Vec<Input>,
+}
+
+fn layout_sha256(rpath: &path::Path) -> Result<String> {
+    let hash = if remote::is_url(rpath) {
+        Sha256::digest(remote::fetch(rpath)?).to_vec()
+    }
+    else {
+        digest::sha256_file(rpath)?
+    };
+    Ok(digest::to_hex(&hash))
+}
+
+/// Describes the build of the image from the layouts, base first
+pub fn collect<F: Read + Seek>(
+    layouts: &[path::PathBuf],
+    defines: &[(String, Value)],
+    regions: &[Region],
+    image: &mut F,
+) -> Result<Manifest> {
+    let layouts = layouts.iter()
+        .map(|rpath| Ok(Layout {
+            path: rpath.display().to_string(),
+            sha256: layout_sha256(rpath)?,
+        }))
+        .collect::<Result<Vec<Layout>>>()?;
+
+    let mut inputs = Vec::new();
+    for region in regions {
+        let source = match &region.source {
+            Some(source) => source.clone(),
+            None => continue,
+        };
+        let mut hasher = Sha256::new();
+        image.seek(SeekFrom::Start(region.addr))?;
+        let copied = io::copy(&mut image.take(region.size), &mut hasher)?;
+        if copied != region.size {
+            bail!("Region {} ends past the end of the image", region.name);
+        }
+        inputs.push(Input {
+            region: region.name.clone(),
+            source,
+            size: region.size,
+            sha256: digest::to_hex(&hasher.finalize()),
+        });
+    }
+
+    Ok(Manifest {
+        layouts,
+        defines: defines.iter().map(|(name, value)| (name.clone(), value.to_string())).collect(),
+        inputs,
+    })
+}
+
+pub fn write(wpath: &path::Path, manifest: &Manifest) -> Result<()> {
+    let mut content = serde_json::to_string_pretty(manifest)?;
+    content.push('\n');
+    fs::write(wpath, content)
+        .with_context(
+            || format!("could not write file `{}`", wpath.display())
+        )
+}
+
+/// Checks the build against the manifest of an earlier one
+pub fn check(lpath: &path::Path, manifest: &Manifest) -> Result<()> {
+    let text = fs::read_to_string(lpath)
+        .with_context(
+            || format!("could not open file `{}`", lpath.display())
+        )?;
+    let locked: Manifest = serde_json::from_str(&text)
+        .with_context(
+            || format!("could not parse file `{}`", lpath.display())
+        )?;
+
+    let mismatch = |message: String| -> Result<()> {
+        Err(CheckError::Mismatch(format!("{}, locked by `{}`", message, lpath.display())).into())
+    };
+
+    if locked.layouts != manifest.layouts {
+        let paths = |layouts: &[Layout]| {
+            layouts.iter()
+                .map(|layout| layout.path.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        };
+        return mismatch(format!(
+            "Layouts {} differ from {}", paths(&manifest.layouts), paths(&locked.layouts)
+        ));
+    }
+    if locked.defines != manifest.defines {
+        let defines = |defines: &BTreeMap<String, String>| {
+            defines.iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        return mismatch(format!(
+            "Defines `{}` differ from `{}`", defines(&manifest.defines), defines(&locked.defines)
+        ));
+    }
+
+    for input in &manifest.inputs {
+        match locked.inputs.iter().find(|other| other.region == input.region) {
+            None => return mismatch(format!("Region {} is not locked", input.region)),
+            Some(other) if other != input => return mismatch(format!(
+                "Region {} has {} bytes from {} with SHA-256 {}, expected {} bytes from {} with {}",
+                input.region, input.size, input.source, input.sha256, other.size, other.source, other.sha256
+            )),
+            Some(_) => {}
+        }
+    }
+    let built = |other: &&Input| manifest.inputs.iter().any(|input| input.region == other.region);
+    if let Some(other) = locked.inputs.iter().find(|other| !built(other)) {
+        return mismatch(format!("Locked region {} is not built", other.region));
+    }
+
+    Ok(())
+}
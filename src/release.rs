@@ -0,0 +1,82 @@
+//! Input files attached to GitHub and GitLab releases.
+//!
+//! The project is given as `owner/repo` for GitHub or `host/group/project`
+//! for other hosts, which are assumed to run GitLab. Tokens are taken from
+//! the `GITHUB_TOKEN` and `GITLAB_TOKEN` environment variables.
+
+use anyhow::{anyhow, Context as _, Result};
+use serde_json::Value;
+use std::env;
+
+use crate::http;
+use crate::progress;
+
+/// Downloads the asset named `asset` from the release tagged `tag`
+pub fn fetch(project: &str, tag: &str, asset: &str, show_progress: bool) -> Result<Vec<u8>> {
+    let (host, path) = match project.split_once('/') {
+        Some((host, path)) if host.contains('.') => (host, path),
+        _ => ("github.com", project),
+    };
+
+    let request = if host == "github.com" {
+        github(path, tag, asset)
+    }
+    else {
+        gitlab(host, path, tag, asset)
+    };
+
+    request
+        .and_then(|request| Ok(http::call(request)?))
+        .and_then(|response| {
+            let total = response.header("content-length").and_then(|len| len.parse().ok());
+            Ok(progress::read_to_end(response.into_reader(), asset, total, show_progress)?)
+        })
+        .with_context(
+            || format!("Could not download {} from release {} of {}", asset, tag, project)
+        )
+}
+
+fn get_json(request: ureq::Request) -> Result<Value> {
+    Ok(serde_json::from_str(&http::call(request)?.into_string()?)?)
+}
+
+fn github(repo: &str, tag: &str, asset: &str) -> Result<ureq::Request> {
+    let token = env::var("GITHUB_TOKEN").ok();
+    let authorize = |request: ureq::Request| match &token {
+        Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+        None => request,
+    };
+
+    let url = format!("https://api.github.com/repos/{}/releases/tags/{}", repo, tag);
+    let release = get_json(authorize(http::get(&url)))?;
+    let url = release["assets"].as_array()
+        .into_iter()
+        .flatten()
+        .find(|item| item["name"] == asset)
+        .and_then(|item| item["url"].as_str())
+        .ok_or_else(|| anyhow!("Release has no asset named '{}'", asset))?;
+
+    Ok(authorize(http::get(url)).set("Accept", "application/octet-stream"))
+}
+
+fn gitlab(host: &str, project: &str, tag: &str, asset: &str) -> Result<ureq::Request> {
+    let token = env::var("GITLAB_TOKEN").ok();
+    let authorize = |request: ureq::Request| match &token {
+        Some(token) => request.set("PRIVATE-TOKEN", token),
+        None => request,
+    };
+
+    let url = format!(
+        "https://{}/api/v4/projects/{}/releases/{}",
+        host, project.replace('/', "%2F"), tag
+    );
+    let release = get_json(authorize(http::get(&url)))?;
+    let url = release["assets"]["links"].as_array()
+        .into_iter()
+        .flatten()
+        .find(|item| item["name"] == asset)
+        .and_then(|item| item["direct_asset_url"].as_str().or_else(|| item["url"].as_str()))
+        .ok_or_else(|| anyhow!("Release has no asset named '{}'", asset))?;
+
+    Ok(authorize(http::get(url)))
+}
@@ -0,0 +1,69 @@
+//! Layouts fetched over HTTP(S) when the layout argument is a URL such as
+//! `https://example.com/fw.bcl`. The URL may be pinned with a
+//! `#sha256=<hex>` fragment, the layout is then rejected unless it has that
+//! digest. Relative input paths of a remote layout are resolved against the
+//! current directory.
+
+use anyhow::{bail, Context as _, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path;
+use std::sync::{Mutex, OnceLock};
+
+use crate::digest;
+use crate::fixture;
+use crate::http;
+use crate::layout::CheckError;
+
+/// Layouts already fetched, a build reads the layout several times and must
+/// see the same text every time
+static FETCHED: OnceLock<Mutex<HashMap<String, Vec<u8>>>> = OnceLock::new();
+
+/// Checks whether the layout argument is an http(s) URL
+pub fn is_url(rpath: &path::Path) -> bool {
+    rpath.to_str()
+        .is_some_and(|text| text.starts_with("http://") || text.starts_with("https://"))
+}
+
+/// Downloads the layout once per process and checks its pinned digest
+pub fn fetch(rpath: &path::Path) -> Result<Vec<u8>> {
+    let text = rpath.to_string_lossy();
+    let fetched = FETCHED.get_or_init(Default::default);
+    if let Some(data) = fetched.lock().unwrap().get(text.as_ref()) {
+        return Ok(data.clone());
+    }
+
+    let (url, pinned) = match text.split_once('#') {
+        Some((url, fragment)) => match fragment.strip_prefix("sha256=") {
+            Some(hex) if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+                (url, Some(hex.to_ascii_lowercase()))
+            }
+            _ => bail!("Invalid layout URL fragment '#{}', expected #sha256=<64 hex digits>", fragment),
+        },
+        None => (text.as_ref(), None),
+    };
+
+    let data = fixture::fetch(url, || {
+        let mut data = Vec::new();
+        http::call(http::get(url))
+            .map_err(anyhow::Error::from)
+            .and_then(|response| Ok(response.into_reader().read_to_end(&mut data)?))
+            .with_context(
+                || format!("could not download layout `{}`", url)
+            )?;
+        Ok(data)
+    })?;
+
+    if let Some(hex) = pinned {
+        let actual = digest::to_hex(&Sha256::digest(&data));
+        if actual != hex {
+            bail!(CheckError::Mismatch(format!(
+                "Layout `{}` has SHA-256 {}, expected {}", url, actual, hex
+            )));
+        }
+    }
+
+    fetched.lock().unwrap().insert(text.into_owned(), data.clone());
+    Ok(data)
+}
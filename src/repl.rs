@@ -0,0 +1,112 @@
+use anyhow::{Context as _, Result};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Cursor, Read, Seek, Write};
+use std::path;
+
+use crate::directive;
+use crate::expr;
+use crate::layout::{self, Context, Entry, Memory, Value};
+
+const HELP: &str = "\
+Statements:   <offset>:<name>:<function>,<args...>
+Directives:   default <name> <value>
+              param <name>: <type>
+              struct <name> { <field>: <type>, ... }
+Expressions:  numbers and variables such as $NAME.start combined with
+              + - * / % << >> & | ^ ~ and parentheses, prints the value
+Functions:    read_u32(\"<file>\", <offset>)  peek_u32(<addr>)
+              len(<str>)  substr(<str>, <start>, <end>)  parse_int(<str>, <base>)
+Commands:     :vars  list variables
+              :help  show this help
+              :quit  leave the REPL";
+
+/// Reads statements and expressions from stdin and executes them against
+/// the image, or against an in-memory one if no path is given
+pub fn run(image: Option<&path::Path>, defines: &[(String, Value)]) -> Result<()> {
+    let mut ctx = Context::new(defines);
+
+    match image {
+        Some(wpath) => {
+            let mut outf = OpenOptions::new()
+                .write(true)
+                .read(true)
+                .create(true)
+                .truncate(false)
+                .open(wpath)
+                .with_context(
+                    || format!("could not open file `{}`", wpath.display())
+                )?;
+            interact(&mut ctx, &mut outf)
+        }
+        None => interact(&mut ctx, &mut Cursor::new(Vec::new())),
+    }
+}
+
+fn interact<F>(ctx: &mut Context, outf: &mut F) -> Result<()>
+where
+    F: Seek + Read + Write,
+{
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+
+    println!("bincomb REPL, type :help for help");
+
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+
+        let mut buf = String::new();
+        if input.read_line(&mut buf)? == 0 {
+            println!();
+            break;
+        }
+        let line = buf.trim();
+
+        match line {
+            "" => continue,
+            ":quit" | ":q" => break,
+            ":help" => println!("{}", HELP),
+            ":vars" => {
+                let mut names = ctx.vars.keys().collect::<Vec<&String>>();
+                names.sort();
+                for name in names {
+                    println!("${} = {} (0x{:x})", name, ctx.vars[name], ctx.vars[name]);
+                }
+                let mut names = ctx.strings.keys().collect::<Vec<&String>>();
+                names.sort();
+                for name in names {
+                    println!("${} = {:?}", name, ctx.strings[name]);
+                }
+            }
+            _ if directive::is_directive(line) => {
+                if let Some(Err(err)) = directive::execute(ctx, line, Some(&mut *outf)) {
+                    println!("Error: {:#}", err);
+                }
+            }
+            _ if line.contains(':') => {
+                let result = Entry::from_str(line)
+                    .and_then(|entry| match entry.enabled(ctx)? {
+                        true => layout::process_entry(ctx, outf, &entry).map(Some),
+                        false => Ok(None),
+                    });
+                match result {
+                    Ok(Some(region)) => println!(
+                        "{}: 0x{:08x}..0x{:08x} ({} bytes)",
+                        region.name, region.addr, region.end(), region.size
+                    ),
+                    Ok(None) => println!("Skipped"),
+                    Err(err) => println!("Error: {:#}", err),
+                }
+            }
+            _ => match expr::eval_value(line, &ctx.vars, Some(&mut Memory::new(ctx, Some(&mut *outf)))) {
+                Ok(Value::Int(value)) => println!("{} (0x{:x})", value, value),
+                Ok(Value::Str(value)) => println!("{:?}", value),
+                Err(err) => println!("Error: {:#}", err),
+            },
+        }
+    }
+
+    outf.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+//! Detached minisign signatures of inputs, checked when a `file`, `git`,
+//! `release` or `oci` statement has `verify=SIG` and `key=PUBKEY` arguments.
+//! Both are paths, the key is the `.pub` file written by `minisign -G`.
+
+use anyhow::{bail, Context as _, Result};
+use minisign_verify::{PublicKey, Signature};
+use std::fs;
+use std::path;
+
+use crate::layout::CheckError;
+
+/// The signature and the public key an input is verified with
+pub struct Signed {
+    pub signature: path::PathBuf,
+    pub key: path::PathBuf,
+}
+
+impl Signed {
+    /// Checks that the input named `name` was signed with the key
+    pub fn verify(&self, name: &str, data: &[u8]) -> Result<()> {
+        let key = fs::read_to_string(&self.key)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(PublicKey::decode(text.trim())?))
+            .with_context(
+                || format!("could not read public key `{}`", self.key.display())
+            )?;
+        let signature = fs::read_to_string(&self.signature)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(Signature::decode(&text)?))
+            .with_context(
+                || format!("could not read signature `{}`", self.signature.display())
+            )?;
+
+        // Signatures of the whole input made by minisign before 0.8 are
+        // accepted too
+        if let Err(err) = key.verify(data, &signature, true) {
+            bail!(CheckError::Mismatch(format!(
+                "Signature `{}` of {} does not verify with `{}`: {}",
+                self.signature.display(), name, self.key.display(), err
+            )));
+        }
+
+        Ok(())
+    }
+}
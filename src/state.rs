@@ -0,0 +1,156 @@
+//! Incremental builds: the digest of everything the image is built from is
+//! kept next to the output, a build is skipped when it didn't change.
+//!
+//! The inputs are those the last build resolved, a change of the layout or
+//! the defines that makes it read others changes the digest anyway.
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path;
+
+use crate::digest;
+use crate::layout::Value;
+use crate::remote;
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    digest: String,
+    output_size: u64,
+    /// SHA-256 of every input file and directory the build resolved
+    #[serde(default)]
+    inputs: BTreeMap<path::PathBuf, String>,
+    /// SHA-256 of the files written besides the output, such as `--map`
+    #[serde(default)]
+    outputs: BTreeMap<path::PathBuf, String>,
+}
+
+fn state_path(wpath: &path::Path) -> path::PathBuf {
+    let mut name = wpath.file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_default();
+    name.push(".bincomb.state");
+    wpath.with_file_name(name)
+}
+
+/// Computes the digest of the layout and its overlays, the defines, the
+/// options changing the output and the files they name, such as
+/// `--target`. The inputs the layout reads are checked by `up_to_date`.
+pub fn compute(
+    rpath: &path::Path,
+    overlays: &[path::PathBuf],
+    defines: &[(String, Value)],
+    options: &[String],
+    files: &[path::PathBuf],
+    expand_env: bool,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut texts = Vec::new();
+
+    if remote::is_url(rpath) {
+        texts.push(remote::fetch(rpath)?);
+    }
+    else {
+        texts.push(read(rpath)?);
+    }
+    for opath in overlays {
+        texts.push(read(opath)?);
+    }
+    for text in &texts {
+        hasher.update(Sha256::digest(text));
+    }
+
+    let mut sorted = defines.to_vec();
+    sorted.sort();
+    for (name, value) in sorted {
+        let line = format!("{}={}\n", name, value);
+        texts.push(line.clone().into_bytes());
+        hasher.update(line);
+    }
+    for option in options {
+        hasher.update(format!("{}\n", option));
+    }
+    for fpath in files {
+        hasher.update(fpath.to_string_lossy().as_bytes());
+        hasher.update(digest::sha256_input(fpath)?);
+    }
+
+    // The environment variables `${NAME}` is replaced with
+    if expand_env {
+        let mut names = texts.iter()
+            .flat_map(|text| environment_names(&String::from_utf8_lossy(text)))
+            .collect::<Vec<String>>();
+        names.sort();
+        names.dedup();
+        for name in names {
+            hasher.update(format!("${{{}}}={:?}\n", name, env::var_os(&name)));
+        }
+    }
+
+    Ok(digest::to_hex(&hasher.finalize()))
+}
+
+fn read(rpath: &path::Path) -> Result<Vec<u8>> {
+    fs::read(rpath)
+        .with_context(
+            || format!("could not read file `{}`", rpath.display())
+        )
+}
+
+/// Returns the names of the `${NAME}` references in the text
+fn environment_names(text: &str) -> Vec<String> {
+    text.split("${")
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}'))
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// Checks whether the output was built with the same digest from inputs
+/// which didn't change, and neither it nor the files written besides it
+/// were modified or removed since then
+pub fn up_to_date(wpath: &path::Path, digest: &str, outputs: &[path::PathBuf]) -> bool {
+    let state = fs::read(state_path(wpath))
+        .ok()
+        .and_then(|content| serde_json::from_slice::<State>(&content).ok());
+    let size = fs::metadata(wpath).map(|meta| meta.len()).ok();
+
+    match state {
+        Some(state) => {
+            state.digest == digest
+                && Some(state.output_size) == size
+                && state.inputs.iter().all(|(ipath, hash)| unchanged(ipath, hash))
+                && outputs.iter().all(|opath| state.outputs.get(opath).is_some_and(|hash| unchanged(opath, hash)))
+        }
+        None => false,
+    }
+}
+
+fn unchanged(fpath: &path::Path, hash: &str) -> bool {
+    digest::sha256_input(fpath).is_ok_and(|actual| digest::to_hex(&actual) == hash)
+}
+
+fn hashes(fpaths: &[path::PathBuf]) -> Result<BTreeMap<path::PathBuf, String>> {
+    fpaths.iter()
+        .map(|fpath| Ok((fpath.clone(), digest::to_hex(&digest::sha256_input(fpath)?))))
+        .collect()
+}
+
+/// Records the digest the output was built with, the inputs resolved by the
+/// build and the files it wrote besides the output
+pub fn save(wpath: &path::Path, digest: &str, inputs: &[path::PathBuf], outputs: &[path::PathBuf]) -> Result<()> {
+    let spath = state_path(wpath);
+    let state = State {
+        digest: digest.to_string(),
+        output_size: fs::metadata(wpath)?.len(),
+        inputs: hashes(inputs)?,
+        outputs: hashes(outputs)?,
+    };
+    fs::write(&spath, serde_json::to_vec(&state)?)
+        .with_context(
+            || format!("could not write file `{}`", spath.display())
+        )
+}
@@ -0,0 +1,41 @@
+//! Per-statement timing report printed by `--stats`.
+
+use std::time::Duration;
+
+use crate::layout::Region;
+
+/// Prints the time each statement took and the bytes it processed to stderr,
+/// bytes include both the data written and the ranges read for checksums
+pub fn print(regions: &[Region]) {
+    let width = regions.iter()
+        .map(|region| region.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("NAME".len());
+
+    eprintln!(
+        "{:<width$}  {:<8}  {:>12}  {:>10}  {:>12}  SOURCE",
+        "NAME", "FUNCTION", "BYTES", "TIME", "THROUGHPUT",
+        width = width
+    );
+
+    for region in regions {
+        let bytes = region.size + region.reads.iter().map(|&(_, length)| length).sum::<u64>();
+        let seconds = region.elapsed.as_secs_f64();
+        let throughput = if seconds > 0.0 {
+            format!("{:.1} MiB/s", bytes as f64 / seconds / (1024.0 * 1024.0))
+        }
+        else {
+            "-".to_string()
+        };
+        eprintln!(
+            "{:<width$}  {:<8}  {:>12}  {:>8.1}ms  {:>12}  {}",
+            region.name, region.func, bytes, seconds * 1000.0, throughput,
+            region.source.as_deref().unwrap_or("-"),
+            width = width
+        );
+    }
+
+    let total = regions.iter().map(|region| region.elapsed).sum::<Duration>();
+    eprintln!("Total time in statements: {:.1}ms", total.as_secs_f64() * 1000.0);
+}
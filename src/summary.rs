@@ -0,0 +1,91 @@
+//! The table of written regions printed at the end of a build.
+
+use clap::ValueEnum;
+use std::env;
+use std::io::{self, IsTerminal};
+use std::path;
+
+use crate::layout::Region;
+
+/// When to color the summary
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum ColorChoice {
+    /// If stderr is a terminal and `NO_COLOR` is not set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+const BOLD: &str = "1";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const CYAN: &str = "36";
+const DIM: &str = "2";
+
+/// Wraps the text, padded to `width`, in the SGR sequence if coloring
+fn paint(text: &str, width: usize, style: &str, color: bool) -> String {
+    let padded = format!("{:<width$}", text, width = width);
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", style, padded)
+    }
+    else {
+        padded
+    }
+}
+
+/// Prints the regions with their ranges, sources and checksums followed by
+/// the size and digest of the output to stderr
+pub fn print(regions: &[Region], wpath: &path::Path, size: u64, sha256: Option<&str>, color: bool) {
+    let rows = regions.iter()
+        .map(|region| [
+            region.name.clone(),
+            format!("0x{:08x}..0x{:08x}", region.addr, region.end()),
+            region.size.to_string(),
+            region.source.clone().unwrap_or_default(),
+            region.checksum.map(|value| format!("0x{:x}", value)).unwrap_or_default(),
+        ])
+        .collect::<Vec<[String; 5]>>();
+
+    let header = ["Region", "Range", "Size", "Source", "Checksum"];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let line = header.iter()
+        .zip(widths)
+        .map(|(title, width)| paint(title, width, BOLD, color))
+        .collect::<Vec<String>>();
+    eprintln!("{}", line.join("  ").trim_end());
+
+    for row in &rows {
+        let styles = [CYAN, "", "", DIM, YELLOW];
+        let line = row.iter()
+            .zip(widths)
+            .zip(styles)
+            .map(|((cell, width), style)| paint(cell, width, style, color && !style.is_empty()))
+            .collect::<Vec<String>>();
+        eprintln!("{}", line.join("  ").trim_end());
+    }
+
+    let done = format!(
+        "Successfully written {} regions ({} bytes) to `{}`",
+        regions.len(), size, wpath.display()
+    );
+    eprintln!("{}", paint(&done, 0, GREEN, color));
+    if let Some(sha256) = sha256 {
+        eprintln!("SHA-256: {}", sha256);
+    }
+}
@@ -0,0 +1,105 @@
+//! Arrays of records read from CSV or JSON files.
+//!
+//! Every row is encoded with the fields of a struct declared in the layout.
+//! CSV files have a header naming the fields, JSON files hold an array of
+//! objects. Integers may be written in hex with `0x`, bytes fields take
+//! text or `[hex bytes]`, fields a row leaves out are zeroed.
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use std::fs;
+use std::path;
+
+use crate::value::Value;
+use crate::fields::{self, FieldType, Struct};
+use crate::layout::parse_uint;
+
+/// The cells of a row by column name
+type Row = Vec<(String, String)>;
+
+/// Reads the rows of the file, the format is chosen by its extension
+pub fn read(rpath: &path::Path) -> Result<Vec<Row>> {
+    let text = fs::read_to_string(rpath)
+        .with_context(
+            || format!("could not open file `{}`", rpath.display())
+        )?;
+
+    let extension = rpath.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "csv" => read_csv(&text),
+        "json" => read_json(&text),
+        _ => bail!("Unknown format of `{}`, expected .csv or .json", rpath.display()),
+    }
+    .with_context(
+        || format!("could not parse file `{}`", rpath.display())
+    )
+}
+
+fn read_csv(text: &str) -> Result<Vec<Row>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+    let header = reader.headers()?.clone();
+
+    reader.records()
+        .map(|record| {
+            Ok(header.iter()
+                .zip(record?.iter())
+                .map(|(name, cell)| (name.to_string(), cell.to_string()))
+                .collect())
+        })
+        .collect()
+}
+
+fn read_json(text: &str) -> Result<Vec<Row>> {
+    let rows: Vec<serde_json::Map<String, serde_json::Value>> = serde_json::from_str(text)?;
+
+    rows.into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|(name, value)| {
+                    let cell = match value {
+                        serde_json::Value::Number(number) => number.to_string(),
+                        serde_json::Value::String(text) => text,
+                        _ => bail!("Field {} must be a number or a string", name),
+                    };
+                    Ok((name, cell))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn cell_value(kind: &FieldType, cell: &str) -> Result<Value> {
+    Ok(match kind {
+        FieldType::Int(_) | FieldType::Bits(..) => Value::Int(parse_uint(cell)?),
+        FieldType::Bytes(_) => match Value::parse_literal(cell)? {
+            Some(Value::Bytes(bytes)) => Value::Bytes(bytes),
+            _ => Value::Str(cell.to_string()),
+        },
+    })
+}
+
+/// Encodes the rows one after another
+pub fn encode(rows: &[Row], declared: &Struct) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+
+    for (index, row) in rows.iter().enumerate() {
+        let context = || format!("invalid row {}", index + 1);
+
+        if let Some((name, _)) = row.iter().find(|(name, _)| !declared.iter().any(|(field, _)| field == name)) {
+            return Err(anyhow!("Unknown field {}", name)).with_context(context);
+        }
+        let record = fields::encode_record(declared, |field, kind| {
+            row.iter()
+                .find(|(name, cell)| name == field && !cell.is_empty())
+                .map(|(_, cell)| cell_value(kind, cell))
+                .transpose()
+        });
+        out.extend(record.with_context(context)?);
+    }
+
+    Ok(out)
+}
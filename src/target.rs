@@ -0,0 +1,283 @@
+//! Flash geometry of the device an image is built for, loaded from a TOML
+//! file given with `--target`:
+//!
+//! ```toml
+//! size = "256K"
+//! erase_value = 0xff
+//! sectors = [{ size = "16K", count = 4 }, { size = "64K", count = 1 }, { size = "128K", count = 1 }]
+//! reserved = [{ name = "otp", start = 0x3c000, size = "16K" }]
+//! ```
+//!
+//! Offsets are relative to the start of the image. Sectors of a single size
+//! may be given as `sector_size` instead of a map.
+
+use anyhow::{bail, Context as _, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+
+use crate::layout::{CheckError, Region};
+use crate::partitions;
+
+/// A size in bytes, either a number or a string with a `K`, `M` or `G` suffix
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+impl Size {
+    fn bytes(&self) -> Result<u64> {
+        match self {
+            Size::Bytes(bytes) => Ok(*bytes),
+            Size::Text(text) => partitions::parse_size(&HashMap::new(), text),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSectors {
+    size: Size,
+    #[serde(default = "one")]
+    count: u64,
+}
+
+fn one() -> u64 {
+    1
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawReserved {
+    name: String,
+    start: Size,
+    size: Size,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTarget {
+    size: Option<Size>,
+    erase_value: Option<u8>,
+    sector_size: Option<Size>,
+    #[serde(default)]
+    sectors: Vec<RawSectors>,
+    #[serde(default)]
+    reserved: Vec<RawReserved>,
+}
+
+/// Where the sectors of the flash start
+#[derive(Clone)]
+enum Sectors {
+    Unknown,
+    Uniform(u64),
+    /// Offsets of every sector boundary in ascending order, including the
+    /// start and the end of the map
+    Map(Vec<u64>),
+}
+
+/// An area regions must not write to
+#[derive(Clone)]
+struct Reserved {
+    name: String,
+    start: u64,
+    size: u64,
+}
+
+#[derive(Clone)]
+pub struct Target {
+    pub size: Option<u64>,
+    pub erase_value: u8,
+    sectors: Sectors,
+    reserved: Vec<Reserved>,
+}
+
+impl Target {
+    /// A flash of unknown size with sectors of `sector_size` bytes
+    pub fn uniform(sector_size: u64) -> Result<Target> {
+        if sector_size == 0 {
+            bail!("Sector size must not be zero");
+        }
+        Ok(Target {
+            size: None,
+            erase_value: 0xff,
+            sectors: Sectors::Uniform(sector_size),
+            reserved: Vec::new(),
+        })
+    }
+
+    pub fn load(rpath: &path::Path) -> Result<Target> {
+        let text = fs::read_to_string(rpath)
+            .with_context(
+                || format!("could not open file `{}`", rpath.display())
+            )?;
+        let raw: RawTarget = toml::from_str(&text)
+            .with_context(
+                || format!("could not parse file `{}`", rpath.display())
+            )?;
+        Target::from_raw(raw)
+            .with_context(
+                || format!("invalid target `{}`", rpath.display())
+            )
+    }
+
+    fn from_raw(raw: RawTarget) -> Result<Target> {
+        let sectors = match (raw.sector_size, raw.sectors.is_empty()) {
+            (Some(_), false) => bail!("Expected either sector_size or sectors"),
+            (Some(size), true) => match size.bytes()? {
+                0 => bail!("Sector size must not be zero"),
+                size => Sectors::Uniform(size),
+            },
+            (None, true) => Sectors::Unknown,
+            (None, false) => {
+                let mut boundaries = vec![0];
+                let mut end = 0u64;
+                for run in &raw.sectors {
+                    let size = run.size.bytes()?;
+                    if size == 0 {
+                        bail!("Sector size must not be zero");
+                    }
+                    for _ in 0..run.count {
+                        end = end.checked_add(size)
+                            .context("Sector map exceeds the 64-bit address space")?;
+                        boundaries.push(end);
+                    }
+                }
+                Sectors::Map(boundaries)
+            }
+        };
+
+        let size = raw.size.as_ref().map(Size::bytes).transpose()?;
+        if let (Some(size), Sectors::Map(boundaries)) = (size, &sectors) {
+            if boundaries.last() != Some(&size) {
+                bail!("Sectors cover 0x{:x} bytes, but the flash has 0x{:x}", boundaries.last().unwrap_or(&0), size);
+            }
+        }
+
+        let reserved = raw.reserved.iter()
+            .map(|area| Ok(Reserved {
+                name: area.name.clone(),
+                start: area.start.bytes()?,
+                size: area.size.bytes()?,
+            }))
+            .collect::<Result<Vec<Reserved>>>()?;
+
+        Ok(Target {
+            size,
+            erase_value: raw.erase_value.unwrap_or(0xff),
+            sectors,
+            reserved,
+        })
+    }
+
+    fn is_boundary(&self, offset: u64) -> bool {
+        match &self.sectors {
+            Sectors::Unknown => true,
+            Sectors::Uniform(size) => offset.is_multiple_of(*size),
+            Sectors::Map(boundaries) => boundaries.binary_search(&offset).is_ok(),
+        }
+    }
+
+    /// Checks whether the region starts and ends at sector boundaries
+    pub fn aligned(&self, region: &Region) -> bool {
+        region.size == 0 || (self.is_boundary(region.addr) && self.is_boundary(region.end()))
+    }
+
+    /// Checks that the regions fit the flash and don't touch reserved areas
+    pub fn check(&self, regions: &[Region]) -> Result<()> {
+        for region in regions.iter().filter(|region| region.size > 0) {
+            if let Some(size) = self.size {
+                if region.end() > size {
+                    bail!(CheckError::Placement(format!(
+                        "Region {} at 0x{:x}..0x{:x} does not fit the flash of 0x{:x} bytes",
+                        region.name, region.addr, region.end(), size
+                    )));
+                }
+            }
+            for area in &self.reserved {
+                if region.addr < area.start.saturating_add(area.size) && area.start < region.end() {
+                    bail!(CheckError::Placement(format!(
+                        "Region {} at 0x{:x}..0x{:x} touches the reserved area {} at 0x{:x}..0x{:x}",
+                        region.name, region.addr, region.end(),
+                        area.name, area.start, area.start.saturating_add(area.size)
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// The target of the example in the module documentation
+    const EXAMPLE: &str = r#"
+size = "256K"
+erase_value = 0xff
+sectors = [{ size = "16K", count = 4 }, { size = "64K", count = 1 }, { size = "128K", count = 1 }]
+reserved = [{ name = "otp", start = 0x3c000, size = "16K" }]
+"#;
+
+    fn parse(text: &str) -> Result<Target> {
+        Target::from_raw(toml::from_str(text)?)
+    }
+
+    fn region(addr: u64, size: u64) -> Region {
+        Region {
+            name: "R".to_string(),
+            addr,
+            size,
+            func: "file".to_string(),
+            source: None,
+            checksum: None,
+            reads: Vec::new(),
+            elapsed: Duration::ZERO,
+            sealed: false,
+        }
+    }
+
+    #[test]
+    fn sector_maps() {
+        let target = parse(EXAMPLE).unwrap();
+        assert_eq!((target.size, target.erase_value), (Some(0x40000), 0xff));
+
+        assert!(target.aligned(&region(0x0, 0x4000)));
+        assert!(target.aligned(&region(0x4000, 0xc000)));
+        assert!(target.aligned(&region(0x10000, 0x10000)));
+        assert!(target.aligned(&region(0x20000, 0x20000)));
+        assert!(!target.aligned(&region(0x10000, 0x4000)));
+        assert!(!target.aligned(&region(0x2000, 0x2000)));
+        assert!(target.aligned(&region(0x2000, 0)));
+
+        let uniform = parse("sector_size = \"4K\"").unwrap();
+        assert!(uniform.aligned(&region(0x3000, 0x1000)));
+        assert!(!uniform.aligned(&region(0x3000, 0x800)));
+        assert_eq!(uniform.size, None);
+    }
+
+    #[test]
+    fn placement() {
+        let target = parse(EXAMPLE).unwrap();
+        assert!(target.check(&[region(0x0, 0x3c000)]).is_ok());
+        // Touching the reserved area or running past the end of the flash
+        assert!(target.check(&[region(0x3b000, 0x2000)]).is_err());
+        assert!(target.check(&[region(0x40000, 0x10)]).is_err());
+        // Empty regions write nothing
+        assert!(target.check(&[region(0x3c000, 0)]).is_ok());
+    }
+
+    #[test]
+    fn invalid_targets() {
+        assert!(parse("size = \"128K\"\nsectors = [{ size = \"64K\", count = 1 }]").is_err());
+        assert!(parse("sector_size = 0").is_err());
+        assert!(parse("sector_size = 4096\nsectors = [{ size = 4096 }]").is_err());
+        assert!(parse("sector_count = 4").is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use clap::ValueEnum;
+
+/// Starter layouts written by the `init` subcommand
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Template {
+    /// A single application followed by its CRC
+    SingleAppCrc,
+    /// A bootloader and two application slots for OTA updates
+    Stm32DualSlot,
+    /// A fixed size header followed by a payload
+    HeaderPayload,
+}
+
+impl Template {
+    pub fn content(self) -> &'static str {
+        match self {
+            Template::SingleAppCrc => SINGLE_APP_CRC,
+            Template::Stm32DualSlot => STM32_DUAL_SLOT,
+            Template::HeaderPayload => HEADER_PAYLOAD,
+        }
+    }
+}
+
+const SINGLE_APP_CRC: &str = "\
+# Single application with a CRC-16 at the end of its slot
+#
+# Every statement is `<offset>:<name>:<function>,<args...>` and defines the
+# variables $<name>.start and $<name>.size usable by the following statements.
+
+# The application binary placed at the start of the image
+0x00000:APP:file,app.bin
+
+# CRC-16 of the application stored in the last two bytes of the 256K slot
+0x3fffe:APP_CRC:crc16,$APP.start,$APP.size
+";
+
+const STM32_DUAL_SLOT: &str = "\
+# STM32 bootloader with two application slots for OTA updates
+#
+# Every statement is `<offset>:<name>:<function>,<args...>` and defines the
+# variables $<name>.start and $<name>.size usable by the following statements.
+#
+# 0x00000 - 0x07fff  bootloader (32K)
+# 0x08000 - 0x3ffff  slot A (224K), CRC-16 in the last two bytes
+# 0x40000 - 0x77fff  slot B (224K), CRC-16 in the last two bytes
+
+0x00000:BOOT:file,bootloader.bin
+
+0x08000:SLOT_A:file,app.bin
+0x3fffe:SLOT_A_CRC:crc16,$SLOT_A.start,$SLOT_A.size
+
+0x40000:SLOT_B:file,app.bin
+0x77ffe:SLOT_B_CRC:crc16,$SLOT_B.start,$SLOT_B.size
+";
+
+const HEADER_PAYLOAD: &str = "\
+# Fixed size header followed by a payload
+#
+# Every statement is `<offset>:<name>:<function>,<args...>` and defines the
+# variables $<name>.start and $<name>.size usable by the following statements.
+
+# The header occupies the first 256 bytes, its last two bytes are the CRC
+0x000:HEADER:file,header.bin
+
+# The payload follows the header
+0x100:PAYLOAD:file,payload.bin
+
+# CRC-16 of the payload stored at the end of the header
+0x0fe:PAYLOAD_CRC:crc16,$PAYLOAD.start,$PAYLOAD.size
+";
@@ -0,0 +1,457 @@
+//! Inputs downloaded from http(s) URLs by the `url` function, or from S3
+//! buckets as described in `s3`.
+//!
+//! The URLs of every `url` statement are downloaded at the same time before
+//! the layout is interpreted, which otherwise downloads them one after
+//! another as it reaches them.
+//!
+//! Downloads are kept in a cache directory keyed by the SHA-256 of the URL,
+//! along with the `ETag` and `Last-Modified` headers of the response. A
+//! cached download is revalidated with `If-None-Match` and
+//! `If-Modified-Since` and only downloaded again when it changed.
+//!
+//! When the connection breaks during a download, the rest of the file is
+//! requested with `Range` and `If-Range` as long as `--retries` are left.
+//! The bytes received so far are kept as `KEY.part` in the cache, a later
+//! build resumes the download if it failed anyway. Servers without `ETag`
+//! or `Last-Modified` can't tell whether the file changed, their downloads
+//! are started again.
+//!
+//! Requests carry the `--http-header` headers and those given to the
+//! statement. Unless one of them is `Authorization`, the login of the host
+//! in `~/.netrc`, or the file named by `NETRC`, is sent with basic
+//! authentication.
+
+use anyhow::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path;
+use std::process;
+use std::sync::OnceLock;
+use std::thread;
+
+use crate::digest;
+use crate::fixture;
+use crate::http;
+use crate::progress;
+use crate::s3;
+
+/// Names and values of request headers
+pub type Headers = Vec<(String, String)>;
+
+pub struct Options {
+    /// Headers sent with every download
+    pub headers: Headers,
+    /// Directory of the cached downloads, none to download every time
+    pub cache: Option<path::PathBuf>,
+    /// Download again instead of revalidating the cached downloads
+    pub refresh: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            headers: Vec::new(),
+            cache: default_cache(),
+            refresh: false,
+        }
+    }
+}
+
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+
+/// Sets the options for the rest of the process
+pub fn configure(options: Options) {
+    let _ = OPTIONS.set(options);
+}
+
+fn options() -> &'static Options {
+    OPTIONS.get_or_init(Default::default)
+}
+
+/// Returns `bincomb/downloads` in the cache directory of the user
+pub fn default_cache() -> Option<path::PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(path::PathBuf::from)
+        .or_else(|| env::var_os("LOCALAPPDATA").map(path::PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| path::Path::new(&home).join(".cache")))?;
+    Some(base.join("bincomb").join("downloads"))
+}
+
+/// What is known about a cached download, kept as `KEY.json` next to it
+#[derive(Serialize, Deserialize)]
+struct Cached {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// SHA-256 of the download, a damaged one is downloaded again
+    sha256: String,
+}
+
+/// The file of an interrupted download, kept as `KEY.part.json` next to the
+/// bytes received
+#[derive(Serialize, Deserialize)]
+struct Partial {
+    url: String,
+    /// `ETag` or `Last-Modified` of the response the bytes are from
+    validator: String,
+}
+
+/// Name of the download in the fixtures and the cache of the build
+pub fn name(url: &str) -> String {
+    format!("url:{}", url)
+}
+
+/// Parses a `NAME: VALUE` header
+pub fn parse_header(s: &str) -> Result<(String, String)> {
+    match s.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => bail!("Expected a header as NAME: VALUE instead of '{}'", s),
+    }
+}
+
+/// Downloads the file at the URL with the headers, or takes it from the
+/// cache if the server reports that it didn't change
+pub fn fetch(url: &str, headers: &[(String, String)], show_progress: bool) -> Result<Vec<u8>> {
+    let options = options();
+    let result = start(url, headers).and_then(|request| match &options.cache {
+        Some(dir) => fetch_cached(request, url, dir, options.refresh, show_progress),
+        None => http::call(request.clone())
+            .map_err(anyhow::Error::from)
+            .and_then(|response| receive(&request, response, Vec::new(), None, url, show_progress)),
+    });
+    result
+        .with_context(
+            || format!("Could not download {}", url)
+        )
+}
+
+/// Starts the request of the URL, or the signed one of the object an
+/// `s3://` URL names
+fn start(url: &str, headers: &[(String, String)]) -> Result<ureq::Request> {
+    if !s3::is_s3(url) {
+        return Ok(request(url, headers));
+    }
+    let (location, mut signed) = s3::request(url)?;
+    signed.extend_from_slice(headers);
+    Ok(request(&location, &signed))
+}
+
+/// Starts the request with the headers of the options, then the given
+/// ones, or the netrc login of the host without an `Authorization` header
+fn request(url: &str, headers: &[(String, String)]) -> ureq::Request {
+    let mut request = http::get(url);
+    for (name, value) in options().headers.iter().chain(headers) {
+        request = request.set(name, value);
+    }
+    if request.header("authorization").is_none() {
+        let login = request.request_url()
+            .ok()
+            .and_then(|parsed| netrc(parsed.host()));
+        if let Some((user, password)) = login {
+            request = request.set("Authorization", &http::basic_auth(&user, &password));
+        }
+    }
+    request
+}
+
+/// Returns the login and password of the host in the netrc file
+fn netrc(host: &str) -> Option<(String, String)> {
+    let npath = env::var_os("NETRC")
+        .map(path::PathBuf::from)
+        .or_else(|| {
+            let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+            Some(path::Path::new(&home).join(if cfg!(windows) { "_netrc" } else { ".netrc" }))
+        })?;
+    parse_netrc(&fs::read_to_string(npath).ok()?, host)
+}
+
+/// Finds the login of the `machine`, or else the `default` entry, of a
+/// netrc file
+fn parse_netrc(text: &str, host: &str) -> Option<(String, String)> {
+    let mut matching = false;
+    let mut login = None;
+    let mut password = None;
+    let mut in_macro = false;
+
+    for line in text.lines() {
+        // A macro definition runs until the next empty line
+        if in_macro {
+            in_macro = !line.trim().is_empty();
+            continue;
+        }
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" | "default" => {
+                    if let (true, Some(login), Some(password)) = (matching, login.take(), password.take()) {
+                        return Some((login, password));
+                    }
+                    matching = token == "default" || tokens.next() == Some(host);
+                }
+                "login" => login = tokens.next().map(str::to_string),
+                "password" => password = tokens.next().map(str::to_string),
+                "account" => {
+                    tokens.next();
+                }
+                "macdef" => {
+                    in_macro = true;
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    match (matching, login, password) {
+        (true, Some(login), Some(password)) => Some((login, password)),
+        _ => None,
+    }
+}
+
+/// Returns what tells whether the file changed since the response, to send
+/// as `If-Range`. Weak ETags can't be used for ranges.
+fn validator(response: &ureq::Response) -> Option<String> {
+    response.header("etag")
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| response.header("last-modified"))
+        .map(str::to_string)
+}
+
+/// Reads the body of the response to the request after the bytes of `data`
+/// if it is partial, or else instead of them. When the connection breaks,
+/// the rest is requested with `Range` while retries are left. The bytes
+/// are also written to `part` for a later build to resume the download.
+fn receive(
+    request: &ureq::Request,
+    mut response: ureq::Response,
+    mut data: Vec<u8>,
+    mut part: Option<&mut File>,
+    url: &str,
+    show_progress: bool,
+) -> Result<Vec<u8>> {
+    let label = url.rsplit('/').next().unwrap_or(url);
+    let mut attempt = 0;
+    loop {
+        if response.status() != 206 {
+            data.clear();
+            if let Some(part) = part.as_deref_mut() {
+                part.set_len(0)?;
+                part.seek(SeekFrom::Start(0))?;
+            }
+        }
+        else if range_start(&response) != Some(data.len() as u64) {
+            bail!("The server sent another range than the one requested");
+        }
+
+        let validator = validator(&response);
+        let total = response.header("content-length").and_then(|len| len.parse().ok());
+        let reader = response.into_reader();
+        let result = if progress::wanted(show_progress, total) {
+            append(progress::Reader::new(reader, label, total), &mut data, part.as_deref_mut())
+        }
+        else {
+            append(reader, &mut data, part.as_deref_mut())
+        };
+
+        match (result, validator) {
+            (Ok(()), _) => return Ok(data),
+            (Err(err), Some(validator)) if http::wait_retry(attempt, &err) => {
+                attempt += 1;
+                let resume = request.clone()
+                    .set("Range", &format!("bytes={}-", data.len()))
+                    .set("If-Range", &validator);
+                response = http::call(resume)?;
+            }
+            (Err(err), _) => return Err(err.into()),
+        }
+    }
+}
+
+/// Returns the first byte of a `206 Partial Content` response
+fn range_start(response: &ureq::Response) -> Option<u64> {
+    response.header("content-range")?
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Appends everything read to `data` and `part`
+fn append<R: Read>(mut reader: R, data: &mut Vec<u8>, mut part: Option<&mut File>) -> io::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        data.extend_from_slice(&buf[..n]);
+        if let Some(part) = part.as_deref_mut() {
+            part.write_all(&buf[..n])?;
+        }
+    }
+}
+
+fn fetch_cached(
+    request: ureq::Request,
+    url: &str,
+    dir: &path::Path,
+    refresh: bool,
+    show_progress: bool,
+) -> Result<Vec<u8>> {
+    let key = digest::to_hex(&Sha256::digest(url.as_bytes()));
+    let (cached, partial) = if refresh {
+        (None, None)
+    }
+    else {
+        (read_cached(dir, &key), read_partial(dir, &key))
+    };
+
+    let mut first = request.clone();
+    if let Some((info, _)) = &cached {
+        if let Some(etag) = &info.etag {
+            first = first.set("If-None-Match", etag);
+        }
+        if let Some(date) = &info.last_modified {
+            first = first.set("If-Modified-Since", date);
+        }
+    }
+    if let Some((info, data)) = &partial {
+        first = first
+            .set("Range", &format!("bytes={}-", data.len()))
+            .set("If-Range", &info.validator);
+    }
+    let response = http::call(first)?;
+    if response.status() == 304 {
+        match cached {
+            Some((_, data)) => return Ok(data),
+            None => bail!("The server answered 304 Not Modified to an unconditional request"),
+        }
+    }
+
+    let etag = response.header("etag").map(str::to_string);
+    let last_modified = response.header("last-modified").map(str::to_string);
+    // Without either header the download can't be revalidated or resumed
+    let part = match validator(&response) {
+        Some(validator) => open_part(dir, &key, url, &validator).ok(),
+        None => None,
+    };
+    let data = match part {
+        Some(mut part) => {
+            let received = partial.map(|(_, data)| data).unwrap_or_default();
+            receive(&request, response, received, Some(&mut part), url, show_progress)?
+        }
+        None => receive(&request, response, Vec::new(), None, url, show_progress)?,
+    };
+
+    if etag.is_some() || last_modified.is_some() {
+        let info = Cached {
+            url: url.to_string(),
+            etag,
+            last_modified,
+            sha256: digest::to_hex(&Sha256::digest(&data)),
+        };
+        // The cache only saves downloads, failing to update it fails nothing
+        let _ = store(dir, &key, &info, &data);
+    }
+    let _ = fs::remove_file(dir.join(format!("{}.part", key)));
+    let _ = fs::remove_file(dir.join(format!("{}.part.json", key)));
+    Ok(data)
+}
+
+/// Returns the bytes an interrupted download received and the response
+/// they are from
+fn read_partial(dir: &path::Path, key: &str) -> Option<(Partial, Vec<u8>)> {
+    let info: Partial = serde_json::from_slice(&fs::read(dir.join(format!("{}.part.json", key))).ok()?).ok()?;
+    let data = fs::read(dir.join(format!("{}.part", key))).ok()?;
+    Some((info, data)).filter(|(_, data)| !data.is_empty())
+}
+
+/// Opens the file keeping the bytes of the download at its end, after
+/// recording the response they are from
+fn open_part(dir: &path::Path, key: &str, url: &str, validator: &str) -> Result<File> {
+    fs::create_dir_all(dir)?;
+    let info = Partial { url: url.to_string(), validator: validator.to_string() };
+    fs::write(dir.join(format!("{}.part.json", key)), serde_json::to_vec(&info)?)?;
+    let mut part = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(format!("{}.part", key)))?;
+    part.seek(SeekFrom::End(0))?;
+    Ok(part)
+}
+
+/// Returns the cached download and what is known about it, none unless it
+/// is complete
+fn read_cached(dir: &path::Path, key: &str) -> Option<(Cached, Vec<u8>)> {
+    let info: Cached = serde_json::from_slice(&fs::read(dir.join(format!("{}.json", key))).ok()?).ok()?;
+    let data = fs::read(dir.join(key)).ok()?;
+    if digest::to_hex(&Sha256::digest(&data)) != info.sha256 {
+        return None;
+    }
+    Some((info, data))
+}
+
+fn store(dir: &path::Path, key: &str, info: &Cached, data: &[u8]) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    // Renamed into place so that other builds never see a partial file
+    let replace = |name: String, content: &[u8]| -> Result<()> {
+        let tpath = dir.join(format!("{}.{}.tmp", name, process::id()));
+        fs::write(&tpath, content)?;
+        fs::rename(&tpath, dir.join(name))?;
+        Ok(())
+    };
+    replace(key.to_string(), data)?;
+    replace(format!("{}.json", key), &serde_json::to_vec(info)?)
+}
+
+/// Downloads the URLs with their headers at the same time. Failed downloads
+/// are left for the statements using them to report, they are retried then.
+pub fn prefetch(downloads: &[(String, Headers)]) {
+    thread::scope(|scope| {
+        for (url, headers) in downloads {
+            // Progress bars of several downloads would overwrite each other
+            scope.spawn(move || fixture::fetch(&name(url), || fetch(url, headers, false)));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn netrc_logins() {
+        let text = "machine a.example.com login alice password one
+machine b.example.com
+    login bob
+    password two
+macdef init
+    machine c.example.com login mallory password three
+
+default login anonymous password guest
+";
+        let login = |host| parse_netrc(text, host);
+        assert_eq!(login("a.example.com"), Some(("alice".to_string(), "one".to_string())));
+        assert_eq!(login("b.example.com"), Some(("bob".to_string(), "two".to_string())));
+        assert_eq!(login("c.example.com"), Some(("anonymous".to_string(), "guest".to_string())));
+        assert_eq!(parse_netrc("machine a.example.com login alice password one", "b.example.com"), None);
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(parse_header("Authorization: Bearer a:b").unwrap(), ("Authorization".to_string(), "Bearer a:b".to_string()));
+        assert!(parse_header("Authorization").is_err());
+        assert!(parse_header(": value").is_err());
+    }
+}
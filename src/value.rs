@@ -0,0 +1,98 @@
+//! Typed literals of function arguments: `"strings"`, `[bytes]` in hex
+//! like `[00 11 22 aa bb cc]` and raw byte strings like `b"\x7fELF\0"`.
+//! Numbers and variables are left to the caller.
+
+use anyhow::{anyhow, bail, Result};
+
+/// A typed argument such as a field of a struct or a property of a device
+/// tree
+pub enum Value {
+    Int(u64),
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    /// Parses a literal value, numbers are parsed by the caller
+    pub fn parse_literal(s: &str) -> Result<Option<Value>> {
+        if let Some(bytes) = parse_byte_string(s)? {
+            return Ok(Some(Value::Bytes(bytes)));
+        }
+        if let Some(text) = s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            return Ok(Some(Value::Str(text.to_string())));
+        }
+        if let Some(hex) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            let bytes = hex.split_whitespace()
+                .map(|byte| u8::from_str_radix(byte, 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| anyhow!("Invalid byte string {}", s))?;
+            return Ok(Some(Value::Bytes(bytes)));
+        }
+        Ok(None)
+    }
+}
+
+/// Parses a `b"..."` literal, whose bytes are taken verbatim except for
+/// the escapes `\xNN`, `\0`, `\n`, `\r`, `\t`, `\\` and `\"`
+pub fn parse_byte_string(s: &str) -> Result<Option<Vec<u8>>> {
+    let text = match s.strip_prefix("b\"").and_then(|s| s.strip_suffix('"')) {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        let (&escape, after) = rest.split_first()
+            .ok_or_else(|| anyhow!("Byte string {} ends with a backslash", s))?;
+        rest = after;
+        bytes.push(match escape {
+            b'x' => {
+                let hex = rest.get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| anyhow!("Expected two hex digits after \\x in {}", s))?;
+                rest = &rest[2..];
+                hex
+            }
+            b'0' => 0,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'\\' | b'"' => escape,
+            _ => bail!("Unknown escape \\{} in {}", escape as char, s),
+        });
+    }
+
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_strings() {
+        assert_eq!(parse_byte_string(r#"b"\x7fELF\0""#).unwrap().unwrap(), b"\x7fELF\0");
+        assert_eq!(parse_byte_string(r#"b"a,b\"\\\n""#).unwrap().unwrap(), b"a,b\"\\\n");
+        assert_eq!(parse_byte_string(r#"b"""#).unwrap().unwrap(), b"");
+        assert!(parse_byte_string(r#""text""#).unwrap().is_none());
+        assert!(parse_byte_string(r#"b"\x7""#).is_err());
+        assert!(parse_byte_string(r#"b"\q""#).is_err());
+        assert!(parse_byte_string(r#"b"\""#).is_err());
+    }
+
+    #[test]
+    fn literals() {
+        assert!(matches!(Value::parse_literal(r#""a, b""#).unwrap(), Some(Value::Str(text)) if text == "a, b"));
+        assert!(matches!(Value::parse_literal("[00 ff]").unwrap(), Some(Value::Bytes(bytes)) if bytes == [0x00, 0xff]));
+        assert!(matches!(Value::parse_literal(r#"b"\x01""#).unwrap(), Some(Value::Bytes(bytes)) if bytes == [0x01]));
+        assert!(Value::parse_literal("0x10").unwrap().is_none());
+        assert!(Value::parse_literal("[0g]").is_err());
+    }
+}
@@ -0,0 +1,527 @@
+//! Runs the `bincomb` binary on layouts written to temporary directories
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path;
+use std::process::{Command, Output};
+use std::sync::Arc;
+use std::thread;
+
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+/// Creates an empty directory for the test with the files in it
+fn workdir(test: &str, files: &[(&str, &[u8])]) -> path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("bincomb-test-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (name, data) in files {
+        let fpath = dir.join(name);
+        fs::create_dir_all(fpath.parent().unwrap()).unwrap();
+        fs::write(fpath, data).unwrap();
+    }
+    dir
+}
+
+fn bincomb(dir: &path::Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bincomb"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "exit status {}\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Reads the request line and headers sent on the connection
+fn read_request(stream: &TcpStream) -> String {
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    while reader.read_line(&mut request).unwrap() > 2 {}
+    request
+}
+
+/// Answers `count` connections one after another with the response `answer`
+/// returns for the request, giving the base URL and the requests received
+fn serve<F>(count: usize, answer: F) -> (String, thread::JoinHandle<Vec<String>>)
+where
+    F: Fn(&str) -> Vec<u8> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        (0..count)
+            .map(|_| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let request = read_request(&stream);
+                let _ = stream.write_all(&answer(&request));
+                request
+            })
+            .collect()
+    });
+    (base, server)
+}
+
+/// A response with the status, extra headers and body
+fn response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status, headers, body.len()
+    ).into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+fn ok(body: &[u8]) -> Vec<u8> {
+    response("200 OK", "", body)
+}
+
+const CHECKSUM_LAYOUT: &[u8] = b"0x0:A:file,a.bin
+0x100:C16:crc16,0x0,4
+0x110:HEX:u32_hex,$C16.value
+0x120:D:digest_var,0x0,4
+0x130:DIGEST:cstring,$D.digest,72
+";
+
+#[test]
+fn dry_run_uses_checksums() {
+    let dir = workdir("dry-run", &[("a.bin", b"abcdefgh"), ("layout.txt", CHECKSUM_LAYOUT)]);
+    let output = bincomb(&dir, &["--dry-run", "layout.txt", "out.bin"]);
+    assert_success(&output);
+    assert!(!dir.join("out.bin").exists());
+}
+
+#[test]
+fn check_uses_checksums() {
+    let dir = workdir("check", &[("a.bin", b"abcdefgh"), ("layout.txt", CHECKSUM_LAYOUT)]);
+    assert_success(&bincomb(&dir, &["check", "layout.txt"]));
+}
+
+#[test]
+fn bootcheck_crc_headers() {
+    let dir = workdir("bootcheck", &[("a.bin", b"abcdefgh"), ("layout.txt", CHECKSUM_LAYOUT)]);
+    assert_success(&bincomb(&dir, &["-q", "layout.txt", "out.bin"]));
+    let output = bincomb(&dir, &["bootcheck", "--profile", "crc-header", "layout.txt", "out.bin"]);
+    assert_success(&output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("C16: CRC of 0x0..0x4"));
+
+    // A changed byte of the checked data
+    let mut image = fs::read(dir.join("out.bin")).unwrap();
+    image[2] ^= 1;
+    fs::write(dir.join("corrupt.bin"), image).unwrap();
+    let output = bincomb(&dir, &["bootcheck", "--profile", "crc-header", "layout.txt", "corrupt.bin"]);
+    assert_eq!(output.status.code(), Some(6));
+
+    // The layout has nothing MCUboot checks
+    let output = bincomb(&dir, &["bootcheck", "--profile", "mcuboot", "layout.txt", "out.bin"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn check_allows_forward_references() {
+    let dir = workdir("check-forward", &[
+        ("a.bin", b"abcdefgh"),
+        ("layout.txt", b"0x0:HDR:u32_hex,$APP.size\n0x10:APP:file,a.bin\n"),
+    ]);
+    assert_success(&bincomb(&dir, &["check", "layout.txt"]));
+}
+
+#[test]
+fn check_skips_downloads_and_digests() {
+    let layout = format!(
+        "0x0:A:file,a.bin,sha256:{}\n0x10:G:git,http://127.0.0.1:9/repo.git,main,fw.bin\n0x20:R:release,owner/repo,v1,fw.bin\n",
+        "0".repeat(64)
+    );
+    let dir = workdir("check-offline", &[("a.bin", b"abcdefgh"), ("layout.txt", layout.as_bytes())]);
+    assert_success(&bincomb(&dir, &["check", "layout.txt"]));
+
+    fs::remove_file(dir.join("a.bin")).unwrap();
+    assert!(!bincomb(&dir, &["check", "layout.txt"]).status.success());
+}
+
+#[test]
+fn build_writes_checksums() {
+    let dir = workdir("build", &[("a.bin", b"abcdefgh"), ("layout.txt", CHECKSUM_LAYOUT)]);
+    assert_success(&bincomb(&dir, &["layout.txt", "out.bin"]));
+    let image = fs::read(dir.join("out.bin")).unwrap();
+    assert_eq!(&image[0x100..0x102], &[0x6b, 0xa3]);
+    assert_eq!(&image[0x110..0x118], b"0000a36b");
+}
+
+#[test]
+fn extract_stays_in_the_directory() {
+    let dir = workdir("extract", &[
+        ("a.bin", b"abcdefgh"),
+        ("layout.txt", b"0x0:A:file,a.bin\n"),
+        ("escape.txt", b"0x0:../escape:file,a.bin\n"),
+    ]);
+    assert_success(&bincomb(&dir, &["-q", "layout.txt", "out.bin"]));
+    assert_success(&bincomb(&dir, &["extract", "layout.txt", "out.bin", "regions"]));
+    assert_eq!(fs::read(dir.join("regions/A.bin")).unwrap(), b"abcdefgh");
+
+    assert!(!bincomb(&dir, &["extract", "escape.txt", "out.bin", "regions"]).status.success());
+    assert!(!dir.join("escape.bin").exists());
+}
+
+#[test]
+fn jobs_match_serial_build() {
+    let dir = workdir("jobs", &[("a.bin", b"abcdefgh"), ("layout.txt", CHECKSUM_LAYOUT)]);
+    assert_success(&bincomb(&dir, &["-j", "1", "layout.txt", "serial.bin"]));
+    assert_success(&bincomb(&dir, &["-j", "4", "layout.txt", "parallel.bin"]));
+    assert_eq!(fs::read(dir.join("parallel.bin")).unwrap(), fs::read(dir.join("serial.bin")).unwrap());
+}
+
+#[cfg(unix)]
+#[test]
+fn sparse_builds_leave_holes() {
+    use std::os::unix::fs::MetadataExt;
+
+    let zeros = vec![0; 0x10000];
+    let dir = workdir("sparse", &[
+        ("a.bin", b"abcdefgh"),
+        ("zeros.bin", &zeros),
+        ("layout.txt", b"0x0:A:file,a.bin\n0x1000:Z:file,zeros.bin\n0x20000:B:file,a.bin\n"),
+    ]);
+    for jobs in ["1", "4"] {
+        let sparse = format!("sparse-{}.bin", jobs);
+        let dense = format!("dense-{}.bin", jobs);
+        assert_success(&bincomb(&dir, &["-q", "-j", jobs, "--sparse", "layout.txt", &sparse]));
+        assert_success(&bincomb(&dir, &["-q", "-j", jobs, "layout.txt", &dense]));
+
+        assert_eq!(fs::read(dir.join(&sparse)).unwrap(), fs::read(dir.join(&dense)).unwrap());
+        let blocks = |name: &str| fs::metadata(dir.join(name)).unwrap().blocks();
+        assert!(blocks(&sparse) < blocks(&dense), "{} blocks with --jobs {}", blocks(&sparse), jobs);
+    }
+}
+
+#[test]
+fn incremental_rebuilds_on_changes() {
+    let dir = workdir("incremental", &[("rootfs/etc/hostname", b"a\n"), ("layout.txt", b"0x0:FS:archive,rootfs,tar\n")]);
+    let build = |args: &[&str]| {
+        let mut all = vec!["--incremental", "-f", "layout.txt", "out.bin"];
+        all.extend(args);
+        let output = bincomb(&dir, &all);
+        assert_success(&output);
+        String::from_utf8_lossy(&output.stderr).contains("is up to date")
+    };
+
+    assert!(!build(&[]));
+    assert!(build(&[]));
+    // A file of a directory input
+    fs::write(dir.join("rootfs/etc/hostname"), b"b\n").unwrap();
+    assert!(!build(&[]));
+    assert!(build(&[]));
+    // An option changing the output
+    assert!(!build(&["--trim"]));
+    assert!(build(&["--trim"]));
+}
+
+#[test]
+fn incremental_rebuilds_on_options() {
+    let dir = workdir("incremental-options", &[
+        ("a.bin", b"abcdefgh"),
+        ("layout.txt", b"0x100:A:file,a.bin\n"),
+        ("target.toml", b"size = 0x1000\nsector_size = 4\n"),
+    ]);
+    assert_success(&bincomb(&dir, &["-q", "--record", "fixtures", "layout.txt", "recorded.bin"]));
+    let build = |args: &[&str]| {
+        let mut all = vec!["--incremental", "-f", "layout.txt", "out.bin"];
+        all.extend(args);
+        let output = bincomb(&dir, &all);
+        assert_success(&output);
+        String::from_utf8_lossy(&output.stderr).contains("is up to date")
+    };
+
+    assert!(!build(&[]));
+    let options: &[&[&str]] = &[
+        &["--trim"],
+        &["--sector-size", "4"],
+        &["--sector-size", "4", "--deny-unaligned"],
+        &["--target", "target.toml"],
+        &["--min-offset", "0x100"],
+        &["--reproducible"],
+        &["--expand-env"],
+        &["--relative-to-cwd"],
+        &["--sparse"],
+        &["--mode", "600"],
+        &["--touch", "1000"],
+        &["--replay", "fixtures"],
+        &["--pre-cmd", "true"],
+        &["--map", "out.map"],
+        &["--emit-header", "out.h"],
+        &["--emit-rust", "out.rs"],
+        &["--emit-ld", "out.ld"],
+        &["--emit-ld", "out.ld", "--ld-base", "0x8000000"],
+        &["--emit-graph", "out.dot"],
+        &["--vars-out", "vars.json"],
+        &["--emit-provenance", "provenance.json"],
+        &["--emit-provenance", "provenance.json", "--locked", "provenance.json"],
+        &["--emit-checksums"],
+        &["--emit-checksums=md5"],
+    ];
+    for args in options {
+        assert!(!build(args), "{:?} didn't rebuild", args);
+        assert!(build(args), "{:?} rebuilt twice", args);
+    }
+}
+
+#[test]
+fn incremental_rewrites_side_outputs() {
+    let dir = workdir("incremental-outputs", &[("a.bin", b"abcdefgh"), ("layout.txt", b"0x0:A:file,a.bin\n")]);
+    let build = |args: &[&str]| {
+        let mut all = vec!["--incremental", "-f", "layout.txt", "out.bin"];
+        all.extend(args);
+        let output = bincomb(&dir, &all);
+        assert_success(&output);
+        String::from_utf8_lossy(&output.stderr).contains("is up to date")
+    };
+
+    assert!(!build(&["--map", "out.map", "--emit-checksums"]));
+    assert!(build(&["--map", "out.map", "--emit-checksums"]));
+    fs::remove_file(dir.join("out.map")).unwrap();
+    assert!(!build(&["--map", "out.map", "--emit-checksums"]));
+    assert!(dir.join("out.map").exists());
+    fs::write(dir.join("out.bin.sha256"), b"edited\n").unwrap();
+    assert!(!build(&["--map", "out.map", "--emit-checksums"]));
+    assert!(build(&["--map", "out.map", "--emit-checksums"]));
+    // Reports of the build are never skipped
+    assert!(!build(&["--map", "out.map", "--emit-checksums", "--stats"]));
+    assert!(!build(&["--map", "out.map", "--emit-checksums", "--stats"]));
+}
+
+#[test]
+fn record_and_replay_directories() {
+    let dir = workdir("replay", &[
+        ("rootfs/etc/hostname", b"device\n"),
+        ("rootfs/bin/init", b"#!/bin/sh\n"),
+        ("a.bin", b"abcd"),
+        ("layout.txt", b"0x0:A:file,a.bin\n0x10:FS:archive,rootfs,cpio\n"),
+    ]);
+    assert_success(&bincomb(&dir, &["-q", "--record", "fixtures", "layout.txt", "recorded.bin"]));
+
+    // The replay must not read the original inputs
+    fs::rename(dir.join("rootfs"), dir.join("moved")).unwrap();
+    fs::remove_file(dir.join("a.bin")).unwrap();
+    assert_success(&bincomb(&dir, &["-q", "--replay", "fixtures", "layout.txt", "replayed.bin"]));
+
+    assert_eq!(fs::read(dir.join("recorded.bin")).unwrap(), fs::read(dir.join("replayed.bin")).unwrap());
+}
+
+#[test]
+fn url_downloads_inputs() {
+    let (base, server) = serve(1, |_| ok(b"abcd"));
+    let layout = format!(
+        "0x0:A:url,{}/a.bin,sha256:88d4266fd4e6338d13b845fcf289579d209c897823b9217da3e161936f031589\n",
+        base
+    );
+    let dir = workdir("url", &[("layout.txt", layout.as_bytes())]);
+    assert_success(&bincomb(&dir, &["-q", "layout.txt", "out.bin"]));
+    assert_eq!(fs::read(dir.join("out.bin")).unwrap(), b"abcd");
+    assert!(server.join().unwrap()[0].starts_with("GET /a.bin HTTP/1.1"));
+
+    let (base, _) = serve(1, |_| ok(b"abce"));
+    let layout = format!("0x0:A:url,{}/a.bin,sha256:{}\n", base, "0".repeat(64));
+    fs::write(dir.join("layout.txt"), layout).unwrap();
+    assert!(!bincomb(&dir, &["-q", "layout.txt", "out.bin"]).status.success());
+}
+
+#[test]
+fn url_downloads_in_parallel() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    // Neither request is answered before both arrived
+    thread::spawn(move || {
+        let streams = (0..2)
+            .map(|_| {
+                let (stream, _) = listener.accept().unwrap();
+                let request = read_request(&stream);
+                (stream, request)
+            })
+            .collect::<Vec<_>>();
+        for (mut stream, request) in streams {
+            let body = if request.starts_with("GET /a.bin") { b"aaaa" } else { b"bbbb" };
+            let _ = stream.write_all(&ok(body));
+        }
+    });
+    let layout = format!("0x0:A:url,{0}/a.bin\n0x4:B:url,{0}/b.bin\n", base);
+    let dir = workdir("url-parallel", &[("layout.txt", layout.as_bytes())]);
+    assert_success(&bincomb(&dir, &["-q", "--http-timeout", "10", "layout.txt", "out.bin"]));
+    assert_eq!(fs::read(dir.join("out.bin")).unwrap(), b"aaaabbbb");
+}
+
+#[test]
+fn url_downloads_are_cached() {
+    let (base, server) = serve(3, |request| {
+        if request.contains("If-None-Match: \"v1\"") {
+            response("304 Not Modified", "", b"")
+        }
+        else {
+            response("200 OK", "ETag: \"v1\"\r\n", b"abcd")
+        }
+    });
+    let layout = format!("0x0:A:url,{}/a.bin\n", base);
+    let dir = workdir("url-cache", &[("layout.txt", layout.as_bytes())]);
+    for refresh in [false, false, true] {
+        let mut args = vec!["-q", "--force", "--cache-dir", "cache", "layout.txt", "out.bin"];
+        if refresh {
+            args.push("--refresh");
+        }
+        assert_success(&bincomb(&dir, &args));
+        assert_eq!(fs::read(dir.join("out.bin")).unwrap(), b"abcd");
+    }
+
+    let requests = server.join().unwrap();
+    assert!(!requests[0].contains("If-None-Match"));
+    assert!(requests[1].contains("If-None-Match: \"v1\""));
+    assert!(!requests[2].contains("If-None-Match"));
+}
+
+#[test]
+fn url_downloads_resume() {
+    let (base, server) = serve(4, |request| {
+        if request.contains("Range: bytes=4-\r\n") {
+            response("206 Partial Content", "ETag: \"v1\"\r\nContent-Range: bytes 4-7/8\r\n", b"efgh")
+        }
+        else {
+            // The connection breaks after half of the file
+            let mut response = response("200 OK", "ETag: \"v1\"\r\n", b"abcdefgh");
+            response.truncate(response.len() - 4);
+            response
+        }
+    });
+    let layout = format!("0x0:A:url,{}/a.bin\n", base);
+    let dir = workdir("url-resume", &[("layout.txt", layout.as_bytes())]);
+    let build = |cache, retries| {
+        bincomb(&dir, &["-q", "--force", "--cache-dir", cache, "--retries", retries, "--retry-delay", "0", "layout.txt", "out.bin"])
+    };
+
+    assert_success(&build("cache", "1"));
+    assert_eq!(fs::read(dir.join("out.bin")).unwrap(), b"abcdefgh");
+
+    // A later build resumes the download which failed
+    assert!(!build("other", "0").status.success());
+    assert_success(&build("other", "0"));
+    assert_eq!(fs::read(dir.join("out.bin")).unwrap(), b"abcdefgh");
+    assert!(fs::read_dir(dir.join("other")).unwrap().all(|entry| {
+        !entry.unwrap().file_name().to_string_lossy().ends_with(".part")
+    }));
+
+    let requests = server.join().unwrap();
+    for index in [1, 3] {
+        assert!(requests[index].contains("Range: bytes=4-\r\n"));
+        assert!(requests[index].contains("If-Range: \"v1\"\r\n"));
+    }
+}
+
+#[test]
+fn url_downloads_authenticate() {
+    let (base, server) = serve(2, |_| ok(b"abcd"));
+    let layout = format!("0x0:A:url,{0}/a.bin,bearer=$TOKEN\n0x4:B:url,{0}/b.bin\n", base);
+    let dir = workdir("url-auth", &[
+        ("layout.txt", layout.as_bytes()),
+        ("netrc", b"machine 127.0.0.1 login alice password open\n"),
+    ]);
+    let output = Command::new(env!("CARGO_BIN_EXE_bincomb"))
+        .current_dir(&dir)
+        .env("NETRC", dir.join("netrc"))
+        .args(["-q", "--no-cache", "-D", "TOKEN=secret", "--http-header", "X-Team: fw", "layout.txt", "out.bin"])
+        .output()
+        .unwrap();
+    assert_success(&output);
+
+    let mut requests = server.join().unwrap();
+    requests.sort();
+    assert!(requests[0].contains("Authorization: Bearer secret\r\n"));
+    assert!(requests[1].contains("Authorization: Basic YWxpY2U6b3Blbg==\r\n"));
+    assert!(requests.iter().all(|request| request.contains("X-Team: fw\r\n")));
+}
+
+#[test]
+fn url_downloads_s3_objects() {
+    let (base, server) = serve(1, |_| ok(b"abcd"));
+    let dir = workdir("url-s3", &[("layout.txt", b"0x0:A:url,s3://firmware/releases/app v1.bin\n")]);
+    let output = Command::new(env!("CARGO_BIN_EXE_bincomb"))
+        .current_dir(&dir)
+        .env("AWS_ENDPOINT_URL", &base)
+        .env("AWS_REGION", "eu-central-1")
+        .env("AWS_ACCESS_KEY_ID", "AKIDEXAMPLE")
+        .env("AWS_SECRET_ACCESS_KEY", "secret")
+        .env("AWS_SESSION_TOKEN", "token")
+        .args(["-q", "--no-cache", "layout.txt", "out.bin"])
+        .output()
+        .unwrap();
+    assert_success(&output);
+    assert_eq!(fs::read(dir.join("out.bin")).unwrap(), b"abcd");
+
+    let request = &server.join().unwrap()[0];
+    assert!(request.starts_with("GET /firmware/releases/app%20v1.bin HTTP/1.1\r\n"));
+    assert!(request.contains("x-amz-security-token: token\r\n"));
+    assert!(request.contains("Authorization: AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+    assert!(request.contains(
+        "/eu-central-1/s3/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date;x-amz-security-token, Signature="
+    ));
+}
+
+#[test]
+fn url_downloads_with_client_certificates() {
+    let tls = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+    let certs = |name| {
+        CertificateDer::pem_file_iter(tls.join(name))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_parsable_certificates(certs("ca.pem"));
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .unwrap();
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs("server.pem"), PrivateKeyDer::from_pem_file(tls.join("server.key")).unwrap())
+        .unwrap();
+    let config = Arc::new(config);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("https://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let connection = rustls::ServerConnection::new(config.clone()).unwrap();
+            let mut stream = rustls::StreamOwned::new(connection, stream.unwrap());
+            let mut reader = BufReader::new(&mut stream);
+            let mut line = String::new();
+            // The handshake fails without a trusted client certificate
+            while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                line.clear();
+            }
+            let _ = stream.write_all(&ok(b"abcd"));
+            let _ = stream.flush();
+        }
+    });
+
+    let layout = format!("0x0:A:url,{}/a.bin\n", base);
+    let dir = workdir("url-tls", &[("layout.txt", layout.as_bytes())]);
+    let ca = tls.join("ca.pem");
+    let ca = ca.to_str().unwrap();
+    assert!(!bincomb(&dir, &["-q", "--no-cache", "--ca-cert", ca, "layout.txt", "out.bin"]).status.success());
+
+    let (cert, key) = (tls.join("client.pem"), tls.join("client.key"));
+    let output = bincomb(&dir, &[
+        "-q", "--no-cache", "--ca-cert", ca,
+        "--client-cert", cert.to_str().unwrap(), "--client-key", key.to_str().unwrap(),
+        "layout.txt", "out.bin",
+    ]);
+    assert_success(&output);
+    assert_eq!(fs::read(dir.join("out.bin")).unwrap(), b"abcd");
+}